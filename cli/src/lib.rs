@@ -1,3 +1,2 @@
 pub mod project;
 pub mod executor;
-pub mod rest;
@@ -8,7 +8,7 @@ use figment::Figment;
 use http::{Request, StatusCode};
 
 use pyo3::ffi::PyErr_SetInterrupt;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use toml::{self, Value};
 use tungstenite::{
     connect, handshake::client::Response, stream::MaybeTlsStream, Message, WebSocket,
@@ -16,15 +16,22 @@ use tungstenite::{
 
 use ovejas::executor::python_executor;
 use ovejas::project::find_project_root;
-use ovejas::rest::{
-    DeviceCreateDTO, DeviceDeleteDTO, EnrollDeviceDTO, UserCreateDTO, UserDeleteDTO,
+use shared::api_client::{ApiError, BlockingApiClient};
+use shared::compression::{compress, Compression, COMPRESSION_HEADER};
+use shared::desired_state::DesiredState;
+use shared::rest_dtos::{
+    ChangeKind, DeviceCreateDTO, DeviceDeleteDTO, DeviceLabelsDTO, EnrollDeviceDTO, EnvironmentDriftDTO, EnvironmentDriftModeDTO, EnvironmentMaintenanceDTO, EnvironmentPromoteDTO, EnvironmentRetentionDTO,
+    EnvironmentSelectorDTO, EnvironmentSequenceDTO, EnvironmentStatusDTO, JoinTokenCreateDTO, ResponseDTO, SecretDeleteDTO, SecretListDTO, SecretSetDTO, UserCreateDTO,
+    UserDeleteDTO, WebhookCreateDTO, WebhookDeleteDTO, WebhookDeliveryListDTO, WebhookListDTO,
 };
+use shared::signing::{sign_state, signing_key_from_base64, SigningKey, StateSignature, DESTROYED_STATE};
 use shared::state_operations::{StateAction, StateOperationMessage};
+use shared::state_patch::canonical_json;
 use tungstenite::error::Error;
 
 use uuid::Uuid;
 
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 use tracing_subscriber;
 
 fn init_conn(
@@ -40,6 +47,7 @@ fn init_conn(
         .header("host", "example.com")
         .header("connection", "upgrade")
         .header("authorization", cli_token)
+        .header(COMPRESSION_HEADER, Compression::Zstd.as_str())
         .header("sec-websocket-version", 13)
         .body(())
         .unwrap();
@@ -59,6 +67,23 @@ fn init_conn(
     return (websocket, response);
 }
 
+// Compressed if the server accepted it when the socket was opened
+fn state_operation_frame(state_operation: StateOperationMessage, response: &Response) -> Message {
+    let compression = response.headers()
+        .get(COMPRESSION_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.parse::<Compression>().ok());
+
+    match compression {
+        Some(compression) => {
+            let serialized = serde_json::to_vec(&state_operation).expect("Could not serialize");
+
+            Message::Binary(compress(Some(compression), serialized).into())
+        },
+        None => state_operation.into(),
+    }
+}
+
 struct ProjectMetadata {
     project_name: String,
 }
@@ -67,9 +92,21 @@ struct ProjectMetadata {
 enum ProjectError {
     NotFoundError(String),
     PythonExecutorError(String),
+    InvalidStateError(String),
     FailedToReadError,
 }
 
+impl std::fmt::Display for ProjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProjectError::NotFoundError(err) => write!(f, "{err}"),
+            ProjectError::PythonExecutorError(err) => write!(f, "Could not run the project: {err}"),
+            ProjectError::InvalidStateError(err) => write!(f, "Invalid state: {err}"),
+            ProjectError::FailedToReadError => write!(f, "Could not read the project"),
+        }
+    }
+}
+
 fn get_project_metadata() -> Result<ProjectMetadata, ProjectError> {
     let project_root_dir = find_project_root().ok_or(ProjectError::NotFoundError(String::from(
         "Could not find project root.",
@@ -107,13 +144,45 @@ fn get_target_state() -> Result<String, ProjectError> {
     let target_state = python_executor(project_root_dir.clone())
         .map_err(|err| ProjectError::PythonExecutorError(err.to_string()))?;
 
+    // The server validates it again, this only fails earlier with the same error
+    DesiredState::parse(target_state.as_str())
+        .map_err(|err| ProjectError::InvalidStateError(err.to_string()))?;
+
     Ok(target_state)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ServerResponse {
-    msg: String,
-    data: serde_json::Value,
+fn log_api_error(err: ApiError) {
+    match err {
+        // e.g. the conflicting resources of a refused promotion
+        ApiError::Server { msg, data, .. } if !data.is_null() => error!("{msg}: {data:#}"),
+        err => error!("{err}"),
+    }
+}
+
+// Signed for the sequence the server gives the next state of the environment,
+// agents refuse anything older than what they applied
+fn sign_next_state(
+    api_client: &BlockingApiClient,
+    signing_key: &SigningKey,
+    project: &str,
+    environment: &str,
+    state: &str,
+) -> Result<StateSignature, ApiError> {
+    let environment_sequence_dto = EnvironmentSequenceDTO {
+        project_name: project.to_string(),
+        environment_name: environment.to_string(),
+    };
+
+    let sequence = api_client.environment_sequence(&environment_sequence_dto)?.data;
+
+    Ok(sign_state(signing_key, project, environment, sequence, state))
+}
+
+fn log_response<T: std::fmt::Debug>(response: Result<ResponseDTO<T>, ApiError>) {
+    match response {
+        Ok(response) => info!(response = format!("{:#?}", response)),
+        Err(err) => log_api_error(err),
+    }
 }
 
 #[derive(Deserialize)]
@@ -121,6 +190,8 @@ struct Config {
     port: Option<u64>,
     address: Option<String>,
     cli_token: Option<String>,
+    // Ed25519 seed in base64, signs the states pushed with `up` and `down`
+    signing_key: Option<String>,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -134,11 +205,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .bin_name("ovejas")
         .subcommand_required(true)
         .subcommand(
-            clap::command!("up").arg(
-                clap::arg!(-e --env <ENVIRONMENT>)
-                    .required(true)
-                    .value_parser(clap::value_parser!(String)),
-            ),
+            clap::command!("up")
+                .arg(
+                    clap::arg!(-e --env <ENVIRONMENT>)
+                        .required(true)
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("override-window")
+                        .long("override-window")
+                        .action(ArgAction::SetTrue)
+                        .help("Send the state to devices even outside the maintenance windows"),
+                ),
         )
         .subcommand(
             clap::command!("preview").arg(
@@ -154,6 +232,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .value_parser(clap::value_parser!(String)),
             ),
         )
+        .subcommand(
+            clap::command!("promote")
+                .arg(
+                    clap::arg!(--from <ENVIRONMENT>)
+                        .required(true)
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    clap::arg!(--to <ENVIRONMENT>)
+                        .required(true)
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .short('y')
+                        .long("yes")
+                        .action(ArgAction::SetTrue)
+                        .help("Promote without asking for confirmation"),
+                ),
+        )
         .subcommand(
             clap::command!("device")
                 .subcommand(
@@ -177,6 +275,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             .required(true)
                             .value_parser(clap::value_parser!(String)),
                     ),
+                )
+                .subcommand(
+                    clap::command!("label")
+                        .arg(
+                            Arg::new("machine-id")
+                                .short('i')
+                                .long("machine-id")
+                                .required(true)
+                                .action(ArgAction::Set)
+                                .value_name("UUID"),
+                        )
+                        .arg(
+                            Arg::new("labels")
+                                .action(ArgAction::Append)
+                                .value_name("KEY=VALUE"),
+                        )
+                        .arg(
+                            Arg::new("remove")
+                                .short('r')
+                                .long("remove")
+                                .action(ArgAction::Append)
+                                .value_name("KEY"),
+                        ),
                 ),
         )
         .subcommand(
@@ -211,6 +332,179 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             .required(true)
                             .value_parser(clap::value_parser!(String)),
                     ),
+                )
+                .subcommand(
+                    clap::command!("set-selector")
+                        .arg(
+                            clap::arg!(-s --selector <SELECTOR>)
+                                .value_parser(clap::value_parser!(String))
+                                .conflicts_with("clear"),
+                        )
+                        .arg(
+                            Arg::new("clear")
+                                .long("clear")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .group(
+                            clap::ArgGroup::new("selector-action")
+                                .args(["selector", "clear"])
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    clap::command!("maintenance")
+                        .arg(
+                            Arg::new("window")
+                                .short('w')
+                                .long("window")
+                                .action(ArgAction::Append)
+                                .value_name("DAYS HH:MM-HH:MM")
+                                .conflicts_with("clear"),
+                        )
+                        .arg(
+                            Arg::new("timezone")
+                                .long("timezone")
+                                .action(ArgAction::Set)
+                                .value_name("TIMEZONE")
+                                .requires("window"),
+                        )
+                        .arg(
+                            Arg::new("clear")
+                                .long("clear")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .group(
+                            clap::ArgGroup::new("maintenance-action")
+                                .args(["window", "clear"])
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    clap::command!("join-token")
+                        .arg(
+                            Arg::new("expires-in")
+                                .long("expires-in")
+                                .action(ArgAction::Set)
+                                .value_name("HOURS")
+                                .default_value("24")
+                                .value_parser(clap::value_parser!(i64)),
+                        )
+                        .arg(
+                            Arg::new("max-uses")
+                                .long("max-uses")
+                                .action(ArgAction::Set)
+                                .value_name("N")
+                                .default_value("1")
+                                .value_parser(clap::value_parser!(i32)),
+                        ),
+                )
+                .subcommand(
+                    clap::command!("retention")
+                        .arg(
+                            Arg::new("keep-last")
+                                .long("keep-last")
+                                .action(ArgAction::Set)
+                                .value_name("N")
+                                .value_parser(clap::value_parser!(i32)),
+                        )
+                        .arg(
+                            Arg::new("max-age-days")
+                                .long("max-age-days")
+                                .action(ArgAction::Set)
+                                .value_name("DAYS")
+                                .value_parser(clap::value_parser!(i32)),
+                        ),
+                )
+                .subcommand(clap::command!("drift"))
+                .subcommand(clap::command!("status"))
+                .subcommand(
+                    clap::command!("drift-mode").arg(
+                        Arg::new("mode")
+                            .long("mode")
+                            .required(true)
+                            .action(ArgAction::Set)
+                            .value_parser(["report", "remediate"]),
+                    ),
+                ),
+        )
+        .subcommand(
+            clap::command!("secret")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::command!("set")
+                        .arg(
+                            clap::arg!(-e --env <ENVIRONMENT>)
+                                .required(true)
+                                .value_parser(clap::value_parser!(String)),
+                        )
+                        .arg(
+                            clap::arg!(-n --name <NAME>)
+                                .required(true)
+                                .value_parser(clap::value_parser!(String)),
+                        )
+                        .arg(
+                            clap::arg!(-v --value <VALUE>)
+                                .value_parser(clap::value_parser!(String)),
+                        ),
+                )
+                .subcommand(
+                    clap::command!("list").arg(
+                        clap::arg!(-e --env <ENVIRONMENT>)
+                            .required(true)
+                            .value_parser(clap::value_parser!(String)),
+                    ),
+                )
+                .subcommand(
+                    clap::command!("rm")
+                        .arg(
+                            clap::arg!(-e --env <ENVIRONMENT>)
+                                .required(true)
+                                .value_parser(clap::value_parser!(String)),
+                        )
+                        .arg(
+                            clap::arg!(-n --name <NAME>)
+                                .required(true)
+                                .value_parser(clap::value_parser!(String)),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::command!("webhook")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::command!("add")
+                        .arg(
+                            clap::arg!(-u --url <URL>)
+                                .required(true)
+                                .value_parser(clap::value_parser!(String)),
+                        )
+                        .arg(
+                            Arg::new("event")
+                                .short('e')
+                                .long("event")
+                                .required(true)
+                                .action(ArgAction::Append)
+                                .value_name("EVENT"),
+                        )
+                        .arg(
+                            clap::arg!(-s --secret <SECRET>)
+                                .value_parser(clap::value_parser!(String)),
+                        ),
+                )
+                .subcommand(clap::command!("list"))
+                .subcommand(
+                    clap::command!("rm").arg(
+                        clap::arg!(-i --id <ID>)
+                            .required(true)
+                            .value_parser(clap::value_parser!(i32)),
+                    ),
+                )
+                .subcommand(
+                    clap::command!("deliveries").arg(
+                        clap::arg!(-i --id <ID>)
+                            .required(true)
+                            .value_parser(clap::value_parser!(i32)),
+                    ),
                 ),
         )
         .subcommand(
@@ -235,15 +529,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let config: Config = Figment::new()
         .merge(Yaml::file("config.yml"))
-        .join(Env::raw().only(&["PORT", "ADDRESS", "CLI_TOKEN"]))
+        .join(Env::raw().only(&["PORT", "ADDRESS", "CLI_TOKEN", "SIGNING_KEY"]))
         .extract()
         .unwrap();
 
     let address = config.address.unwrap_or("127.0.0.1".into());
     let port = config.port.unwrap_or(9734u64.into());
     let cli_token = config.cli_token.expect("no cli_token");
+    let signing_key = config.signing_key
+        .map(|signing_key| signing_key_from_base64(signing_key.as_str()).expect("Invalid signing key"));
 
     let full_addr = format!("{address}:{port}");
+    let api_client = BlockingApiClient::new(format!("http://{full_addr}"), cli_token.clone());
 
     match matches.subcommand() {
         Some(("up", matches)) => {
@@ -253,18 +550,48 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .expect("Expected environment");
 
             let project_metadata = get_project_metadata().unwrap();
-            let target_state = get_target_state().unwrap();
+
+            let target_state = match get_target_state() {
+                Ok(target_state) => target_state,
+                Err(err) => {
+                    error!("{err}");
+                    return Ok(());
+                },
+            };
+
+            // The server stores the canonical form, that is what devices verify
+            let signature = signing_key.as_ref().map(|signing_key| {
+                let canonical_state = canonical_json(target_state.as_str()).expect("Invalid state");
+
+                sign_next_state(&api_client, signing_key, &project_metadata.project_name, environment, canonical_state.as_str())
+            });
+
+            let signature = match signature.transpose() {
+                Ok(signature) => signature,
+                Err(err) => {
+                    log_api_error(err);
+                    return Ok(());
+                },
+            };
 
             let state_operation = StateOperationMessage {
                 environment: environment.to_string(),
                 action: StateAction::Up,
                 state: Some(target_state),
                 project: project_metadata.project_name,
+                override_window: matches.get_flag("override-window"),
+                signature,
             };
 
-            info!("Target state pushed to remote.");
+            let _ = websocket.send(state_operation_frame(state_operation, &response));
 
-            let _ = websocket.send(state_operation.into());
+            match websocket.read() {
+                Ok(Message::Text(reply)) => {
+                    info!(response = format!("{:#?}", serde_json::from_str::<ResponseDTO<serde_json::Value>>(reply.as_str())));
+                },
+                Ok(message) => error!("Unexpected reply from the server: {message:?}"),
+                Err(err) => error!("Could not read the server reply: {err}"),
+            }
 
             websocket.send(Message::Close(Option::None)).unwrap();
         }
@@ -282,9 +609,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 action: StateAction::Preview,
                 state: None,
                 project: project_metadata.project_name,
+                override_window: false,
+                signature: None,
             };
 
-            let _ = websocket.send(state_operation.into());
+            let _ = websocket.send(state_operation_frame(state_operation, &response));
 
             // println!("{}", target_state);
 
@@ -299,19 +628,91 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let project_metadata = get_project_metadata().unwrap();
             let target_state = get_target_state().unwrap();
 
+            let signature = signing_key.as_ref()
+                .map(|signing_key| sign_next_state(&api_client, signing_key, &project_metadata.project_name, environment, DESTROYED_STATE));
+
+            let signature = match signature.transpose() {
+                Ok(signature) => signature,
+                Err(err) => {
+                    log_api_error(err);
+                    return Ok(());
+                },
+            };
+
             let state_operation = StateOperationMessage {
                 environment: environment.to_string(),
                 action: StateAction::Down,
                 state: Some(target_state),
                 project: project_metadata.project_name,
+                override_window: false,
+                signature,
             };
 
-            let _ = websocket.send(state_operation.into());
+            let _ = websocket.send(state_operation_frame(state_operation, &response));
 
             // info!("{response:?}");
 
             websocket.send(Message::Close(Option::None)).unwrap();
         }
+        Some(("promote", matches)) => {
+            let from_environment = matches.get_one::<String>("from").expect("Expected source environment");
+            let to_environment = matches.get_one::<String>("to").expect("Expected target environment");
+
+            let project_metadata = get_project_metadata().unwrap();
+
+            let mut environment_promote_dto = EnvironmentPromoteDTO {
+                project_name: project_metadata.project_name,
+                from_environment: from_environment.to_string(),
+                to_environment: to_environment.to_string(),
+                source_state_id: None,
+                dry_run: true,
+            };
+
+            let preview = match api_client.environment_promote(&environment_promote_dto) {
+                Ok(response) => response.data,
+                Err(err) => {
+                    log_api_error(err);
+                    return Ok(());
+                }
+            };
+
+            info!("Promoting state {} from '{from_environment}' to '{to_environment}'", preview.source_state_id);
+
+            if preview.changes.is_empty() {
+                info!("No resource changes, '{to_environment}' already matches '{from_environment}'");
+            }
+
+            for change in preview.changes {
+                let marker = match change.change {
+                    ChangeKind::Added => "+",
+                    ChangeKind::Removed => "-",
+                    ChangeKind::Changed => "~",
+                };
+
+                info!("{marker} {}", change.urn);
+            }
+
+            if !matches.get_flag("yes") {
+                eprint!("Promote to '{to_environment}'? [y/N] ");
+
+                let mut answer = String::new();
+
+                std::io::stdin()
+                    .read_line(&mut answer)
+                    .expect("Failed to read confirmation from stdin");
+
+                if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                    info!("Promotion cancelled");
+                    return Ok(());
+                }
+            }
+
+            // Pinning the previewed state makes the server refuse if staging moved in between
+            environment_promote_dto.source_state_id = Some(preview.source_state_id);
+            environment_promote_dto.dry_run = false;
+
+            log_response(api_client.environment_promote(&environment_promote_dto));
+        }
         Some(("device", matches)) => match matches.subcommand() {
             Some(("write", matches)) => {
                 let name = matches.get_one::<String>("name").expect("Expected name");
@@ -324,17 +725,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     machine_id: machine_id.to_string(),
                 };
 
-                let client = reqwest::blocking::Client::new();
-
-                let response = client
-                    .post(format!("http://{full_addr}/device"))
-                    .json(&device_create_dto)
-                    .header("machine-type", "cli")
-                    .header("Authorization", cli_token)
-                    .send()
-                    .unwrap();
-
-                info!(response = format!("{:#?}", &response.json::<ServerResponse>()));
+                log_response(api_client.device_create(&device_create_dto));
             }
             Some(("delete", matches)) => {
                 let name = matches.get_one::<String>("name").expect("Expected name");
@@ -343,17 +734,37 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     name: name.to_string(),
                 };
 
-                let client = reqwest::blocking::Client::new();
+                log_response(api_client.device_delete(&device_delete_dto));
+            }
+            Some(("label", matches)) => {
+                let machine_id = matches
+                    .get_one::<String>("machine-id")
+                    .expect("Expected machine-id");
 
-                let response = client
-                    .delete(format!("http://{full_addr}/device"))
-                    .json(&device_delete_dto)
-                    .header("machine-type", "cli")
-                    .header("Authorization", cli_token)
-                    .send()
-                    .unwrap();
+                let labels_to_set = matches
+                    .get_many::<String>("labels")
+                    .unwrap_or_default()
+                    .map(|label| {
+                        label
+                            .split_once('=')
+                            .map(|(key, value)| (key.to_string(), value.to_string()))
+                            .expect("Labels must be written as KEY=VALUE")
+                    })
+                    .collect();
+
+                let labels_to_remove = matches
+                    .get_many::<String>("remove")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect();
+
+                let device_labels_dto = DeviceLabelsDTO {
+                    machine_id: machine_id.to_string(),
+                    set: labels_to_set,
+                    remove: labels_to_remove,
+                };
 
-                info!(response = format!("{:#?}", &response.json::<ServerResponse>()));
+                log_response(api_client.device_set_labels(&device_labels_dto));
             }
             _ => unreachable!("Clap should ensure we don't get here"),
         },
@@ -370,66 +781,282 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                     let project_metadata = get_project_metadata().unwrap();
 
-                    let device_create_dto = EnrollDeviceDTO {
+                    let enroll_device_dto = EnrollDeviceDTO {
                         machine_id: machine_id.to_string(),
                         environment_name: environment.to_string(),
                         project_name: project_metadata.project_name,
                     };
 
-                    let client = reqwest::blocking::Client::new();
+                    log_response(api_client.enroll_device(&enroll_device_dto));
+                }
+                Some(("set-selector", matches)) => {
+                    let project_metadata = get_project_metadata().unwrap();
+
+                    let environment_selector_dto = EnvironmentSelectorDTO {
+                        project_name: project_metadata.project_name,
+                        environment_name: environment.to_string(),
+                        label_selector: matches.get_one::<String>("selector").cloned(),
+                    };
+
+                    log_response(api_client.environment_set_selector(&environment_selector_dto));
+                }
+                Some(("maintenance", matches)) => {
+                    let project_metadata = get_project_metadata().unwrap();
+
+                    let environment_maintenance_dto = EnvironmentMaintenanceDTO {
+                        project_name: project_metadata.project_name,
+                        environment_name: environment.to_string(),
+                        windows: matches
+                            .get_many::<String>("window")
+                            .unwrap_or_default()
+                            .cloned()
+                            .collect(),
+                        timezone: matches.get_one::<String>("timezone").cloned(),
+                    };
+
+                    log_response(api_client.environment_set_maintenance(&environment_maintenance_dto));
+                }
+                Some(("join-token", matches)) => {
+                    let project_metadata = get_project_metadata().unwrap();
+
+                    let join_token_create_dto = JoinTokenCreateDTO {
+                        project_name: project_metadata.project_name,
+                        environment_name: environment.to_string(),
+                        expires_in_hours: *matches.get_one::<i64>("expires-in").unwrap(),
+                        max_uses: *matches.get_one::<i32>("max-uses").unwrap(),
+                    };
+
+                    log_response(api_client.join_token_create(&join_token_create_dto));
+                }
+                Some(("retention", matches)) => {
+                    let project_metadata = get_project_metadata().unwrap();
+
+                    let environment_retention_dto = EnvironmentRetentionDTO {
+                        project_name: project_metadata.project_name,
+                        environment_name: environment.to_string(),
+                        keep_last: matches.get_one::<i32>("keep-last").copied(),
+                        max_age_days: matches.get_one::<i32>("max-age-days").copied(),
+                    };
+
+                    log_response(api_client.environment_set_retention(&environment_retention_dto));
+                }
+                Some(("status", _)) => {
+                    let project_metadata = get_project_metadata().unwrap();
+
+                    let environment_status_dto = EnvironmentStatusDTO {
+                        project_name: project_metadata.project_name,
+                        environment_name: environment.to_string(),
+                    };
+
+                    let device_statuses = match api_client.environment_status(&environment_status_dto) {
+                        Ok(response) => response.data,
+                        Err(err) => {
+                            log_api_error(err);
+                            return Ok(());
+                        }
+                    };
+
+                    for device_status in device_statuses {
+                        let status = device_status.status
+                            .map(|status| status.to_string())
+                            .unwrap_or(String::from("unknown"));
+
+                        let since = device_status.status_updated_at
+                            .map(|updated_at| format!(" since {updated_at}"))
+                            .unwrap_or_default();
+
+                        let presence = if device_status.online { "online" } else { "offline" };
+
+                        info!("{} ({presence}): {status}{since}", device_status.name);
+                    }
+                }
+                Some(("drift", _)) => {
+                    let project_metadata = get_project_metadata().unwrap();
+
+                    let environment_drift_dto = EnvironmentDriftDTO {
+                        project_name: project_metadata.project_name,
+                        environment_name: environment.to_string(),
+                    };
+
+                    log_response(api_client.environment_drift(&environment_drift_dto));
+                }
+                Some(("drift-mode", matches)) => {
+                    let project_metadata = get_project_metadata().unwrap();
 
-                    let response = client
-                        .post(format!("http://{full_addr}/enroll_device"))
-                        .json(&device_create_dto)
-                        .header("machine-type", "cli")
-                        .header("Authorization", cli_token)
-                        .send()
-                        .unwrap();
+                    let environment_drift_mode_dto = EnvironmentDriftModeDTO {
+                        project_name: project_metadata.project_name,
+                        environment_name: environment.to_string(),
+                        mode: matches.get_one::<String>("mode").expect("Expected mode").to_string(),
+                    };
 
-                    info!(response = format!("{:#?}", &response.json::<ServerResponse>()));
+                    log_response(api_client.environment_set_drift_mode(&environment_drift_mode_dto));
                 }
                 _ => unreachable!("Clap should ensure we don't get here"),
             }
         }
-        Some(("user", matches)) => match matches.subcommand() {
-            Some(("write", matches)) => {
+        Some(("secret", matches)) => match matches.subcommand() {
+            Some(("set", matches)) => {
+                let environment = matches
+                    .get_one::<String>("env")
+                    .expect("Expected environment");
                 let name = matches.get_one::<String>("name").expect("Expected name");
 
-                let device_create_dto = UserCreateDTO {
+                // Without `--value` the value is read from stdin, which keeps it
+                // out of the shell history and the process list
+                let value = match matches.get_one::<String>("value") {
+                    Some(value) => {
+                        warn!("Passing the secret value with --value leaves it in the shell history, pipe it through stdin instead");
+
+                        value.to_string()
+                    }
+                    None => {
+                        let mut value = String::new();
+
+                        std::io::stdin()
+                            .read_line(&mut value)
+                            .expect("Failed to read secret value from stdin");
+
+                        value.trim_end_matches(['\r', '\n']).to_string()
+                    }
+                };
+
+                let project_metadata = get_project_metadata().unwrap();
+
+                let secret_set_dto = SecretSetDTO {
+                    project_name: project_metadata.project_name,
+                    environment_name: environment.to_string(),
                     name: name.to_string(),
-                    password: String::from("placeholder-password"),
+                    value,
                 };
 
-                let client = reqwest::blocking::Client::new();
+                log_response(api_client.secret_set(&secret_set_dto));
+            }
+            Some(("list", matches)) => {
+                let environment = matches
+                    .get_one::<String>("env")
+                    .expect("Expected environment");
 
-                let response = client
-                    .post(format!("http://{full_addr}/user"))
-                    .json(&device_create_dto)
-                    .header("machine-type", "cli")
-                    .header("Authorization", cli_token)
-                    .send()
-                    .unwrap();
+                let project_metadata = get_project_metadata().unwrap();
 
-                info!(response = format!("{:#?}", &response.json::<ServerResponse>()));
+                let secret_list_dto = SecretListDTO {
+                    project_name: project_metadata.project_name,
+                    environment_name: environment.to_string(),
+                };
+
+                log_response(api_client.secret_list(&secret_list_dto));
             }
-            Some(("delete", matches)) => {
+            Some(("rm", matches)) => {
+                let environment = matches
+                    .get_one::<String>("env")
+                    .expect("Expected environment");
                 let name = matches.get_one::<String>("name").expect("Expected name");
 
-                let device_delete_dto = DeviceDeleteDTO {
+                let project_metadata = get_project_metadata().unwrap();
+
+                let secret_delete_dto = SecretDeleteDTO {
+                    project_name: project_metadata.project_name,
+                    environment_name: environment.to_string(),
+                    name: name.to_string(),
+                };
+
+                log_response(api_client.secret_delete(&secret_delete_dto));
+            }
+            _ => unreachable!("Clap should ensure we don't get here"),
+        },
+        Some(("webhook", matches)) => match matches.subcommand() {
+            Some(("add", matches)) => {
+                let url = matches.get_one::<String>("url").expect("Expected url");
+
+                let events = matches
+                    .get_many::<String>("event")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect();
+
+                // Without `--secret` the secret is read from stdin, which keeps it
+                // out of the shell history and the process list
+                let secret = match matches.get_one::<String>("secret") {
+                    Some(secret) => {
+                        warn!("Passing the webhook secret with --secret leaves it in the shell history, pipe it through stdin instead");
+
+                        secret.to_string()
+                    }
+                    None => {
+                        let mut secret = String::new();
+
+                        std::io::stdin()
+                            .read_line(&mut secret)
+                            .expect("Failed to read webhook secret from stdin");
+
+                        secret.trim_end_matches(['\r', '\n']).to_string()
+                    }
+                };
+
+                let project_metadata = get_project_metadata().unwrap();
+
+                let webhook_create_dto = WebhookCreateDTO {
+                    project_name: project_metadata.project_name,
+                    url: url.to_string(),
+                    events,
+                    secret,
+                };
+
+                log_response(api_client.webhook_create(&webhook_create_dto));
+            }
+            Some(("list", _)) => {
+                let project_metadata = get_project_metadata().unwrap();
+
+                let webhook_list_dto = WebhookListDTO {
+                    project_name: project_metadata.project_name,
+                };
+
+                log_response(api_client.webhook_list(&webhook_list_dto));
+            }
+            Some(("rm", matches)) => {
+                let id = matches.get_one::<i32>("id").expect("Expected id");
+
+                let project_metadata = get_project_metadata().unwrap();
+
+                let webhook_delete_dto = WebhookDeleteDTO {
+                    project_name: project_metadata.project_name,
+                    id: *id,
+                };
+
+                log_response(api_client.webhook_delete(&webhook_delete_dto));
+            }
+            Some(("deliveries", matches)) => {
+                let id = matches.get_one::<i32>("id").expect("Expected id");
+
+                let project_metadata = get_project_metadata().unwrap();
+
+                let webhook_delivery_list_dto = WebhookDeliveryListDTO {
+                    project_name: project_metadata.project_name,
+                    id: *id,
+                };
+
+                log_response(api_client.webhook_delivery_list(&webhook_delivery_list_dto));
+            }
+            _ => unreachable!("Clap should ensure we don't get here"),
+        },
+        Some(("user", matches)) => match matches.subcommand() {
+            Some(("write", matches)) => {
+                let name = matches.get_one::<String>("name").expect("Expected name");
+
+                let user_create_dto = UserCreateDTO {
                     name: name.to_string(),
+                    password: String::from("placeholder-password"),
                 };
 
-                let client = reqwest::blocking::Client::new();
+                log_response(api_client.user_create(&user_create_dto));
+            }
+            Some(("delete", matches)) => {
+                let name = matches.get_one::<String>("name").expect("Expected name");
 
-                let response = client
-                    .delete(format!("http://{full_addr}/user"))
-                    .json(&device_delete_dto)
-                    .header("machine-type", "cli")
-                    .header("Authorization", cli_token)
-                    .send()
-                    .unwrap();
+                let user_delete_dto = UserDeleteDTO {
+                    name: name.to_string(),
+                };
 
-                info!(response = format!("{:#?}", &response.json::<ServerResponse>()));
+                log_response(api_client.user_delete(&user_delete_dto));
             }
             _ => unreachable!("Clap should ensure we don't get here"),
         },
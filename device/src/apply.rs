@@ -0,0 +1,108 @@
+use shared::request_operations::DeviceStatus;
+use tracing::{debug, error, warn};
+
+// A change to the system that knows how to undo itself
+pub trait Change {
+    fn apply(&self, dry_run: bool) -> Result<(), String>;
+    fn revert(&self, dry_run: bool) -> Result<(), String>;
+    // Used in logs and failure reasons, e.g. the URN of the resource
+    fn describe(&self) -> String;
+}
+
+// Applies the changes in order, reporting `Applying` before the first one and
+// after each one. If a change fails, the ones already applied are reverted
+// newest first and the error is `RolledBack`, or `Failed` when reverting
+// fails too, leaving the system as it was before the update when possible.
+pub fn apply_changes<C: Change>(
+    changes: &[C],
+    dry_run: bool,
+    report_progress: &mut dyn FnMut(DeviceStatus),
+) -> Result<(), DeviceStatus> {
+    let total = changes.len() as u32;
+
+    report_progress(DeviceStatus::Applying { applied: 0, total });
+
+    for (applied, change) in changes.iter().enumerate() {
+        if let Err(err) = change.apply(dry_run) {
+            let reason = format!("Could not apply {}: {err}", change.describe());
+
+            error!("{reason}, rolling back {applied} applied changes");
+
+            for applied_change in changes[..applied].iter().rev() {
+                if let Err(err) = applied_change.revert(dry_run) {
+                    return Err(DeviceStatus::Failed {
+                        reason: format!("{reason}, could not roll back {}: {err}", applied_change.describe()),
+                    });
+                }
+
+                warn!(change = applied_change.describe(), "Rolled back change");
+            }
+
+            return Err(DeviceStatus::RolledBack { reason });
+        }
+
+        debug!(change = change.describe(), "Applied change");
+
+        report_progress(DeviceStatus::Applying { applied: applied as u32 + 1, total });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use shared::request_operations::DeviceStatus;
+
+    use super::{apply_changes, Change};
+
+    struct FakeChange<'a> {
+        name: &'static str,
+        fails_to_apply: bool,
+        fails_to_revert: bool,
+        log: &'a RefCell<Vec<String>>,
+    }
+
+    impl Change for FakeChange<'_> {
+        fn apply(&self, _dry_run: bool) -> Result<(), String> {
+            self.log.borrow_mut().push(format!("apply {}", self.name));
+
+            if self.fails_to_apply { Err(String::from("exit status 1")) } else { Ok(()) }
+        }
+
+        fn revert(&self, _dry_run: bool) -> Result<(), String> {
+            self.log.borrow_mut().push(format!("revert {}", self.name));
+
+            if self.fails_to_revert { Err(String::from("exit status 2")) } else { Ok(()) }
+        }
+
+        fn describe(&self) -> String {
+            self.name.to_string()
+        }
+    }
+
+    #[test]
+    fn failed_change_rolls_back_applied_ones() {
+        let log = RefCell::new(Vec::new());
+        let change = |name, fails_to_apply, fails_to_revert| FakeChange { name, fails_to_apply, fails_to_revert, log: &log };
+
+        let mut progress = Vec::new();
+
+        assert_eq!(apply_changes(&[change("a", false, false), change("b", false, false)], false, &mut |status| progress.push(status)), Ok(()));
+        assert_eq!(progress.last(), Some(&DeviceStatus::Applying { applied: 2, total: 2 }));
+
+        log.borrow_mut().clear();
+
+        let result = apply_changes(&[change("a", false, false), change("b", false, false), change("c", true, false)], false, &mut |_| {});
+
+        assert_eq!(result, Err(DeviceStatus::RolledBack { reason: String::from("Could not apply c: exit status 1") }));
+        assert_eq!(*log.borrow(), ["apply a", "apply b", "apply c", "revert b", "revert a"]);
+
+        let result = apply_changes(&[change("a", false, true), change("b", true, false)], false, &mut |_| {});
+
+        assert_eq!(result, Err(DeviceStatus::Failed {
+            reason: String::from("Could not apply b: exit status 1, could not roll back a: exit status 2"),
+        }));
+    }
+}
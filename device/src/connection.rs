@@ -0,0 +1,216 @@
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use shared::handshake::{Hello, HelloResponse};
+use tungstenite::{stream::MaybeTlsStream, Bytes, Message, WebSocket};
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    IdleTimeout,
+    Closed(Option<String>),
+    Handshake(String),
+    Transport(Box<tungstenite::Error>),
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::IdleTimeout => write!(f, "No frames received from the server before the idle timeout"),
+            ConnectionError::Closed(Some(reason)) => write!(f, "Connection closed by the server: {reason}"),
+            ConnectionError::Closed(None) => write!(f, "Connection closed by the server"),
+            ConnectionError::Handshake(reason) => write!(f, "Handshake failed: {reason}"),
+            ConnectionError::Transport(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+impl From<tungstenite::Error> for ConnectionError {
+    fn from(err: tungstenite::Error) -> Self {
+        ConnectionError::Transport(Box::new(err))
+    }
+}
+
+// What to do when the server was quiet for a whole heartbeat interval
+#[derive(Debug, PartialEq)]
+pub enum HeartbeatAction {
+    Ping,
+    IdleTimeout,
+}
+
+#[derive(Debug)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub idle_timeout: Duration,
+    last_seen: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, idle_timeout: Duration) -> Self {
+        Heartbeat {
+            interval,
+            idle_timeout,
+            last_seen: Instant::now(),
+        }
+    }
+
+    pub fn saw_frame(&mut self, now: Instant) {
+        self.last_seen = now;
+    }
+
+    pub fn on_read_timeout(&self, now: Instant) -> HeartbeatAction {
+        if now.duration_since(self.last_seen) > self.idle_timeout {
+            HeartbeatAction::IdleTimeout
+        } else {
+            HeartbeatAction::Ping
+        }
+    }
+
+    // Reads block at most one heartbeat interval, so the device gets a chance
+    // to ping the server even when it has nothing to say.
+    pub fn apply(&mut self, socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> std::io::Result<()> {
+        self.last_seen = Instant::now();
+
+        match socket.get_mut() {
+            MaybeTlsStream::Plain(stream) => stream.set_read_timeout(Some(self.interval)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn is_read_timeout(err: &tungstenite::Error) -> bool {
+    match err {
+        tungstenite::Error::Io(err) => matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+        _ => false,
+    }
+}
+
+// Returns the next data frame. Pings from the server are answered by
+// tungstenite on the next read or write, so control frames only count as a
+// sign of life here.
+pub fn read_message(
+    socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    heartbeat: &mut Heartbeat,
+) -> Result<Message, ConnectionError> {
+    loop {
+        match socket.read() {
+            Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_)) => {
+                heartbeat.saw_frame(Instant::now());
+            },
+            Ok(Message::Close(frame)) => {
+                // Sends the close reply queued by tungstenite
+                let _ = socket.flush();
+
+                return Err(ConnectionError::Closed(frame.map(|frame| frame.reason.to_string())));
+            },
+            Ok(message) => {
+                heartbeat.saw_frame(Instant::now());
+
+                return Ok(message);
+            },
+            Err(err) if is_read_timeout(&err) => match heartbeat.on_read_timeout(Instant::now()) {
+                HeartbeatAction::Ping => socket.send(Message::Ping(Bytes::new()))?,
+                HeartbeatAction::IdleTimeout => {
+                    let _ = socket.close(None);
+
+                    return Err(ConnectionError::IdleTimeout);
+                },
+            },
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+// Announces the agent to the server, which either answers with its own hello
+// or closes the connection explaining why the agent is not compatible.
+pub fn handshake(
+    socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    heartbeat: &mut Heartbeat,
+    hello: Hello,
+) -> Result<HelloResponse, ConnectionError> {
+    socket.send(hello.into())?;
+
+    let message = read_message(socket, heartbeat)?;
+
+    if !message.is_text() {
+        return Err(ConnectionError::Handshake(String::from("The server did not answer the hello, it may be too old")));
+    }
+
+    HelloResponse::try_from(message).map_err(|err| ConnectionError::Handshake(format!("Invalid hello response: {err}")))
+}
+
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    // The delay doubles on every attempt up to `max`, and a random half of it
+    // is dropped so agents don't reconnect in lockstep after a server restart.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.initial
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+
+        self.attempt = self.attempt.saturating_add(1);
+
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Backoff, Heartbeat, HeartbeatAction};
+
+    #[test]
+    fn quiet_server_is_pinged_until_idle_timeout() {
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(15), Duration::from_secs(45));
+        let start = Instant::now();
+
+        heartbeat.saw_frame(start);
+
+        assert_eq!(heartbeat.on_read_timeout(start + Duration::from_secs(15)), HeartbeatAction::Ping);
+        assert_eq!(heartbeat.on_read_timeout(start + Duration::from_secs(45)), HeartbeatAction::Ping);
+        assert_eq!(heartbeat.on_read_timeout(start + Duration::from_secs(46)), HeartbeatAction::IdleTimeout);
+
+        // A pong to the last ping keeps the connection alive
+        heartbeat.saw_frame(start + Duration::from_secs(30));
+        assert_eq!(heartbeat.on_read_timeout(start + Duration::from_secs(46)), HeartbeatAction::Ping);
+    }
+
+    #[test]
+    fn backoff_grows_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+
+        let delays: Vec<Duration> = (0..6).map(|_| backoff.next_delay()).collect();
+
+        for (attempt, delay) in delays.iter().enumerate() {
+            let expected = Duration::from_secs(1 << attempt.min(3));
+
+            assert!(*delay >= expected / 2 && *delay <= expected, "attempt {attempt}: {delay:?}");
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+use shared::request_operations::ResourceDrift;
+
+fn parameter_to_string(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+// Only the parameters the provider reads back from the system are compared,
+// so write-only ones like passwords never show up as drift.
+pub fn compare_resource(urn: &str, desired: &Value, actual: Option<&Value>) -> Vec<ResourceDrift> {
+    let Some(actual) = actual else {
+        return vec![ResourceDrift::Missing { urn: urn.to_string() }];
+    };
+
+    let Some(actual) = actual.as_object() else {
+        return vec![];
+    };
+
+    actual
+        .iter()
+        .filter_map(|(parameter, actual_value)| {
+            let expected_value = desired.get(parameter)?;
+
+            if expected_value == actual_value {
+                return None;
+            }
+
+            Some(ResourceDrift::Changed {
+                urn: urn.to_string(),
+                parameter: parameter.clone(),
+                expected: parameter_to_string(expected_value),
+                actual: parameter_to_string(actual_value),
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct DriftCheck {
+    interval: Duration,
+    last_check: Option<Instant>,
+}
+
+impl DriftCheck {
+    // A zero interval disables the checks.
+    pub fn new(interval: Duration) -> Self {
+        DriftCheck {
+            interval,
+            last_check: None,
+        }
+    }
+
+    pub fn is_due(&mut self) -> bool {
+        if self.interval.is_zero() || self.last_check.is_some_and(|last_check| last_check.elapsed() < self.interval) {
+            return false;
+        }
+
+        self.last_check = Some(Instant::now());
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+    use shared::request_operations::ResourceDrift;
+
+    use super::{compare_resource, DriftCheck};
+
+    #[test]
+    fn drift_from_read_parameters() {
+        let urn = "ovejas.system::User::admin";
+        let desired = json!({ "name": "admin", "uid": 1000, "gid": 1000, "password": "hunter2" });
+
+        assert_eq!(compare_resource(urn, &desired, None), vec![ResourceDrift::Missing { urn: urn.to_string() }]);
+        assert!(compare_resource(urn, &desired, Some(&json!({ "name": "admin", "uid": 1000, "gid": 1000 }))).is_empty());
+
+        assert_eq!(
+            compare_resource(urn, &desired, Some(&json!({ "name": "admin", "uid": 1000, "gid": 100 }))),
+            vec![ResourceDrift::Changed {
+                urn: urn.to_string(),
+                parameter: String::from("gid"),
+                expected: String::from("1000"),
+                actual: String::from("100"),
+            }],
+        );
+
+        let mut drift_check = DriftCheck::new(Duration::from_secs(60));
+        assert!(drift_check.is_due());
+        assert!(!drift_check.is_due());
+        assert!(!DriftCheck::new(Duration::ZERO).is_due());
+    }
+}
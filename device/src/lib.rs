@@ -1,10 +1,14 @@
+pub mod apply;
+pub mod connection;
+pub mod drift;
 pub mod state;
 
 #[cfg(test)]
 mod tests {
     use std::{fs::File, io::Read};
 
-    use serde_json::{json, Value};
+    use serde_json::json;
+    use shared::desired_state::DesiredState;
 
     use crate::state::StateDelta;
 
@@ -15,25 +19,22 @@ mod tests {
         let mut local_json = String::new();
         let _ = local_file.unwrap().read_to_string(&mut local_json);
 
-        let local_json: Value = serde_json::from_str(local_json.as_str()).unwrap();
+        let local_state = DesiredState::parse(local_json.as_str()).unwrap();
 
         let remote_file = File::open(format!("examples/resource_b.json"));
 
         let mut remote_json = String::new();
         let _ = remote_file.unwrap().read_to_string(&mut remote_json);
 
-        let remote_json: Value = serde_json::from_str(remote_json.as_str()).unwrap();
+        let remote_state = DesiredState::parse(remote_json.as_str()).unwrap();
 
-        let delta = StateDelta::from_json(
-            local_json.as_object().unwrap()["resources"].clone(),
-            remote_json.as_object().unwrap()["resources"].clone()
-        );
+        let delta = StateDelta::from_resources(&local_state.resources, &remote_state.resources);
 
         let expected = StateDelta { 
             resources_to_delete: vec![],
             resources_to_create: vec![],
             resources_to_update: vec![
-                json!(
+                serde_json::from_value(json!(
                 {
                     "urn": "ovejas.system::User::user_0",
                     "parameters": {
@@ -41,7 +42,7 @@ mod tests {
                         "uid": 110,
                         "gid": 111
                     }
-                })
+                })).unwrap()
             ]
         };
 
@@ -1,36 +1,41 @@
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::fs::create_dir;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
 use std::ops::Deref;
 use std::thread;
 use std::time::Duration;
 use std::{fs, net::TcpStream};
 use chrono::{DateTime, NaiveDateTime, Utc};
+use device::apply::{apply_changes, Change};
+use device::connection::{handshake, read_message, Backoff, Heartbeat};
+use device::drift::{compare_resource, DriftCheck};
 use device::state::{self, StateDelta};
 use figment::{Figment, providers::{Format, Yaml, Env}};
 use http::{Request, Response};
 use md5::{Md5, Digest};
 use tungstenite::handshake::machine;
-use tungstenite::{connect, stream::MaybeTlsStream, Message, WebSocket};
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig};
+use tungstenite::{client::connect_with_config, stream::MaybeTlsStream, Message, WebSocket};
 use walkdir::WalkDir;
 use std::env::home_dir;
 use std::path::Path;
 use regex::Regex;
 
-use shared::request_operations::{CurrentStatusResponse, DeviceStatus, EnvironmentUpdate, EnvironmentUpdateOperation, RequestOperations};
-
-#[derive(Deserialize, Serialize, Debug)]
-struct ResourceSchema {
-    urn: String,
-    parameters: HashMap<String, String>,
-}
-
-#[derive(Deserialize, Serialize, Debug)]
-struct StateSchema {
-    schema_version: i32,
-    resources: Vec<ResourceSchema>,
-}
+use shared::compression::{DEFAULT_MAX_MESSAGE_SIZE, SUPPORTED_COMPRESSIONS};
+use shared::desired_state::{DesiredState, ResourceSpec, Urn, STATE_VERSION};
+use shared::handshake::{Hello, PROTOCOL_VERSION};
+use shared::request_operations::{CurrentStatusResponse, DeviceStatus, EnvironmentUpdate, EnvironmentUpdateOperation, FrameCodec, MessageIds, RequestOperations, ResourceDrift, ResponseOperations};
+use shared::secrets::visit_secret_references;
+use shared::signing::{check_not_replayed, verify_state, verifying_key_from_base64, AppliedSignature, StateSignature, VerifyingKey, DESTROYED_STATE};
+use shared::state_patch;
 
 const OVEJAS_DIR: &str = ".ovejas";
+const DEVICE_TOKEN_FILE: &str = "device_token";
+
+// Resource kinds `Resource::provider` knows about, announced to the server
+const PROVIDER_KINDS: &[&str] = &["User"];
 
 fn get_ovejas_root_dir() -> String {
     let home = home_dir().unwrap();
@@ -38,26 +43,94 @@ fn get_ovejas_root_dir() -> String {
     format!("{}/{OVEJAS_DIR}", home.to_string_lossy())
 }
 
-fn process_environment_update_request(environment: String, environment_update: EnvironmentUpdate) {
+// States are validated by the server, an invalid one here means the file or
+// the message got corrupted, so nothing is applied.
+fn parse_state(environment: &str, json: &str) -> Result<DesiredState, DeviceStatus> {
+    DesiredState::parse(json).map_err(|err| {
+        error!(environment, "Invalid state, skipping: {err}");
+
+        DeviceStatus::Failed { reason: format!("Invalid state: {err}") }
+    })
+}
+
+fn applied_signature_path(environment: &str) -> String {
+    format!("{}/state/signature.{environment}.json", get_ovejas_root_dir())
+}
+
+// Kept next to the state file once a signed state is applied. Missing until
+// then, but an unreadable one refuses updates instead of forgetting the
+// project and time replays are checked against.
+fn read_applied_signature(environment: &str) -> Result<Option<AppliedSignature>, String> {
+    match fs::read_to_string(applied_signature_path(environment)) {
+        Ok(applied_signature) => serde_json::from_str(&applied_signature)
+            .map(Some)
+            .map_err(|err| format!("Invalid applied signature file: {err}")),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("Could not read applied signature file: {err}")),
+    }
+}
+
+fn write_applied_signature(environment: &str, signature: &StateSignature) {
+    let applied_signature = serde_json::to_string(&AppliedSignature::from(signature)).expect("Could not serialize applied signature");
+
+    if let Err(err) = fs::write(applied_signature_path(environment), applied_signature) {
+        error!(environment, "Could not write applied signature file: {err}");
+    }
+}
+
+// Returns the status the environment ends up in when the update is not
+// applied. The state file is only written once every change is applied, so
+// the device keeps reporting the previous state hash otherwise.
+fn process_environment_update_request(
+    environment: String,
+    environment_update: EnvironmentUpdate,
+    trusted_keys: Option<&[VerifyingKey]>,
+    report_progress: &mut dyn FnMut(DeviceStatus),
+) -> Result<(), DeviceStatus> {
     let dry_run = false;
 
+    // Checked before anything is applied. A patch is checked once patched,
+    // the signature covers the whole document. A valid signature is still
+    // refused if it is older than, or for another project than, the last
+    // state applied to the environment. Without trusted keys every state is
+    // refused, unless the agent allows unsigned states.
+    if let Some(trusted_keys) = trusted_keys {
+        let signed_state = match environment_update.operation {
+            EnvironmentUpdateOperation::Destroy => Some(DESTROYED_STATE),
+            EnvironmentUpdateOperation::Patch { .. } => None,
+            _ => Some(environment_update.state.as_deref().unwrap_or_default()),
+        };
+
+        if let Some(signed_state) = signed_state {
+            let verified = verify_state(trusted_keys, environment_update.signature.as_ref(), &environment, signed_state)
+                .map_err(|err| err.to_string())
+                .and_then(|_| read_applied_signature(&environment))
+                .and_then(|last_applied| {
+                    let signature = environment_update.signature.as_ref().expect("Verified signature");
+
+                    check_not_replayed(signature, last_applied.as_ref()).map_err(|err| err.to_string())
+                });
+
+            if let Err(err) = verified {
+                error!(environment, "Refusing update: {err}");
+                return Err(DeviceStatus::Failed { reason: format!("Refused update: {err}") });
+            }
+        }
+    }
+
+    let secrets = &environment_update.secrets;
+
     match environment_update.operation {
         EnvironmentUpdateOperation::Create => {
             let target_state = environment_update.state.expect("Failed to get environment state");
-            let target_state_json: serde_json::Value = serde_json::from_str(target_state.clone().as_str()).unwrap();
+            let target_desired_state = parse_state(&environment, &target_state)?;
 
-            let resources = target_state_json
-                .get("resources")
-                .expect("Target state doesn't have the key 'resources'")
-                .as_array()
-                .unwrap();
+            let changes: Vec<ResourceChange> = target_desired_state.resources
+                .iter()
+                .map(|resource| ResourceChange::Create(Resource::from(resource).with_secrets(secrets)))
+                .collect();
 
-            for resource in resources {
-                let resource: Resource = serde_json::from_value(resource.clone()).unwrap();
-                resource.create(dry_run);
-
-                debug!(created_resource = serde_json::to_string_pretty(&resource).unwrap());
-            }
+            apply_changes(&changes, dry_run, report_progress)?;
 
             let ovejas_root_dir = get_ovejas_root_dir();
             let state_file_path = format!("{ovejas_root_dir}/state/state.{environment}.json");
@@ -69,39 +142,33 @@ fn process_environment_update_request(environment: String, environment_update: E
         },
         EnvironmentUpdateOperation::Update =>  {
             let target_state = environment_update.state.expect("Failed to get environment state");
-            let target_state_json: serde_json::Value = serde_json::from_str(target_state.clone().as_str()).unwrap();
+            let target_desired_state = parse_state(&environment, &target_state)?;
 
             let ovejas_root_dir = get_ovejas_root_dir();
             let state_file_path = format!("{ovejas_root_dir}/state/state.{environment}.json");
 
             let local_state = fs::read_to_string(state_file_path.clone()).expect("Failed to read local state file");
-            let local_state_json: serde_json::Value = serde_json::from_str(local_state.as_str()).unwrap();
+            let local_desired_state = parse_state(&environment, &local_state)?;
 
-            let delta = StateDelta::from_json(
-                local_state_json.as_object().unwrap()["resources"].clone(),
-                target_state_json.as_object().unwrap()["resources"].clone(),
-            );
+            let delta = StateDelta::from_resources(&local_desired_state.resources, &target_desired_state.resources);
 
-            for resource in delta.resources_to_delete {
-                let resource: Resource = serde_json::from_value(resource.clone()).unwrap();
-                resource.delete(dry_run);
+            // Updates are rolled back to the resource as it is in the state file
+            let local_resources: HashMap<&Urn, &ResourceSpec> = local_desired_state.resources
+                .iter()
+                .map(|resource| (&resource.urn, resource))
+                .collect();
 
-                debug!(deleted_resource = serde_json::to_string_pretty(&resource).unwrap());
-            }
+            let changes: Vec<ResourceChange> = delta.resources_to_delete
+                .iter()
+                .map(|resource| ResourceChange::Delete(Resource::from(resource).with_secrets(secrets)))
+                .chain(delta.resources_to_update.iter().map(|resource| ResourceChange::Update {
+                    previous: Resource::from(local_resources[&resource.urn]).with_secrets(secrets),
+                    target: Resource::from(resource).with_secrets(secrets),
+                }))
+                .chain(delta.resources_to_create.iter().map(|resource| ResourceChange::Create(Resource::from(resource).with_secrets(secrets))))
+                .collect();
 
-            for resource in delta.resources_to_update {
-                let resource: Resource = serde_json::from_value(resource.clone()).unwrap();
-                resource.update(dry_run);
-
-                debug!(updated_resource = serde_json::to_string_pretty(&resource).unwrap());
-            }
-
-            for resource in delta.resources_to_create {
-                let resource: Resource = serde_json::from_value(resource.clone()).unwrap();
-                resource.create(dry_run);
-
-                debug!(created_resource = serde_json::to_string_pretty(&resource).unwrap());
-            }
+            apply_changes(&changes, dry_run, report_progress)?;
 
             if !dry_run {
                 fs::write(state_file_path, target_state.clone().as_str())
@@ -113,35 +180,97 @@ fn process_environment_update_request(environment: String, environment_update: E
             let state_file_path = format!("{ovejas_root_dir}/state/state.{environment}.json");
 
             let local_state = fs::read_to_string(state_file_path.clone()).expect("Failed to read local state file");
-            let local_state_json: serde_json::Value = serde_json::from_str(local_state.as_str()).unwrap();
+            let local_desired_state = parse_state(&environment, &local_state)?;
 
-            let resources = local_state_json
-                .get("resources")
-                .expect("Local state doesn't have the key 'resources'")
-                .as_array()
-                .unwrap();
+            if local_desired_state.resources.is_empty() {
+                return Ok(());
+            }
+
+            let changes: Vec<ResourceChange> = local_desired_state.resources
+                .iter()
+                .map(|resource| ResourceChange::Delete(Resource::from(resource)))
+                .collect();
 
-            if resources.len() == 0 {
-                return
+            apply_changes(&changes, dry_run, report_progress)?;
+
+            if !dry_run {
+                let target_state = DesiredState {
+                    created_at: Some(Utc::now().to_string()),
+                    ..DesiredState::default()
+                };
+
+                fs::write(state_file_path, target_state.to_json())
+                    .expect(format!("Failed to write statefile({ovejas_root_dir})").as_str());
             }
+        },
+        EnvironmentUpdateOperation::Remediate => {
+            let target_state = environment_update.state.expect("Failed to get environment state");
+            let target_desired_state = parse_state(&environment, &target_state)?;
+
+            // The state file already matches, only the system is brought back
+            // to it. There is nothing to roll back to, the drift is unknown.
+            for resource in &target_desired_state.resources {
+                let resource = Resource::from(resource);
 
-            for resource in resources {
-                let resource: Resource = serde_json::from_value(resource.clone()).unwrap();
-                resource.delete(dry_run);
+                let result = match resource.detect_drift().first() {
+                    Some(ResourceDrift::Missing { .. }) => resource.with_secrets(secrets).create(dry_run),
+                    Some(ResourceDrift::Changed { .. }) => resource.with_secrets(secrets).update(dry_run),
+                    None => continue,
+                };
 
-                debug!(deleted_resource = serde_json::to_string_pretty(&resource).unwrap());
+                if let Err(err) = result {
+                    error!(environment, urn = resource.urn.to_string(), "Could not remediate drifted resource: {err}");
+                    return Err(DeviceStatus::Failed { reason: format!("Could not remediate {}: {err}", resource.urn) });
+                }
+
+                info!(environment, urn = resource.urn.to_string(), "Remediated drifted resource");
             }
+        },
+        EnvironmentUpdateOperation::Patch { base_hash, target_hash } => {
+            let state_patch = environment_update.state.expect("Failed to get environment state patch");
 
-            if !dry_run {
-                let mut target_state = serde_json::json!({ "version": 1, "resources": [] });
+            let ovejas_root_dir = get_ovejas_root_dir();
+            let state_file_path = format!("{ovejas_root_dir}/state/state.{environment}.json");
 
-                target_state["created_at"] = serde_json::Value::String(Utc::now().to_string());
+            let local_state = fs::read_to_string(state_file_path).expect("Failed to read local state file");
 
-                fs::write(state_file_path, target_state.to_string())
-                    .expect(format!("Failed to write statefile({ovejas_root_dir})").as_str());
+            // Nothing is applied, the state file keeps its hash and the
+            // server sends the full state on the next poll
+            if hash_state(&local_state) != base_hash {
+                error!(environment, "Local state is not the one the patch was built from, falling back to the full state");
+                return Err(DeviceStatus::Failed { reason: String::from("Local state is not the one the patch was built from") });
+            }
+
+            let target_state = match state_patch::apply(&local_state, &state_patch) {
+                Ok(target_state) => target_state,
+                Err(err) => {
+                    error!(environment, "{err}, falling back to the full state");
+                    return Err(DeviceStatus::Failed { reason: err.to_string() });
+                },
+            };
+
+            if hash_state(&target_state) != target_hash {
+                error!(environment, "Patched state does not match the expected hash, falling back to the full state");
+                return Err(DeviceStatus::Failed { reason: String::from("Patched state does not match the expected hash") });
             }
+
+            return process_environment_update_request(environment, EnvironmentUpdate {
+                state: Some(target_state),
+                operation: EnvironmentUpdateOperation::Update,
+                secrets: environment_update.secrets,
+                signature: environment_update.signature,
+            }, trusted_keys, report_progress);
         },
     }
+
+    Ok(())
+}
+
+fn hash_state(json: &str) -> [u8; 16] {
+    let mut hasher = Md5::new();
+    hasher.update(json);
+
+    hasher.finalize().into()
 }
 
 fn get_state_hashes() -> HashMap<String, [u8; 16]> {
@@ -164,9 +293,7 @@ fn get_state_hashes() -> HashMap<String, [u8; 16]> {
 
                 let environment = captures.get(1).unwrap().as_str();
 
-                let mut hasher = Md5::new();
-                hasher.update(local_state);
-                let state_hash: [u8; 16] = hasher.finalize().into();
+                let state_hash = hash_state(&local_state);
 
                 state_hashes.insert((environment).to_string(), state_hash);
             },
@@ -179,19 +306,159 @@ fn get_state_hashes() -> HashMap<String, [u8; 16]> {
     state_hashes
 }
 
-use tracing::{info, debug, instrument};
+fn get_state_drift() -> HashMap<String, Vec<ResourceDrift>> {
+    let ovejas_root_dir = get_ovejas_root_dir();
+    let state_dir = format!("{ovejas_root_dir}/state/");
+
+    let state_file_regex = Regex::new(r"^state.(.*).json$").unwrap();
+
+    let mut state_drift = HashMap::new();
+
+    for dir in WalkDir::new(state_dir).min_depth(1) {
+        let dir_result = dir.unwrap();
+
+        let file_name: &str = dir_result.path().file_name().unwrap().to_str().unwrap();
+        let Some(captures) = state_file_regex.captures(file_name) else {
+            continue;
+        };
+
+        let local_state = fs::read_to_string(dir_result.path())
+            .expect("Could not open local state");
+
+        let environment = captures.get(1).unwrap().as_str();
+
+        let Ok(local_desired_state) = parse_state(environment, &local_state) else {
+            continue;
+        };
+
+        let drift: Vec<ResourceDrift> = local_desired_state.resources
+            .iter()
+            .flat_map(|resource| Resource::from(resource).detect_drift())
+            .collect();
+
+        for resource_drift in &drift {
+            warn!(environment, drift = format!("{resource_drift:?}"), "Resource drifted from the state file");
+        }
+
+        state_drift.insert(environment.to_string(), drift);
+    }
+
+    state_drift
+}
+
+use tracing::{info, debug, error, warn, instrument};
 use tracing_subscriber;
 
-fn listen(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Result<(), Box<dyn std::error::Error>> {
-    let msg = socket.read()?;
+fn status_path(environment: &str) -> String {
+    format!("{}/state/status.{environment}.json", get_ovejas_root_dir())
+}
+
+// Kept next to the state file so a restarted agent reports the outcome of the
+// last update instead of `Idle`. Progress is not, an update interrupted by the
+// restart is sent again.
+fn write_status(environment: &str, status: &DeviceStatus) {
+    let status_json = serde_json::to_string(status).expect("Could not serialize status");
+
+    if let Err(err) = fs::write(status_path(environment), status_json) {
+        error!(environment, "Could not write status file: {err}");
+    }
+}
+
+fn read_statuses() -> HashMap<String, DeviceStatus> {
+    let state_dir = format!("{}/state/", get_ovejas_root_dir());
+
+    let status_file_regex = Regex::new(r"^status\.(.*)\.json$").unwrap();
+
+    let mut statuses = HashMap::new();
+
+    for dir in WalkDir::new(state_dir).min_depth(1) {
+        let dir_result = dir.unwrap();
+
+        let file_name: &str = dir_result.path().file_name().unwrap().to_str().unwrap();
+
+        let Some(captures) = status_file_regex.captures(file_name) else {
+            continue;
+        };
+
+        let environment = captures.get(1).unwrap().as_str();
 
-    if !msg.is_binary() {
-        panic!("Invalid read!");
+        let status = fs::read_to_string(dir_result.path())
+            .map_err(|err| err.to_string())
+            .and_then(|status_json| serde_json::from_str(&status_json).map_err(|err| err.to_string()));
+
+        match status {
+            Ok(status) => {
+                statuses.insert(environment.to_string(), status);
+            },
+            Err(err) => warn!(environment, "Could not read status file, reporting the environment as idle: {err}"),
+        }
     }
 
-    let op_code: RequestOperations = msg.into();
+    statuses
+}
+
+// Environments that never received an update are `Idle`.
+fn current_status(
+    state_hashes: HashMap<String, [u8; 16]>,
+    statuses: &HashMap<String, DeviceStatus>,
+    drift: Option<HashMap<String, Vec<ResourceDrift>>>,
+) -> CurrentStatusResponse {
+    let mut environment_statuses = statuses.clone();
+
+    for environment in state_hashes.keys() {
+        environment_statuses.entry(environment.clone()).or_insert(DeviceStatus::Idle);
+    }
+
+    CurrentStatusResponse {
+        statuses: environment_statuses,
+        timestamp: Utc::now().naive_utc().to_string(),
+        state_hashes,
+        drift,
+    }
+}
+
+// Sent on its own while and after applying an update, the server is not
+// waiting for a reply
+fn report_status(
+    socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    message_ids: &mut MessageIds,
+    codec: &FrameCodec,
+    statuses: &HashMap<String, DeviceStatus>,
+) {
+    let report = message_ids.request(ResponseOperations::CurrentStatus(current_status(get_state_hashes(), statuses, None)));
+
+    if let Err(err) = socket.send(codec.encode(&report)) {
+        warn!("Could not report status to remote: {err}");
+    }
+}
+
+fn listen(
+    socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    heartbeat: &mut Heartbeat,
+    drift_check: &mut DriftCheck,
+    message_ids: &mut MessageIds,
+    codec: &FrameCodec,
+    trusted_keys: Option<&[VerifyingKey]>,
+    statuses: &mut HashMap<String, DeviceStatus>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let msg = read_message(socket, heartbeat)?;
+
+    let request = match codec.decode::<RequestOperations>(msg) {
+        Ok(request) => request,
+        Err(err) => {
+            error!("Received an invalid message from the server, closing the connection: {err}");
+
+            let _ = socket.close(Some(CloseFrame {
+                code: CloseCode::Protocol,
+                reason: err.to_string().into(),
+            }));
+            let _ = socket.flush();
+
+            return Err(Box::new(err));
+        },
+    };
 
-    match op_code {
+    match request.body {
         RequestOperations::StatusRequest => {
             info!("Remote requested current state");
 
@@ -199,28 +466,54 @@ fn listen(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Result<(), Box<d
 
             info!(local_state_hashes = format!("{:?}", state_hashes.clone()));
 
-            let current_status = CurrentStatusResponse {
-                status: DeviceStatus::Idle,
-                timestamp: Utc::now().naive_utc().to_string(),
-                state_hashes,
-            };
+            let drift = drift_check.is_due().then(get_state_drift);
+
+            let current_status = current_status(state_hashes, statuses, drift);
+
+            let reply = message_ids.reply(request.id, ResponseOperations::CurrentStatus(current_status));
 
-            socket.send(current_status.into())
+            socket.send(codec.encode(&reply))
                 .expect("Could not send device status to remote");
         },
         RequestOperations::UpdateEnvironmentsRequest(environment_updates) => {
             info!("Remote requested to update current state");
 
+            let any_updates = !environment_updates.is_empty();
+
             for (environment, environment_update) in environment_updates {
                 debug!(
                     environment = environment.clone(),
                     state = serde_json::to_string_pretty(&environment_update.clone().state.unwrap()).unwrap(),
                 );
 
-                process_environment_update_request(
-                    environment,
+                let mut report_progress = |status: DeviceStatus| {
+                    statuses.insert(environment.clone(), status);
+                    report_status(socket, message_ids, codec, statuses);
+                };
+
+                let signature = environment_update.signature.clone();
+
+                let result = process_environment_update_request(
+                    environment.clone(),
                     environment_update,
+                    trusted_keys,
+                    &mut report_progress,
                 );
+
+                if let (Ok(()), false, Some(signature)) = (&result, trusted_keys.is_none(), &signature) {
+                    write_applied_signature(&environment, signature);
+                }
+
+                let status = result.err().unwrap_or(DeviceStatus::Converged);
+
+                info!(environment, status = status.to_string(), "Processed update");
+
+                write_status(&environment, &status);
+                statuses.insert(environment, status);
+            }
+
+            if any_updates {
+                report_status(socket, message_ids, codec, statuses);
             }
         },
     };
@@ -247,109 +540,226 @@ enum Action {
 }
 
 trait ResourceProvider {
-    fn create(&self);
-    fn update(&self);
-    fn delete(&self);
+    fn create(&self) -> Result<(), String>;
+    fn update(&self) -> Result<(), String>;
+    fn delete(&self) -> Result<(), String>;
+    // Parameters as they are on the system, or None if the resource doesn't exist
+    fn read(&self) -> Option<Value>;
+}
+
+// Fails with the stderr of the command when it exits with an error
+fn run_provider_command(command: &mut Command) -> Result<(), String> {
+    let result = command
+        .output()
+        .map_err(|err| format!("Failed to execute process: {err}"))?;
+
+    let stderr = String::from_utf8_lossy(&result.stderr).trim().to_string();
+
+    debug!(
+        status_code = result.status.code(),
+        stdout = String::from_utf8_lossy(&result.stdout).to_string(),
+        stderr,
+    );
+
+    if !result.status.success() {
+        return Err(format!("{} ({})", stderr, result.status));
+    }
+
+    Ok(())
 }
 
 impl ResourceProvider for User {
-    fn create(&self) {
-        let result = Command::new("useradd")
+    fn create(&self) -> Result<(), String> {
+        run_provider_command(Command::new("useradd")
             .args([
                 "--uid", self.uid.to_string().as_str(),
                 "--gid", self.gid.to_string().as_str(),
                 self.name.as_str(),
-            ])
-            .output()
-            .expect("Failed to execute process");
-
-        debug!(
-            status_code = result.status.code(),
-            stdout = String::from_utf8(result.stdout).unwrap(),
-            stderr = String::from_utf8(result.stderr).unwrap(),
-        );
+            ]))
     }
     
-    fn update(&self) {
-        let result = Command::new("usermod")
+    fn update(&self) -> Result<(), String> {
+        run_provider_command(Command::new("usermod")
             .args([
                 "--uid", self.uid.to_string().as_str(),
                 "--gid", self.gid.to_string().as_str(),
                 "--login", self.name.to_string().as_str(),
                 self.name.as_str(),
-            ])
-            .output()
-            .expect("Failed to execute process");
+            ]))
+    }
 
-        debug!(
-            status_code = result.status.code(),
-            stdout = String::from_utf8(result.stdout).unwrap(),
-            stderr = String::from_utf8(result.stderr).unwrap(),
-        );
+    fn delete(&self) -> Result<(), String> {
+        run_provider_command(Command::new("userdel")
+            .args([self.name.as_str()]))
     }
 
-    fn delete(&self) {
-        let result = Command::new("userdel")
-            .args([self.name.as_str()])
+    fn read(&self) -> Option<Value> {
+        let result = Command::new("getent")
+            .args(["passwd", self.name.as_str()])
             .output()
             .expect("Failed to execute process");
 
-        debug!(
-            status_code = result.status.code(),
-            stdout = String::from_utf8(result.stdout).unwrap(),
-            stderr = String::from_utf8(result.stderr).unwrap(),
-        );
+        if !result.status.success() {
+            return None;
+        }
+
+        // name:password:uid:gid:gecos:home:shell
+        let entry = String::from_utf8(result.stdout).ok()?;
+        let fields: Vec<&str> = entry.trim_end().split(':').collect();
+
+        Some(serde_json::json!({
+            "name": fields.first()?,
+            "uid": fields.get(2)?.parse::<u32>().ok()?,
+            "gid": fields.get(3)?.parse::<u32>().ok()?,
+        }))
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Resource {
-    urn: String,
+    urn: Urn,
     parameters: Value,
 }
 
+impl From<&ResourceSpec> for Resource {
+    fn from(resource: &ResourceSpec) -> Self {
+        Resource {
+            urn: resource.urn.clone(),
+            parameters: Value::Object(resource.parameters.clone()),
+        }
+    }
+}
+
 impl Resource {
-    fn get_provider(&self) -> Box<dyn ResourceProvider> {
-        let urn_split: Vec<&str> = self.urn.split("::").collect();
-        let [provider_module, kind, resource_id] = urn_split.try_into()
-            .expect("Invalid urn");
+    // Secrets are only resolved in memory, the state file keeps the references.
+    fn with_secrets(&self, secrets: &HashMap<String, String>) -> Resource {
+        let mut parameters = self.parameters.clone();
+
+        let _ = visit_secret_references(&mut parameters, &mut |name, reference| -> Result<(), Infallible> {
+            match secrets.get(name) {
+                Some(value) => *reference = Value::String(value.clone()),
+                None => {
+                    warn!(urn = self.urn.to_string(), "Secret '{name}' was not sent by the server");
+                    *reference = Value::Null;
+                }
+            }
 
-        let provider = match kind {
-            "User" => serde_json::from_value::<User>(self.parameters.clone()).unwrap(),
-            _ => panic!["resource does not exist"],
-        };
+            Ok(())
+        });
 
-        Box::new(provider)
+        Resource {
+            urn: self.urn.clone(),
+            parameters,
+        }
     }
-    
-    fn create(&self, dry_run: bool) {
+
+    fn detect_drift(&self) -> Vec<ResourceDrift> {
+        let provider = self.provider();
+
+        compare_resource(self.urn.to_string().as_str(), &self.parameters, provider.deref().read().as_ref())
+    }
+}
+
+// What a change needs from a resource, so any provider can be applied
+trait ProvidedResource {
+    fn urn(&self) -> &Urn;
+    fn provider(&self) -> Box<dyn ResourceProvider>;
+
+    // The provider commands fail when the system is already as asked, e.g. a
+    // user removed by hand, so the resource is read first. Otherwise the
+    // update would roll back and be sent again on every poll.
+    fn create(&self, dry_run: bool) -> Result<(), String> {
         if dry_run {
-            return;
+            return Ok(());
+        }
+
+        let provider = self.provider();
+
+        if provider.deref().read().is_some() {
+            debug!(urn = self.urn().to_string(), "Resource already exists, updating it instead");
+            return provider.deref().update();
         }
 
-        let provider = self.get_provider();
-        provider.deref().create();
+        provider.deref().create()
     }
 
-    fn delete(&self, dry_run: bool) {
+    fn delete(&self, dry_run: bool) -> Result<(), String> {
         if dry_run {
-            return;
+            return Ok(());
         }
 
-        let provider = self.get_provider();
-        provider.deref().delete();
+        let provider = self.provider();
+
+        if provider.deref().read().is_none() {
+            debug!(urn = self.urn().to_string(), "Resource is already absent");
+            return Ok(());
+        }
+
+        provider.deref().delete()
     }
 
-    fn update(&self, dry_run: bool) {
+    fn update(&self, dry_run: bool) -> Result<(), String> {
         if dry_run {
-            return;
+            return Ok(());
         }
 
-        let provider = self.get_provider();
-        provider.deref().update();
+        let provider = self.provider();
+
+        if provider.deref().read().is_none() {
+            debug!(urn = self.urn().to_string(), "Resource does not exist, creating it instead");
+            return provider.deref().create();
+        }
+
+        provider.deref().update()
+    }
+}
+
+impl ProvidedResource for Resource {
+    fn urn(&self) -> &Urn {
+        &self.urn
     }
+
+    fn provider(&self) -> Box<dyn ResourceProvider> {
+        match self.urn.kind() {
+            "User" => Box::new(serde_json::from_value::<User>(self.parameters.clone()).unwrap()),
+            _ => panic!["resource does not exist"],
+        }
+    }
+}
+
+// A step of an update, reverted by doing the opposite with the resource as
+// it was in the state file.
+enum ResourceChange<R: ProvidedResource = Resource> {
+    Create(R),
+    Update { previous: R, target: R },
+    Delete(R),
 }
 
+impl<R: ProvidedResource> Change for ResourceChange<R> {
+    fn apply(&self, dry_run: bool) -> Result<(), String> {
+        match self {
+            ResourceChange::Create(resource) => resource.create(dry_run),
+            ResourceChange::Update { target, .. } => target.update(dry_run),
+            ResourceChange::Delete(resource) => resource.delete(dry_run),
+        }
+    }
+
+    fn revert(&self, dry_run: bool) -> Result<(), String> {
+        match self {
+            ResourceChange::Create(resource) => resource.delete(dry_run),
+            ResourceChange::Update { previous, .. } => previous.update(dry_run),
+            ResourceChange::Delete(resource) => resource.create(dry_run),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            ResourceChange::Create(resource) => format!("create {}", resource.urn()),
+            ResourceChange::Update { target, .. } => format!("update {}", target.urn()),
+            ResourceChange::Delete(resource) => format!("delete {}", resource.urn()),
+        }
+    }
+}
 
 #[derive(Deserialize)]
 struct Config {
@@ -357,6 +767,17 @@ struct Config {
     address: Option<String>,
     machine_id: Option<String>,
     device_token: Option<String>,
+    join_token: Option<String>,
+    device_name: Option<String>,
+    heartbeat_interval_seconds: Option<u64>,
+    idle_timeout_seconds: Option<u64>,
+    drift_check_interval_seconds: Option<u64>,
+    max_message_size_bytes: Option<usize>,
+    // Public keys, in base64, whose signed states the agent applies
+    trusted_keys: Option<Vec<String>>,
+    // Only taken into account without trusted keys, states are refused
+    // otherwise
+    allow_unsigned_states: Option<bool>,
 }
 
 #[derive(Debug)]
@@ -370,6 +791,69 @@ struct ServerResponse {
     msg: Option<String>,
 }
 
+type ServerConnection = (WebSocket<MaybeTlsStream<TcpStream>>, Response<Option<Vec<u8>>>);
+
+struct ConnectionConfig {
+    address: String,
+    port: u64,
+    machine_id: String,
+    join_token: Option<String>,
+    device_name: Option<String>,
+}
+
+fn connect_to_server(
+    connection: &ConnectionConfig,
+    max_message_size: usize,
+    device_token: Option<&str>,
+) -> Result<ServerConnection, ServerError> {
+    let full_address = format!("{}:{}", connection.address, connection.port);
+
+    let mut request = Request::builder()
+        .uri(format!("ws://{full_address}/socket"))
+        .header("sec-websocket-key", "foo")
+        .header("upgrade", "websocket")
+        .header("host", connection.address.as_str())
+        .header("connection", "upgrade")
+        .header("machine-type", "device")
+        .header("machine-id", connection.machine_id.as_str())
+        .header("sec-websocket-version", 13);
+
+    match (device_token, &connection.join_token) {
+        (Some(device_token), _) => {
+            request = request.header("authorization", device_token);
+        },
+        (None, Some(join_token)) => {
+            request = request.header("join-token", join_token.as_str());
+
+            if let Some(device_name) = &connection.device_name {
+                request = request.header("device-name", device_name.as_str());
+            }
+        },
+        (None, None) => unreachable!(),
+    }
+
+    let request = request.body(()).unwrap();
+
+    let websocket_config = WebSocketConfig::default()
+        .max_message_size(Some(max_message_size))
+        .max_frame_size(Some(max_message_size));
+
+    connect_with_config(request, Some(websocket_config), 3).map_err(|e: tungstenite::Error| {
+        match e {
+            tungstenite::Error::Http(response) => {
+                let reason_given = response.body()
+                    .as_ref()
+                    .and_then(|body| serde_json::from_slice::<ServerResponse>(body).ok())
+                    .and_then(|response_json| response_json.msg)
+                    .unwrap_or(format!("HTTP status code {}", response.status()));
+
+                ServerError { reason_given }
+            },
+            err => ServerError { reason_given: err.to_string() }
+        }
+    })
+}
+
 fn main() {
     let ovejas_root_dir = get_ovejas_root_dir();
 
@@ -379,13 +863,40 @@ fn main() {
 
     let config: Config = Figment::new()
         .merge(Yaml::file(format!("{}/config.yaml", ovejas_root_dir.clone())))
-        .join(Env::raw().only(&["PORT", "ADDRESS", "DATABASE_PATH", "MACHINE_ID", "DEVICE_TOKEN"]))
+        .join(Env::raw().only(&[
+            "PORT",
+            "ADDRESS",
+            "DATABASE_PATH",
+            "MACHINE_ID",
+            "DEVICE_TOKEN",
+            "JOIN_TOKEN",
+            "DEVICE_NAME",
+            "HEARTBEAT_INTERVAL_SECONDS",
+            "IDLE_TIMEOUT_SECONDS",
+            "DRIFT_CHECK_INTERVAL_SECONDS",
+            "MAX_MESSAGE_SIZE_BYTES",
+            "ALLOW_UNSIGNED_STATES",
+        ]))
         .extract().unwrap();
 
-    let address = config.address.unwrap_or("localhost".into());
-    let port = config.port.unwrap_or(9734u64.into());
-    let machine_id = config.machine_id.expect("machine_id not set");
-    let device_token = config.device_token.expect("device_token not set");
+    let device_token_path = format!("{ovejas_root_dir}/{DEVICE_TOKEN_FILE}");
+
+    let mut heartbeat = Heartbeat::new(
+        Duration::from_secs(config.heartbeat_interval_seconds.unwrap_or(15)),
+        Duration::from_secs(config.idle_timeout_seconds.unwrap_or(45)),
+    );
+
+    let mut drift_check = DriftCheck::new(
+        Duration::from_secs(config.drift_check_interval_seconds.unwrap_or(300)),
+    );
+
+    // A token issued by the server when joining takes over the join token
+    let mut device_token = config.device_token
+        .or_else(|| fs::read_to_string(device_token_path.as_str()).ok().map(|token| token.trim().to_string()));
+
+    if device_token.is_none() && config.join_token.is_none() {
+        panic!("Neither device_token nor join_token are set");
+    }
 
     let state_dir = format!("{ovejas_root_dir}/state");
 
@@ -395,48 +906,224 @@ fn main() {
 
     tracing_subscriber::fmt::init();
 
-    let full_address = format!("{address}:{port}");
+    let connection = ConnectionConfig {
+        address: config.address.unwrap_or("localhost".into()),
+        port: config.port.unwrap_or(9734u64.into()),
+        machine_id: config.machine_id.expect("machine_id not set"),
+        join_token: config.join_token,
+        device_name: config.device_name,
+    };
 
-    let request = Request::builder()
-        .uri(format!("ws://{full_address}/socket"))
-        .header("sec-websocket-key", "foo")
-        .header("upgrade", "websocket")
-        .header("host", address)
-        .header("connection", "upgrade")
-        .header("machine-type", "device")
-        .header("machine-id", machine_id)
-        .header("authorization", device_token)
-        .header("sec-websocket-version", 13)
-        .body(())
-        .unwrap();
+    let max_message_size = config.max_message_size_bytes.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
 
-    let (mut websocket, response) = connect(request).map_err(|e: tungstenite::Error| {
-        match e {
-            tungstenite::Error::Http(response) => {
-                let response_body = response.body().clone().unwrap();
-                let response_json: ServerResponse = serde_json::from_str(String::from_utf8(response_body).unwrap().as_str()).expect("Could not parse server response");
+    let trusted_keys: Vec<VerifyingKey> = config.trusted_keys
+        .unwrap_or_default()
+        .iter()
+        .map(|key| verifying_key_from_base64(key).expect("Invalid trusted key"))
+        .collect();
 
-                ServerError { reason_given: response_json.msg.unwrap() }
-            },
-            _ => ServerError { reason_given: String::from("No reason given") }
-        }
-    }).expect("Could not connect to the server");
+    // Applying unsigned states as root has to be asked for explicitly
+    let trusted_keys = match (trusted_keys.is_empty(), config.allow_unsigned_states.unwrap_or_default()) {
+        (true, true) => {
+            warn!("No trusted keys configured, states are applied without verifying their signature");
+            None
+        },
+        (true, false) => {
+            warn!("No trusted keys configured, every state is refused until one is or allow_unsigned_states is set");
+            Some(trusted_keys)
+        },
+        (false, _) => Some(trusted_keys),
+    };
 
-    info!("Connected successfully to the server!");
-    info!("HTTP status code: {}", response.status());
-    info!("Response headers:");
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
 
-    for (header, _value) in response.headers() {
-        println!("* {header}");
-    }
+    // Kept across reconnections and restarts, the server is told again on the
+    // next poll
+    let mut statuses = read_statuses();
 
     loop {
-        match listen(&mut websocket) {
-            Ok(_) => {},
+        let (mut websocket, response) = match connect_to_server(&connection, max_message_size, device_token.as_deref()) {
+            Ok(connected) => connected,
             Err(err) => {
-                info!("Disconnected (Reason: {err}). Attempting to open connection...");
-                thread::sleep(Duration::from_millis(5000));
+                let delay = backoff.next_delay();
+
+                warn!("Could not connect to the server (Reason: {}). Retrying in {delay:?}...", err.reason_given);
+                thread::sleep(delay);
+
+                continue;
             }
         };
+
+        info!("Connected successfully to the server!");
+
+        if let Some(issued_token) = response.headers().get("device-token") {
+            info!("Registered with a join token, storing the issued device token");
+
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(device_token_path.as_str())
+                .and_then(|mut file| file.write_all(issued_token.as_bytes()))
+                .expect("Failed to store device token");
+
+            // Reconnections authenticate with the issued token, the join token may be used up
+            device_token = issued_token.to_str().ok().map(String::from);
+        }
+
+        info!("HTTP status code: {}", response.status());
+        info!("Response headers:");
+
+        for (header, _value) in response.headers() {
+            println!("* {header}");
+        }
+
+        heartbeat.apply(&mut websocket).expect("Failed to set socket read timeout");
+
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            provider_kinds: PROVIDER_KINDS.iter().map(|kind| kind.to_string()).collect(),
+            max_state_version: STATE_VERSION,
+            compression: SUPPORTED_COMPRESSIONS.iter().map(|compression| compression.as_str().to_string()).collect(),
+        };
+
+        let codec = match handshake(&mut websocket, &mut heartbeat, hello) {
+            Ok(hello_response) => {
+                info!(
+                    protocol_version = hello_response.protocol_version,
+                    server_version = hello_response.server_version,
+                    compression = hello_response.compression.map(|compression| compression.as_str()),
+                    "Handshake completed",
+                );
+
+                FrameCodec {
+                    compression: hello_response.compression,
+                    max_message_size,
+                }
+            },
+            Err(err) => {
+                let delay = backoff.next_delay();
+
+                error!("Server refused the connection (Reason: {err}). Retrying in {delay:?}...");
+                thread::sleep(delay);
+
+                continue;
+            },
+        };
+
+        backoff.reset();
+
+        let mut message_ids = MessageIds::default();
+
+        loop {
+            if let Err(err) = listen(&mut websocket, &mut heartbeat, &mut drift_check, &mut message_ids, &codec, trusted_keys.as_deref(), &mut statuses) {
+                info!("Disconnected (Reason: {err}). Attempting to open connection...");
+                break;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    use serde_json::{json, Value};
+    use shared::desired_state::Urn;
+
+    use device::apply::apply_changes;
+
+    use super::{ProvidedResource, ResourceChange, ResourceProvider};
+
+    thread_local! {
+        static EXISTING: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+        static COMMANDS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // Fails like `useradd` and `userdel` when the system is already as asked
+    #[derive(Clone)]
+    struct FakeResource {
+        urn: Urn,
+        name: String,
+    }
+
+    impl FakeResource {
+        // Runs the command if the resource exists as expected before it
+        fn run(&self, command: &str, exists_before: bool, exists_after: bool) -> Result<(), String> {
+            COMMANDS.with_borrow_mut(|commands| commands.push(format!("{command} {}", self.name)));
+
+            EXISTING.with_borrow_mut(|existing| {
+                if existing.contains(&self.name) != exists_before {
+                    return Err(format!("{command} {} failed", self.name));
+                }
+
+                if exists_after { existing.insert(self.name.clone()) } else { existing.remove(&self.name) };
+
+                Ok(())
+            })
+        }
+    }
+
+    impl ResourceProvider for FakeResource {
+        fn create(&self) -> Result<(), String> {
+            self.run("create", false, true)
+        }
+
+        fn update(&self) -> Result<(), String> {
+            self.run("update", true, true)
+        }
+
+        fn delete(&self) -> Result<(), String> {
+            self.run("delete", true, false)
+        }
+
+        fn read(&self) -> Option<Value> {
+            EXISTING.with_borrow(|existing| existing.contains(&self.name)).then(|| json!({ "name": self.name }))
+        }
+    }
+
+    impl ProvidedResource for FakeResource {
+        fn urn(&self) -> &Urn {
+            &self.urn
+        }
+
+        fn provider(&self) -> Box<dyn ResourceProvider> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn resource(name: &str) -> FakeResource {
+        FakeResource {
+            urn: Urn::from_str(&format!("test::Fake::{name}")).unwrap(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn changes_already_on_the_system_are_not_rolled_back() {
+        EXISTING.with_borrow_mut(|existing| existing.extend([String::from("a"), String::from("c")]));
+
+        // `b` was removed by hand, deleting it again is not a failure
+        let destroy = [ResourceChange::Delete(resource("a")), ResourceChange::Delete(resource("b")), ResourceChange::Delete(resource("c"))];
+
+        assert_eq!(apply_changes(&destroy, false, &mut |_| {}), Ok(()));
+        assert!(EXISTING.with_borrow(|existing| existing.is_empty()));
+        assert_eq!(COMMANDS.take(), ["delete a", "delete c"]);
+
+        // `d` was created by hand and `e` removed, the update brings both to the target
+        EXISTING.with_borrow_mut(|existing| existing.insert(String::from("d")));
+
+        let update = [
+            ResourceChange::Create(resource("d")),
+            ResourceChange::Update { previous: resource("e"), target: resource("e") },
+        ];
+
+        assert_eq!(apply_changes(&update, false, &mut |_| {}), Ok(()));
+        assert_eq!(COMMANDS.take(), ["update d", "create e"]);
     }
 }
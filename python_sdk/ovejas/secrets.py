@@ -0,0 +1,4 @@
+SECRET_MARKER = '$secret'
+
+def secret(name: str) -> dict[str, str]:
+    return {SECRET_MARKER: name}
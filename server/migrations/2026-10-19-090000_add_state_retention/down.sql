@@ -0,0 +1,4 @@
+ALTER TABLE environments_devices DROP reported_state_hash;
+
+ALTER TABLE environments DROP retention_max_age_days;
+ALTER TABLE environments DROP retention_keep_last;
@@ -0,0 +1,4 @@
+ALTER TABLE environments ADD retention_keep_last INTEGER;
+ALTER TABLE environments ADD retention_max_age_days INTEGER;
+
+ALTER TABLE environments_devices ADD reported_state_hash VARCHAR;
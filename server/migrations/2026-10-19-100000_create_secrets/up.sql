@@ -0,0 +1,15 @@
+CREATE TABLE secrets (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+
+    name VARCHAR NOT NULL,
+    nonce BLOB NOT NULL,
+    ciphertext BLOB NOT NULL,
+    revision INTEGER NOT NULL DEFAULT 1,
+
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    updated_at DATETIME,
+
+    environment_id INTEGER NOT NULL,
+    FOREIGN KEY(environment_id) REFERENCES environments(id),
+    UNIQUE(environment_id, name)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE environments_devices DROP sent_state_hash;
+ALTER TABLE environments_devices DROP sent_state_id;
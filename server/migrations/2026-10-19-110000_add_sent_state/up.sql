@@ -0,0 +1,2 @@
+ALTER TABLE environments_devices ADD sent_state_id INTEGER REFERENCES states(id) ON DELETE SET NULL;
+ALTER TABLE environments_devices ADD sent_state_hash VARCHAR;
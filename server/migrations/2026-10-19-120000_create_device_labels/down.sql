@@ -0,0 +1,5 @@
+ALTER TABLE environments_devices DROP via_selector;
+
+ALTER TABLE environments DROP label_selector;
+
+DROP TABLE device_labels;
@@ -0,0 +1,17 @@
+CREATE TABLE device_labels (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+
+    key VARCHAR NOT NULL,
+    value VARCHAR NOT NULL,
+
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    updated_at DATETIME,
+
+    device_id INTEGER NOT NULL,
+    FOREIGN KEY(device_id) REFERENCES devices(id),
+    UNIQUE(device_id, key)
+);
+
+ALTER TABLE environments ADD label_selector VARCHAR;
+
+ALTER TABLE environments_devices ADD via_selector BOOLEAN NOT NULL DEFAULT 0;
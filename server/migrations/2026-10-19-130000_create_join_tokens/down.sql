@@ -0,0 +1,3 @@
+ALTER TABLE devices DROP token_hash;
+
+DROP TABLE join_tokens;
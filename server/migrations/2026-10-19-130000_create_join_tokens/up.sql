@@ -0,0 +1,15 @@
+CREATE TABLE join_tokens (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+
+    token_hash VARCHAR NOT NULL UNIQUE,
+    max_uses INTEGER NOT NULL,
+    uses INTEGER NOT NULL DEFAULT 0,
+    expires_at DATETIME NOT NULL,
+
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+    environment_id INTEGER NOT NULL,
+    FOREIGN KEY(environment_id) REFERENCES environments(id)
+);
+
+ALTER TABLE devices ADD token_hash VARCHAR;
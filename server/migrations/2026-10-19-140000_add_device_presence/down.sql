@@ -0,0 +1,2 @@
+ALTER TABLE devices DROP last_seen_at;
+ALTER TABLE devices DROP online;
@@ -0,0 +1,2 @@
+ALTER TABLE devices ADD online BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE devices ADD last_seen_at DATETIME;
@@ -0,0 +1,4 @@
+ALTER TABLE environments_devices DROP apply_pending;
+
+DROP TABLE webhook_deliveries;
+DROP TABLE webhooks;
@@ -0,0 +1,31 @@
+CREATE TABLE webhooks (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+
+    url VARCHAR NOT NULL,
+    events VARCHAR NOT NULL,
+    secret VARCHAR NOT NULL,
+
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+
+    project_id INTEGER NOT NULL,
+    FOREIGN KEY(project_id) REFERENCES projects(id)
+);
+
+CREATE TABLE webhook_deliveries (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+
+    event VARCHAR NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    delivered BOOLEAN NOT NULL DEFAULT 0,
+    response_status INTEGER,
+    error VARCHAR,
+
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    updated_at DATETIME,
+
+    webhook_id INTEGER NOT NULL,
+    FOREIGN KEY(webhook_id) REFERENCES webhooks(id)
+);
+
+ALTER TABLE environments_devices ADD apply_pending BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE states DROP promoted_from_state_id;
@@ -0,0 +1 @@
+ALTER TABLE states ADD promoted_from_state_id INTEGER;
@@ -0,0 +1,4 @@
+ALTER TABLE states DROP override_window;
+
+ALTER TABLE environments DROP maintenance_timezone;
+ALTER TABLE environments DROP maintenance_windows;
@@ -0,0 +1,4 @@
+ALTER TABLE environments ADD maintenance_windows VARCHAR;
+ALTER TABLE environments ADD maintenance_timezone VARCHAR;
+
+ALTER TABLE states ADD override_window BOOLEAN NOT NULL DEFAULT 0;
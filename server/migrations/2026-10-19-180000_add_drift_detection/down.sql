@@ -0,0 +1,4 @@
+ALTER TABLE environments_devices DROP drift_checked_at;
+ALTER TABLE environments_devices DROP drift;
+
+ALTER TABLE environments DROP drift_mode;
@@ -0,0 +1,4 @@
+ALTER TABLE environments ADD drift_mode VARCHAR NOT NULL DEFAULT 'report';
+
+ALTER TABLE environments_devices ADD drift TEXT;
+ALTER TABLE environments_devices ADD drift_checked_at DATETIME;
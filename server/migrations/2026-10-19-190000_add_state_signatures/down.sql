@@ -0,0 +1,2 @@
+ALTER TABLE states DROP signed_by;
+ALTER TABLE states DROP signature;
@@ -0,0 +1,2 @@
+ALTER TABLE states ADD signature VARCHAR;
+ALTER TABLE states ADD signed_by VARCHAR;
@@ -0,0 +1,2 @@
+ALTER TABLE environments_devices DROP status_updated_at;
+ALTER TABLE environments_devices DROP status;
@@ -0,0 +1,2 @@
+ALTER TABLE environments_devices ADD status TEXT;
+ALTER TABLE environments_devices ADD status_updated_at DATETIME;
@@ -0,0 +1 @@
+ALTER TABLE states DROP sequence;
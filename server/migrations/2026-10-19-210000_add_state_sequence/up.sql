@@ -0,0 +1,2 @@
+ALTER TABLE states ADD sequence BIGINT NOT NULL DEFAULT 0;
+UPDATE states SET sequence = id;
@@ -0,0 +1,181 @@
+use std::fmt;
+
+use deadpool_diesel::sqlite::Pool;
+use shared::admin_operations::{AdminDeviceAction, AdminUserAction};
+
+use crate::repository::{device_create, device_delete, device_exists, user_create, user_delete, user_exists, user_list};
+use crate::tokens::{generate_token, hash_token};
+
+#[derive(Debug, PartialEq)]
+pub enum AdminError {
+    UserExists(String),
+    UserNotFound(String),
+    DeviceExists(String),
+    DeviceNotFound(String),
+    MissingDeviceName,
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminError::UserExists(name) => write!(f, "User '{name}' already exists"),
+            AdminError::UserNotFound(name) => write!(f, "User '{name}' not found"),
+            AdminError::DeviceExists(machine_id) => write!(f, "A device with machine-id '{machine_id}' already exists"),
+            AdminError::DeviceNotFound(machine_id) => write!(f, "No device with machine-id '{machine_id}'"),
+            AdminError::MissingDeviceName => write!(f, "A name is required to create a device"),
+        }
+    }
+}
+
+impl std::error::Error for AdminError {}
+
+// Admin actions run by the server binary straight against the database, which
+// is the only way to create the first user. They return the lines to show to
+// the operator, generated tokens are only shown there.
+pub async fn run_user_action(action: AdminUserAction, database_pool: Pool) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    match action {
+        AdminUserAction::CreateUser(message) => {
+            if user_exists(message.username.clone(), database_pool.clone()).await? {
+                return Err(AdminError::UserExists(message.username).into());
+            }
+
+            let generated_token = message.access_token.is_none().then(generate_token);
+            let access_token = message.access_token.or(generated_token.clone()).unwrap_or_default();
+
+            user_create(message.username.clone(), hash_token(access_token.as_str()), database_pool).await?;
+
+            let mut output = vec![format!("Created user '{}'", message.username)];
+            output.extend(generated_token.map(|token| format!("Access token: {token}")));
+
+            Ok(output)
+        },
+        AdminUserAction::DeleteUser(message) => {
+            if user_delete(message.username.clone(), database_pool).await? == 0 {
+                return Err(AdminError::UserNotFound(message.username).into());
+            }
+
+            Ok(vec![format!("Deleted user '{}'", message.username)])
+        },
+        AdminUserAction::ListUsers => {
+            let users = user_list(database_pool).await?;
+
+            Ok(users
+                .iter()
+                .map(|user| format!("{}\t{}\t{}", user.id, user.name, user.created_at))
+                .collect())
+        },
+    }
+}
+
+pub async fn run_device_action(action: AdminDeviceAction, database_pool: Pool) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    match action {
+        AdminDeviceAction::CreateDevice(message) => {
+            let name = message.name.ok_or(AdminError::MissingDeviceName)?;
+
+            if device_exists(message.machine_id.clone(), database_pool.clone()).await? {
+                return Err(AdminError::DeviceExists(message.machine_id).into());
+            }
+
+            let generated_token = message.token.is_none().then(generate_token);
+            let device_token = message.token.or(generated_token.clone()).unwrap_or_default();
+
+            device_create(
+                name.clone(),
+                message.machine_id.clone(),
+                hash_token(device_token.as_str()),
+                database_pool,
+            ).await?;
+
+            let mut output = vec![format!("Created device '{name}' with machine-id '{}'", message.machine_id)];
+            output.extend(generated_token.map(|token| format!("Device token: {token}")));
+
+            Ok(output)
+        },
+        AdminDeviceAction::DeleteDevice(message) => {
+            if device_delete(message.machine_id.clone(), database_pool).await? == 0 {
+                return Err(AdminError::DeviceNotFound(message.machine_id).into());
+            }
+
+            Ok(vec![format!("Deleted device with machine-id '{}'", message.machine_id)])
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::admin_operations::{AdminDeviceAction, AdminDeviceOperationMessage, AdminUserAction, AdminUserOperationMessage};
+
+    use std::collections::HashMap;
+
+    use diesel::prelude::*;
+
+    use crate::repository::tests::{create_environment, devices, enrollments, test_pool};
+    use crate::repository::{device_set_labels, enroll_device_into_environment, user_list};
+    use crate::schema::device_labels;
+    use crate::tokens::hash_token;
+
+    use super::{run_device_action, run_user_action, AdminError};
+
+    fn create_device(machine_id: &str) -> AdminDeviceAction {
+        AdminDeviceAction::CreateDevice(AdminDeviceOperationMessage {
+            machine_id: machine_id.to_string(),
+            name: Some(format!("device-{machine_id}")),
+            token: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn generated_tokens_are_shown_once_and_stored_hashed() {
+        let pool = test_pool().await;
+
+        let output = run_device_action(create_device("m1"), pool.clone()).await.unwrap();
+        let device_token = output[1].strip_prefix("Device token: ").unwrap();
+
+        assert_eq!(devices(&pool).await[0].token_hash, Some(hash_token(device_token)));
+
+        let create_user = |access_token: Option<&str>| AdminUserAction::CreateUser(AdminUserOperationMessage {
+            username: String::from("admin"),
+            access_token: access_token.map(String::from),
+        });
+
+        let output = run_user_action(create_user(None), pool.clone()).await.unwrap();
+        let access_token = output[1].strip_prefix("Access token: ").unwrap();
+
+        assert_eq!(user_list(pool.clone()).await.unwrap()[0].access_token, hash_token(access_token));
+
+        let result = run_user_action(create_user(Some("given")), pool.clone()).await;
+        assert_eq!(result.unwrap_err().downcast_ref::<AdminError>(), Some(&AdminError::UserExists(String::from("admin"))));
+    }
+
+    #[tokio::test]
+    async fn device_delete_matches_the_exact_machine_id() {
+        let pool = test_pool().await;
+
+        run_device_action(create_device("m1"), pool.clone()).await.unwrap();
+
+        let environment_id = create_environment(&pool, "web", "prod", None).await;
+        enroll_device_into_environment(String::from("m1"), String::from("web"), String::from("prod"), pool.clone()).await.unwrap();
+        device_set_labels(String::from("m1"), HashMap::from([(String::from("role"), String::from("edge"))]), Vec::new(), pool.clone()).await.unwrap();
+        assert_eq!(enrollments(&pool, environment_id).await.len(), 1);
+
+        let delete_device = |machine_id: &str| AdminDeviceAction::DeleteDevice(AdminDeviceOperationMessage {
+            machine_id: machine_id.to_string(),
+            name: None,
+            token: None,
+        });
+
+        let result = run_device_action(delete_device("%"), pool.clone()).await;
+        assert_eq!(result.unwrap_err().downcast_ref::<AdminError>(), Some(&AdminError::DeviceNotFound(String::from("%"))));
+        assert_eq!(devices(&pool).await.len(), 1);
+
+        run_device_action(delete_device("m1"), pool.clone()).await.unwrap();
+        assert!(devices(&pool).await.is_empty());
+
+        // Nothing is left pointing at the deleted device
+        assert!(enrollments(&pool, environment_id).await.is_empty());
+
+        let conn = pool.get().await.unwrap();
+        let label_count: i64 = conn.interact(|conn| device_labels::table.count().get_result(conn)).await.unwrap().unwrap();
+        assert_eq!(label_count, 0);
+    }
+}
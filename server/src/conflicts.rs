@@ -0,0 +1,127 @@
+use std::fmt;
+
+use serde_json::Value;
+use shared::desired_state::DesiredState;
+
+// What a resource takes on the device. The agent applies every environment
+// into its own state file, so two environments claiming the same thing keep
+// overwriting each other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceClaim {
+    pub environment: String,
+    pub urn: String,
+    pub user_name: Option<String>,
+    pub uid: Option<i64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ResourceConflict {
+    Urn { urn: String, environments: [String; 2] },
+    UserName { name: String, environments: [String; 2] },
+    Uid { uid: i64, environments: [String; 2] },
+}
+
+impl fmt::Display for ResourceConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceConflict::Urn { urn, environments: [a, b] } => {
+                write!(f, "URN '{urn}' is declared by both '{a}' and '{b}'")
+            },
+            ResourceConflict::UserName { name, environments: [a, b] } => {
+                write!(f, "User name '{name}' is declared by both '{a}' and '{b}'")
+            },
+            ResourceConflict::Uid { uid, environments: [a, b] } => {
+                write!(f, "uid {uid} is declared by both '{a}' and '{b}'")
+            },
+        }
+    }
+}
+
+pub fn resource_claims(environment: &str, state_json: &str) -> Vec<ResourceClaim> {
+    let Ok(state) = DesiredState::from_json(state_json) else {
+        return vec![];
+    };
+
+    state.resources
+        .into_iter()
+        .map(|resource| {
+            let is_user = resource.urn.kind() == "User";
+
+            let user_name = resource.parameters
+                .get("name")
+                .and_then(Value::as_str)
+                .filter(|_| is_user)
+                .map(String::from);
+
+            let uid = resource.parameters
+                .get("uid")
+                .and_then(Value::as_i64)
+                .filter(|_| is_user);
+
+            ResourceClaim {
+                environment: environment.to_string(),
+                urn: resource.urn.to_string(),
+                user_name,
+                uid,
+            }
+        })
+        .collect()
+}
+
+pub fn find_conflicts(claims: &[ResourceClaim], other_claims: &[ResourceClaim]) -> Vec<ResourceConflict> {
+    let mut conflicts = Vec::new();
+
+    for claim in claims {
+        for other_claim in other_claims.iter().filter(|other_claim| other_claim.environment != claim.environment) {
+            let environments = [claim.environment.clone(), other_claim.environment.clone()];
+
+            if claim.urn == other_claim.urn {
+                conflicts.push(ResourceConflict::Urn { urn: claim.urn.clone(), environments });
+                continue;
+            }
+
+            if let (Some(name), Some(other_name)) = (&claim.user_name, &other_claim.user_name) {
+                if name == other_name {
+                    conflicts.push(ResourceConflict::UserName { name: name.clone(), environments: environments.clone() });
+                }
+            }
+
+            if let (Some(uid), Some(other_uid)) = (claim.uid, other_claim.uid) {
+                if uid == other_uid {
+                    conflicts.push(ResourceConflict::Uid { uid, environments });
+                }
+            }
+        }
+    }
+
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_conflicts, resource_claims, ResourceConflict};
+
+    #[test]
+    fn conflicting_users_across_environments() {
+        let web = resource_claims("web/prod", r#"{"resources":[
+            {"urn":"ovejas.system::User::admin","parameters":{"name":"admin","uid":1000,"gid":1000}},
+            {"urn":"ovejas.system::User::deploy","parameters":{"name":"deploy","uid":1001,"gid":1001}}
+        ]}"#);
+
+        let metrics = resource_claims("metrics/prod", r#"{"resources":[
+            {"urn":"ovejas.system::User::admin","parameters":{"name":"admin","uid":2000,"gid":2000}},
+            {"urn":"ovejas.system::User::collector","parameters":{"name":"deploy","uid":1001,"gid":1001}},
+            {"urn":"ovejas.system::User::grafana","parameters":{"name":"grafana","uid":3000,"gid":3000}}
+        ]}"#);
+
+        let environments = [String::from("metrics/prod"), String::from("web/prod")];
+
+        assert_eq!(find_conflicts(&metrics, &web), vec![
+            ResourceConflict::Urn { urn: String::from("ovejas.system::User::admin"), environments: environments.clone() },
+            ResourceConflict::UserName { name: String::from("deploy"), environments: environments.clone() },
+            ResourceConflict::Uid { uid: 1001, environments },
+        ]);
+
+        assert!(find_conflicts(&web, &web).is_empty());
+    }
+}
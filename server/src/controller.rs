@@ -1,9 +1,18 @@
+use chrono::{TimeDelta, Utc};
 use deadpool_diesel::sqlite::Pool;
 use http_body_util::BodyExt;
-use hyper::{body::Incoming, Method, Request, Response, StatusCode};
-use shared::rest_dtos::{DeviceCreateDTO, DeviceDeleteDTO, EnrollDeviceDTO};
+use hyper::{body::Incoming, HeaderMap, Method, Request, Response, StatusCode};
+use shared::rest_dtos::{DeviceCreateDTO, DeviceDeleteDTO, DeviceLabelsDTO, EnrollDeviceDTO, EnvironmentDriftDTO, EnvironmentDriftModeDTO, EnvironmentMaintenanceDTO, EnvironmentPromoteDTO, EnvironmentRetentionDTO, EnvironmentSelectorDTO, EnvironmentSequenceDTO, EnvironmentStatusDTO, JoinTokenCreateDTO, SecretDeleteDTO, SecretListDTO, SecretSetDTO, WebhookCreateDTO, WebhookDeleteDTO, WebhookDeliveryListDTO, WebhookListDTO};
 
-use crate::repository::{device_create, device_delete, enroll_device_into_environment, user_create};
+use crate::drift::DriftMode;
+use crate::labels::{is_valid_label, LabelSelector};
+use crate::maintenance::MaintenanceSchedule;
+use crate::repository::{device_create, device_delete, device_set_labels, enroll_device_into_environment, environment_drift, environment_promote, environment_set_drift_mode, environment_set_label_selector, environment_set_maintenance_windows, EnrollError, PromoteError, environment_set_retention, environment_sequence, environment_status, join_token_create, secret_delete, secret_list, secret_set, user_create, user_find_by_token_hash, webhook_create, webhook_delete, webhook_delivery_list, webhook_list};
+use crate::secrets::{SecretError, SecretsCipher};
+use crate::tokens::{generate_token, hash_token};
+use crate::webhooks::{WebhookEvent, WebhookNotifier};
+
+const WEBHOOK_DELIVERIES_LIMIT: i64 = 20;
 
 pub fn json_response(status_code: StatusCode, msg: String, data: serde_json::Value) -> Response<http_body_util::Full<tokio_tungstenite::tungstenite::Bytes>> {
     let mut payload = serde_json::json!({});
@@ -20,7 +29,25 @@ pub fn json_response(status_code: StatusCode, msg: String, data: serde_json::Val
         .expect("Failed to build response");
 }
 
-pub async fn handle_http_connection(req: &mut Request<Incoming>, database_pool: Pool) -> Response<http_body_util::Full<tokio_tungstenite::tungstenite::Bytes>> { 
+// Every route needs the access token of a user in the `authorization` header,
+// they register devices and hand out their tokens
+pub async fn authorize_user(headers: &HeaderMap, database_pool: Pool) -> Result<(), Response<http_body_util::Full<tokio_tungstenite::tungstenite::Bytes>>> {
+    let Some(access_token) = headers.get("authorization").and_then(|header| header.to_str().ok()) else {
+        return Err(json_response(StatusCode::UNAUTHORIZED, String::from("Missing access token"), serde_json::Value::Null));
+    };
+
+    match user_find_by_token_hash(hash_token(access_token), database_pool).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(json_response(StatusCode::UNAUTHORIZED, String::from("Invalid access token"), serde_json::Value::Null)),
+        Err(err) => Err(json_response(StatusCode::INTERNAL_SERVER_ERROR, err.to_string(), serde_json::Value::Null)),
+    }
+}
+
+pub async fn handle_http_connection(req: &mut Request<Incoming>, database_pool: Pool, secrets_cipher: Option<SecretsCipher>, webhooks: WebhookNotifier) -> Response<http_body_util::Full<tokio_tungstenite::tungstenite::Bytes>> { 
+    if let Err(response) = authorize_user(req.headers(), database_pool.clone()).await {
+        return response;
+    }
+
     let (uri, method) = (req.uri().clone().to_string(), req.method().clone());
 
     let body: Vec<u8> = req.collect()
@@ -34,15 +61,23 @@ pub async fn handle_http_connection(req: &mut Request<Incoming>, database_pool:
         ("/device", Method::POST) => {
             let json: DeviceCreateDTO = serde_json::from_slice(body.as_slice()).unwrap();
 
-            let result = device_create(json.name, json.machine_id, database_pool).await;
+            // The agent has to present this token, only its hash is stored
+            let device_token = generate_token();
 
-            println!("device create result {result:?}");
-            
-            return json_response(
-                StatusCode::OK,
-                String::from("Created device successfully"),
-                serde_json::Value::Null,
-            )
+            let result = device_create(json.name, json.machine_id, hash_token(device_token.as_str()), database_pool).await;
+
+            match result {
+                Ok(()) => json_response(
+                   StatusCode::OK,
+                   String::from("Created device successfully"),
+                   serde_json::json!({ "token": device_token }),
+                ),
+                Err(err) => json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                ),
+            }
         },
         ("/device", Method::DELETE) => {
             let json: DeviceDeleteDTO = serde_json::from_slice(body.as_slice()).unwrap();
@@ -81,20 +116,577 @@ pub async fn handle_http_connection(req: &mut Request<Incoming>, database_pool:
                 database_pool
             ).await;
 
-            return if let Err(err) = result {
+            match result {
+                Ok(()) => json_response(
+                   StatusCode::OK,
+                   String::from("Device enrolled successfully"),
+                   serde_json::Value::Null,
+                ),
+                Err(EnrollError::Conflicts(conflicts)) => json_response(
+                   StatusCode::CONFLICT,
+                   String::from("Environment conflicts with resources already on the device"),
+                   conflicts.iter().map(ToString::to_string).collect::<Vec<String>>().into(),
+                ),
+                Err(err) => json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                ),
+            }
+        },
+        ("/environment/retention", Method::POST) => {
+            let json: EnvironmentRetentionDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            let result = environment_set_retention(
+                json.project_name,
+                json.environment_name,
+                json.keep_last,
+                json.max_age_days,
+                database_pool
+            ).await;
+
+            if let Err(err) = result {
                 json_response(
                    StatusCode::INTERNAL_SERVER_ERROR,
-                   String::from(err.to_string()),
+                   err.to_string(),
                    serde_json::Value::Null,
                 )
             } else {
                 json_response(
                    StatusCode::OK,
-                   String::from("Device enrolled successfully"),
+                   String::from("Retention policy updated successfully"),
+                   serde_json::Value::Null,
+                )
+            }
+        },
+        ("/environment/maintenance", Method::POST) => {
+            let json: EnvironmentMaintenanceDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            if let Err(err) = MaintenanceSchedule::parse(json.windows.join(";").as_str(), json.timezone.as_deref()) {
+                return json_response(
+                   StatusCode::BAD_REQUEST,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                )
+            }
+
+            let result = environment_set_maintenance_windows(
+                json.project_name,
+                json.environment_name,
+                json.windows,
+                json.timezone,
+                database_pool
+            ).await;
+
+            if let Err(err) = result {
+                json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                )
+            } else {
+                json_response(
+                   StatusCode::OK,
+                   String::from("Maintenance windows updated successfully"),
+                   serde_json::Value::Null,
+                )
+            }
+        },
+        ("/environment/drift-mode", Method::POST) => {
+            let json: EnvironmentDriftModeDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            let drift_mode: DriftMode = match json.mode.parse() {
+                Ok(drift_mode) => drift_mode,
+                Err(err) => return json_response(
+                   StatusCode::BAD_REQUEST,
+                   err,
+                   serde_json::Value::Null,
+                ),
+            };
+
+            let result = environment_set_drift_mode(
+                json.project_name,
+                json.environment_name,
+                drift_mode,
+                database_pool
+            ).await;
+
+            if let Err(err) = result {
+                json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                )
+            } else {
+                json_response(
+                   StatusCode::OK,
+                   String::from("Drift mode updated successfully"),
+                   serde_json::Value::Null,
+                )
+            }
+        },
+        ("/environment/drift", Method::GET) => {
+            let json: EnvironmentDriftDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            let result = environment_drift(json.project_name, json.environment_name, database_pool).await;
+
+            match result {
+                Ok(device_drift) => {
+                    let device_drift: Vec<serde_json::Value> = device_drift
+                        .iter()
+                        .map(|(device, enrollment)| serde_json::json!({
+                            "name": device.name,
+                            "machine_id": device.machine_id,
+                            "checked_at": enrollment.drift_checked_at.map(|checked_at| checked_at.to_string()),
+                            "drift": enrollment.drift
+                                .as_deref()
+                                .and_then(|drift| serde_json::from_str::<serde_json::Value>(drift).ok()),
+                        }))
+                        .collect();
+
+                    json_response(
+                       StatusCode::OK,
+                       String::from("Drift listed successfully"),
+                       device_drift.into(),
+                    )
+                },
+                Err(err) => json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                ),
+            }
+        },
+        ("/environment/status", Method::GET) => {
+            let json: EnvironmentStatusDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            let result = environment_status(json.project_name, json.environment_name, database_pool).await;
+
+            match result {
+                Ok(device_status) => {
+                    let device_status: Vec<serde_json::Value> = device_status
+                        .iter()
+                        .map(|(device, enrollment)| serde_json::json!({
+                            "name": device.name,
+                            "machine_id": device.machine_id,
+                            "online": device.online,
+                            "status": enrollment.status
+                                .as_deref()
+                                .and_then(|status| serde_json::from_str::<serde_json::Value>(status).ok()),
+                            "status_updated_at": enrollment.status_updated_at.map(|updated_at| updated_at.to_string()),
+                        }))
+                        .collect();
+
+                    json_response(
+                       StatusCode::OK,
+                       String::from("Status listed successfully"),
+                       device_status.into(),
+                    )
+                },
+                Err(err) => json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                ),
+            }
+        },
+        ("/environment/sequence", Method::GET) => {
+            let json: EnvironmentSequenceDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            match environment_sequence(json.project_name, json.environment_name, database_pool).await {
+                Ok(sequence) => json_response(
+                   StatusCode::OK,
+                   String::from("Sequence to sign the next state with"),
+                   sequence.into(),
+                ),
+                Err(err) => json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                ),
+            }
+        },
+        ("/environment/promote", Method::POST) => {
+            let json: EnvironmentPromoteDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            if json.from_environment == json.to_environment {
+                return json_response(
+                   StatusCode::BAD_REQUEST,
+                   String::from("Source and target environments must be different"),
+                   serde_json::Value::Null,
+                )
+            }
+
+            let result = environment_promote(
+                json.project_name,
+                json.from_environment,
+                json.to_environment.clone(),
+                json.source_state_id,
+                json.dry_run,
+                database_pool
+            ).await;
+
+            match result {
+                Ok(promotion) => {
+                    if let Some(state_id) = promotion.promoted_state_id {
+                        webhooks.notify(WebhookEvent::StatePushed, promotion.project_id, serde_json::json!({
+                            "operation": "promote",
+                            "environment": json.to_environment,
+                            "state_id": state_id,
+                            "promoted_from_state_id": promotion.source_state_id,
+                        }));
+                    }
+
+                    json_response(
+                       StatusCode::OK,
+                       String::from(if json.dry_run { "Promotion preview" } else { "State promoted successfully" }),
+                       serde_json::json!({
+                           "source_state_id": promotion.source_state_id,
+                           "promoted_state_id": promotion.promoted_state_id,
+                           "changes": promotion.changes,
+                       }),
+                    )
+                },
+                Err(err) => match err.downcast_ref::<PromoteError>() {
+                    Some(PromoteError::Conflicts(conflicts)) => json_response(
+                       StatusCode::CONFLICT,
+                       err.to_string(),
+                       conflicts.clone().into(),
+                    ),
+                    Some(PromoteError::SourceChanged { .. }) => json_response(
+                       StatusCode::CONFLICT,
+                       err.to_string(),
+                       serde_json::Value::Null,
+                    ),
+                    Some(PromoteError::MissingSourceState) => json_response(
+                       StatusCode::BAD_REQUEST,
+                       err.to_string(),
+                       serde_json::Value::Null,
+                    ),
+                    _ => json_response(
+                       StatusCode::INTERNAL_SERVER_ERROR,
+                       err.to_string(),
+                       serde_json::Value::Null,
+                    ),
+                },
+            }
+        },
+        ("/device/labels", Method::POST) => {
+            let json: DeviceLabelsDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            if let Some((key, value)) = json.set.iter().find(|(key, value)| !is_valid_label(key, value)) {
+                return json_response(
+                   StatusCode::BAD_REQUEST,
+                   format!("Invalid label '{key}={value}'"),
+                   serde_json::Value::Null,
+                )
+            }
+
+            let result = device_set_labels(json.machine_id, json.set, json.remove, database_pool).await;
+
+            if let Err(err) = result {
+                json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                )
+            } else {
+                json_response(
+                   StatusCode::OK,
+                   String::from("Device labels updated successfully"),
+                   serde_json::Value::Null,
+                )
+            }
+        },
+        ("/environment/selector", Method::POST) => {
+            let json: EnvironmentSelectorDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            if let Some(Err(err)) = json.label_selector.as_ref().map(|selector| selector.parse::<LabelSelector>()) {
+                return json_response(
+                   StatusCode::BAD_REQUEST,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                )
+            }
+
+            let result = environment_set_label_selector(
+                json.project_name,
+                json.environment_name,
+                json.label_selector,
+                database_pool
+            ).await;
+
+            if let Err(err) = result {
+                json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                )
+            } else {
+                json_response(
+                   StatusCode::OK,
+                   String::from("Environment selector updated successfully"),
+                   serde_json::Value::Null,
+                )
+            }
+        },
+        ("/join_token", Method::POST) => {
+            let json: JoinTokenCreateDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            if json.expires_in_hours <= 0 || json.max_uses <= 0 {
+                return json_response(
+                   StatusCode::BAD_REQUEST,
+                   String::from("Expiry and usage count must be positive"),
+                   serde_json::Value::Null,
+                )
+            }
+
+            let Some(expires_at) = TimeDelta::try_hours(json.expires_in_hours)
+                .and_then(|expires_in| Utc::now().naive_utc().checked_add_signed(expires_in)) else {
+                return json_response(
+                   StatusCode::BAD_REQUEST,
+                   String::from("Expiry is too far in the future"),
+                   serde_json::Value::Null,
+                )
+            };
+
+            let result = join_token_create(
+                json.project_name,
+                json.environment_name,
+                expires_at,
+                json.max_uses,
+                database_pool
+            ).await;
+
+            match result {
+                Ok(token) => json_response(
+                   StatusCode::OK,
+                   String::from("Join token created successfully"),
+                   serde_json::json!({ "token": token, "expires_at": expires_at.to_string() }),
+                ),
+                Err(err) => json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                ),
+            }
+        },
+        ("/secret", Method::POST) => {
+            let json: SecretSetDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            let Some(secrets_cipher) = secrets_cipher else {
+                return json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   SecretError::KeyNotConfigured.to_string(),
+                   serde_json::Value::Null,
+                )
+            };
+
+            let result = secret_set(
+                json.project_name,
+                json.environment_name,
+                json.name,
+                json.value,
+                secrets_cipher,
+                database_pool
+            ).await;
+
+            if let Err(err) = result {
+                json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                )
+            } else {
+                json_response(
+                   StatusCode::OK,
+                   String::from("Secret stored successfully"),
                    serde_json::Value::Null,
                 )
             }
         },
+        ("/secrets", Method::GET) => {
+            let json: SecretListDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            let result = secret_list(json.project_name, json.environment_name, database_pool).await;
+
+            match result {
+                Ok(secrets) => {
+                    let secrets: Vec<serde_json::Value> = secrets
+                        .iter()
+                        .map(|secret| serde_json::json!({
+                            "name": secret.name,
+                            "revision": secret.revision,
+                            "created_at": secret.created_at.to_string(),
+                            "updated_at": secret.updated_at.map(|updated_at| updated_at.to_string()),
+                        }))
+                        .collect();
+
+                    json_response(
+                       StatusCode::OK,
+                       String::from("Secrets listed successfully"),
+                       secrets.into(),
+                    )
+                },
+                Err(err) => json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                ),
+            }
+        },
+        ("/secret", Method::DELETE) => {
+            let json: SecretDeleteDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            let result = secret_delete(json.project_name, json.environment_name, json.name, database_pool).await;
+
+            if let Err(err) = result {
+                json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                )
+            } else {
+                json_response(
+                   StatusCode::OK,
+                   String::from("Secret deleted successfully"),
+                   serde_json::Value::Null,
+                )
+            }
+        },
+        ("/webhook", Method::POST) => {
+            let json: WebhookCreateDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            if !json.url.starts_with("http://") && !json.url.starts_with("https://") {
+                return json_response(
+                   StatusCode::BAD_REQUEST,
+                   String::from("Webhook url must start with http:// or https://"),
+                   serde_json::Value::Null,
+                )
+            }
+
+            if json.secret.is_empty() || json.events.is_empty() {
+                return json_response(
+                   StatusCode::BAD_REQUEST,
+                   String::from("Webhook needs a secret and at least one event"),
+                   serde_json::Value::Null,
+                )
+            }
+
+            let events: Result<Vec<WebhookEvent>, _> = json.events
+                .iter()
+                .map(|event| event.parse::<WebhookEvent>())
+                .collect();
+
+            let events = match events {
+                Ok(events) => events,
+                Err(err) => {
+                    return json_response(
+                       StatusCode::BAD_REQUEST,
+                       err.to_string(),
+                       serde_json::Value::Null,
+                    )
+                }
+            };
+
+            let result = webhook_create(json.project_name, json.url, events, json.secret, database_pool).await;
+
+            match result {
+                Ok(webhook_id) => json_response(
+                   StatusCode::OK,
+                   String::from("Webhook created successfully"),
+                   serde_json::json!({ "id": webhook_id }),
+                ),
+                Err(err) => json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                ),
+            }
+        },
+        ("/webhooks", Method::GET) => {
+            let json: WebhookListDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            let result = webhook_list(json.project_name, database_pool).await;
+
+            match result {
+                Ok(webhooks) => {
+                    let webhooks: Vec<serde_json::Value> = webhooks
+                        .iter()
+                        .map(|webhook| serde_json::json!({
+                            "id": webhook.id,
+                            "url": webhook.url,
+                            "events": webhook.events.split(',').collect::<Vec<&str>>(),
+                            "created_at": webhook.created_at.to_string(),
+                        }))
+                        .collect();
+
+                    json_response(
+                       StatusCode::OK,
+                       String::from("Webhooks listed successfully"),
+                       webhooks.into(),
+                    )
+                },
+                Err(err) => json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                ),
+            }
+        },
+        ("/webhook", Method::DELETE) => {
+            let json: WebhookDeleteDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            let result = webhook_delete(json.project_name, json.id, database_pool).await;
+
+            if let Err(err) = result {
+                json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                )
+            } else {
+                json_response(
+                   StatusCode::OK,
+                   String::from("Webhook deleted successfully"),
+                   serde_json::Value::Null,
+                )
+            }
+        },
+        ("/webhook/deliveries", Method::GET) => {
+            let json: WebhookDeliveryListDTO = serde_json::from_slice(body.as_slice()).unwrap();
+
+            let result = webhook_delivery_list(json.project_name, json.id, WEBHOOK_DELIVERIES_LIMIT, database_pool).await;
+
+            match result {
+                Ok(deliveries) => {
+                    let deliveries: Vec<serde_json::Value> = deliveries
+                        .iter()
+                        .map(|delivery| serde_json::json!({
+                            "id": delivery.id,
+                            "event": delivery.event,
+                            "attempts": delivery.attempts,
+                            "delivered": delivery.delivered,
+                            "response_status": delivery.response_status,
+                            "error": delivery.error,
+                            "created_at": delivery.created_at.to_string(),
+                            "updated_at": delivery.updated_at.map(|updated_at| updated_at.to_string()),
+                        }))
+                        .collect();
+
+                    json_response(
+                       StatusCode::OK,
+                       String::from("Webhook deliveries listed successfully"),
+                       deliveries.into(),
+                    )
+                },
+                Err(err) => json_response(
+                   StatusCode::INTERNAL_SERVER_ERROR,
+                   err.to_string(),
+                   serde_json::Value::Null,
+                ),
+            }
+        },
         _ => {
             return json_response(
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -102,5 +694,38 @@ pub async fn handle_http_connection(req: &mut Request<Incoming>, database_pool:
                 serde_json::Value::Null,
             )
         }
-    };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{HeaderMap, StatusCode};
+
+    use super::authorize_user;
+    use crate::repository::{tests::test_pool, user_create};
+    use crate::tokens::hash_token;
+
+    #[tokio::test]
+    async fn unauthenticated_requests_are_refused() {
+        let pool = test_pool().await;
+        user_create(String::from("admin"), hash_token("secret"), pool.clone()).await.unwrap();
+
+        let headers = |access_token: Option<&str>| {
+            let mut headers = HeaderMap::new();
+
+            if let Some(access_token) = access_token {
+                headers.insert("authorization", access_token.parse().unwrap());
+            }
+
+            headers
+        };
+
+        let status = |result: Result<(), _>| result.err().map(|response: hyper::Response<_>| response.status());
+
+        assert_eq!(status(authorize_user(&headers(None), pool.clone()).await), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(authorize_user(&headers(Some("guess")), pool.clone()).await), Some(StatusCode::UNAUTHORIZED));
+        // The stored hash is not a credential itself
+        assert_eq!(status(authorize_user(&headers(Some(hash_token("secret").as_str())), pool.clone()).await), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(authorize_user(&headers(Some("secret")), pool.clone()).await), None);
+    }
 }
@@ -0,0 +1,35 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DriftMode {
+    Report,
+    Remediate,
+}
+
+impl DriftMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DriftMode::Report => "report",
+            DriftMode::Remediate => "remediate",
+        }
+    }
+}
+
+impl fmt::Display for DriftMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for DriftMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "report" => Ok(DriftMode::Report),
+            "remediate" => Ok(DriftMode::Remediate),
+            _ => Err(format!("Invalid drift mode '{mode}', expected 'report' or 'remediate'")),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::models::Devices;
+
+#[derive(Debug, Clone)]
+pub struct DeviceVariables {
+    pub name: String,
+    pub machine_id: String,
+    pub labels: HashMap<String, String>,
+}
+
+impl DeviceVariables {
+    pub fn from_device(device: &Devices, labels: HashMap<String, String>) -> Self {
+        DeviceVariables {
+            name: device.name.clone(),
+            machine_id: device.machine_id.clone().unwrap_or_default(),
+            labels,
+        }
+    }
+
+    fn get(&self, variable: &str) -> Option<&str> {
+        match variable {
+            "device.name" => Some(self.name.as_str()),
+            "device.machine_id" => Some(self.machine_id.as_str()),
+            _ => variable
+                .strip_prefix("device.labels.")
+                .and_then(|label| self.labels.get(label))
+                .map(String::as_str),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InterpolationError {
+    UnknownVariable(String),
+    UnterminatedPlaceholder(String),
+    InvalidState(String),
+}
+
+impl fmt::Display for InterpolationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpolationError::UnknownVariable(variable) => write!(f, "Unknown variable '{variable}'"),
+            InterpolationError::UnterminatedPlaceholder(value) => write!(f, "Unterminated placeholder in '{value}'"),
+            InterpolationError::InvalidState(err) => write!(f, "Invalid state: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for InterpolationError {}
+
+// `${...}` is replaced by the value of the variable, `$${` is kept as a
+// literal `${`.
+fn interpolate_string(value: &str, variables: &DeviceVariables) -> Result<Option<String>, InterpolationError> {
+    if !value.contains("${") {
+        return Ok(None);
+    }
+
+    let mut interpolated = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            interpolated.push_str(&rest[..start - 1]);
+            interpolated.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        interpolated.push_str(&rest[..start]);
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or(InterpolationError::UnterminatedPlaceholder(value.to_string()))?;
+
+        let variable = rest[start + 2..start + end].trim();
+
+        let variable_value = variables
+            .get(variable)
+            .ok_or(InterpolationError::UnknownVariable(variable.to_string()))?;
+
+        interpolated.push_str(variable_value);
+        rest = &rest[start + end + 1..];
+    }
+
+    interpolated.push_str(rest);
+
+    Ok(Some(interpolated))
+}
+
+fn interpolate_value(value: &mut Value, variables: &DeviceVariables) -> Result<bool, InterpolationError> {
+    match value {
+        Value::String(string) => {
+            match interpolate_string(string, variables)? {
+                Some(interpolated) => {
+                    *string = interpolated;
+                    Ok(true)
+                },
+                None => Ok(false),
+            }
+        },
+        Value::Array(values) => {
+            let mut changed = false;
+
+            for value in values {
+                changed |= interpolate_value(value, variables)?;
+            }
+
+            Ok(changed)
+        },
+        Value::Object(values) => {
+            let mut changed = false;
+
+            for value in values.values_mut() {
+                changed |= interpolate_value(value, variables)?;
+            }
+
+            Ok(changed)
+        },
+        _ => Ok(false),
+    }
+}
+
+// The state is returned untouched when it has no placeholders, so its hash
+// stays the same as the one of the stored document.
+pub fn interpolate_state(state_json: &str, variables: &DeviceVariables) -> Result<String, InterpolationError> {
+    if !state_json.contains("${") {
+        return Ok(state_json.to_string());
+    }
+
+    let mut state: Value = serde_json::from_str(state_json)
+        .map_err(|err| InterpolationError::InvalidState(err.to_string()))?;
+
+    if !interpolate_value(&mut state, variables)? {
+        return Ok(state_json.to_string());
+    }
+
+    Ok(state.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{interpolate_state, DeviceVariables, InterpolationError};
+
+    #[test]
+    fn interpolate_device_variables() {
+        let variables = DeviceVariables {
+            name: String::from("edge-01"),
+            machine_id: String::from("4c4c4544"),
+            labels: HashMap::from([(String::from("role"), String::from("edge"))]),
+        };
+
+        let state = r#"{"resources":[{"urn":"ovejas.system::User::admin","parameters":{"name":"admin-${device.name}","home":"/srv/${ device.labels.role }/$${HOME}","uid":1000}}]}"#;
+        let interpolated = interpolate_state(state, &variables).unwrap();
+
+        assert!(interpolated.contains(r#""name":"admin-edge-01""#));
+        assert!(interpolated.contains(r#""home":"/srv/edge/${HOME}""#));
+
+        let unknown = r#"{"resources":[{"parameters":{"name":"${device.labels.site}"}}]}"#;
+
+        assert_eq!(
+            interpolate_state(unknown, &variables),
+            Err(InterpolationError::UnknownVariable(String::from("device.labels.site"))),
+        );
+    }
+}
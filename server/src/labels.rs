@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LabelRequirement {
+    Equals(String, String),
+    NotEquals(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelSelector {
+    requirements: Vec<LabelRequirement>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LabelSelectorError {
+    Empty,
+    InvalidRequirement(String),
+}
+
+impl fmt::Display for LabelSelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LabelSelectorError::Empty => write!(f, "Label selector is empty"),
+            LabelSelectorError::InvalidRequirement(requirement) => {
+                write!(f, "Invalid label requirement '{requirement}', expected 'key=value' or 'key!=value'")
+            },
+        }
+    }
+}
+
+impl std::error::Error for LabelSelectorError {}
+
+fn is_valid_label_part(part: &str) -> bool {
+    !part.is_empty()
+        && part.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+}
+
+impl FromStr for LabelSelector {
+    type Err = LabelSelectorError;
+
+    // Requirements are separated by commas and all of them must match,
+    // e.g. `role=edge,site=scl,tier!=canary`.
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        let mut requirements = Vec::new();
+
+        for requirement in selector.split(',').map(str::trim).filter(|requirement| !requirement.is_empty()) {
+            let invalid_requirement = || LabelSelectorError::InvalidRequirement(requirement.to_string());
+
+            let parsed_requirement = if let Some((key, value)) = requirement.split_once("!=") {
+                LabelRequirement::NotEquals(key.trim().to_string(), value.trim().to_string())
+            } else if let Some((key, value)) = requirement.split_once('=') {
+                LabelRequirement::Equals(key.trim().to_string(), value.trim().to_string())
+            } else {
+                return Err(invalid_requirement());
+            };
+
+            let (LabelRequirement::Equals(key, value) | LabelRequirement::NotEquals(key, value)) = &parsed_requirement;
+
+            if !is_valid_label_part(key) || !is_valid_label_part(value) {
+                return Err(invalid_requirement());
+            }
+
+            requirements.push(parsed_requirement);
+        }
+
+        if requirements.is_empty() {
+            return Err(LabelSelectorError::Empty);
+        }
+
+        Ok(LabelSelector { requirements })
+    }
+}
+
+impl LabelSelector {
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.requirements.iter().all(|requirement| match requirement {
+            LabelRequirement::Equals(key, value) => labels.get(key) == Some(value),
+            LabelRequirement::NotEquals(key, value) => labels.get(key) != Some(value),
+        })
+    }
+}
+
+pub fn is_valid_label(key: &str, value: &str) -> bool {
+    is_valid_label_part(key) && is_valid_label_part(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{LabelSelector, LabelSelectorError};
+
+    #[test]
+    fn selector_matches_labels() {
+        let selector: LabelSelector = "role=edge, site=scl,tier!=canary".parse().unwrap();
+
+        let mut labels = HashMap::from([
+            (String::from("role"), String::from("edge")),
+            (String::from("site"), String::from("scl")),
+        ]);
+
+        assert!(selector.matches(&labels));
+
+        labels.insert(String::from("tier"), String::from("canary"));
+        assert!(!selector.matches(&labels));
+
+        labels.remove("site");
+        labels.remove("tier");
+        assert!(!selector.matches(&labels));
+
+        assert_eq!("".parse::<LabelSelector>(), Err(LabelSelectorError::Empty));
+        assert_eq!(
+            "role".parse::<LabelSelector>(),
+            Err(LabelSelectorError::InvalidRequirement(String::from("role"))),
+        );
+    }
+}
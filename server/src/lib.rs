@@ -3,3 +3,15 @@ pub mod schema;
 pub mod models;
 pub mod repository;
 pub mod controller;
+pub mod retention;
+pub mod secrets;
+pub mod interpolation;
+pub mod labels;
+pub mod tokens;
+pub mod webhooks;
+pub mod sessions;
+pub mod conflicts;
+pub mod promotion;
+pub mod maintenance;
+pub mod drift;
+pub mod admin;
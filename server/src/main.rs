@@ -1,24 +1,45 @@
 use futures::{SinkExt, StreamExt};
-use md5::{Md5, Digest};
 use tokio::{
-    time::{sleep, Duration},
+    time::{interval, sleep_until, timeout, timeout_at, Duration, Instant},
     net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch, Notify},
 };
 
 use tokio_tungstenite::WebSocketStream;
 
-use std::{collections::HashMap, convert::Infallible, net::SocketAddr, str::FromStr};
+use std::{collections::{HashMap, HashSet}, convert::Infallible, net::SocketAddr, str::FromStr, sync::Arc};
 
 use figment::{Figment, providers::{Format, Yaml, Env}};
 
+use chrono::Utc;
+
+use clap::{Arg, ArgAction};
+
 use serde::Deserialize;
 
+use server::admin::{run_device_action, run_user_action};
 use server::{controller::handle_http_connection, schema::{devices, environments, projects}};
-use shared::request_operations::{CurrentStatusResponse, EnvironmentUpdate, EnvironmentUpdateOperation, RequestOperations};
+use server::interpolation::{interpolate_state, DeviceVariables};
+use server::maintenance::environment_schedule;
+use server::repository::{device_project_ids, environment_latest_states, device_set_last_seen, device_set_online, devices_mark_all_offline, environment_conflicts, environment_next_sequence, join_token_redeem, load_device_labels, JoinTokenError, record_device_status, record_drift, record_sent_state, update_reported_state_hashes, user_find_by_token_hash};
+use server::retention::{prune_states, run_retention_task};
+use server::secrets::{resolve_secret_references, SecretsCipher};
+use server::sessions::{DuplicateSessionPolicy, HeartbeatSettings, SessionHandle, SessionRegistry};
+use server::state::{hash_state, unsupported_resource_kinds};
+use server::tokens::hash_token;
+use server::webhooks::{run_webhook_dispatcher, WebhookEvent, WebhookNotifier};
+use shared::compression::{decompress, Compression, COMPRESSION_HEADER, DEFAULT_MAX_MESSAGE_SIZE};
+use shared::desired_state::{DesiredState, STATE_VERSION};
+use shared::handshake::{is_supported_protocol, Hello, HelloResponse, MIN_PROTOCOL_VERSION, PATCH_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use shared::request_operations::{Envelope, EnvironmentUpdate, EnvironmentUpdateOperation, FrameCodec, MessageDecodeError, MessageId, MessageIds, RequestOperations, ResponseOperations};
+use shared::admin_operations::{AdminDeviceAction, AdminDeviceOperationMessage, AdminUserAction, AdminUserOperationMessage};
 use shared::state_operations::{StateOperationMessage, StateAction};
+use shared::signing::{public_key_base64, sign_state, signing_key_from_base64, verify_signature, SignatureError, SigningKey, StateSignature, DESTROYED_STATE};
+use shared::state_patch;
 use serde_json::json;
 
-use tracing::{info, debug, error, instrument};
+use tracing::{info, debug, error, warn, instrument};
 use tracing_subscriber;
 
 #[derive(Deserialize)]
@@ -26,34 +47,298 @@ struct Config {
     port: Option<u64>,
     address: Option<String>,
     database_url: Option<String>,
+    prune_interval_seconds: Option<u64>,
+    secrets_key: Option<String>,
+    state_signing_key: Option<String>,
+    heartbeat_interval_seconds: Option<u64>,
+    idle_timeout_seconds: Option<u64>,
+    shutdown_timeout_seconds: Option<u64>,
+    duplicate_session_policy: Option<String>,
+    max_message_size_bytes: Option<usize>,
+}
+
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(5000);
+
+// Previous states of an environment the server looks for the device's
+// current one in, to send a patch instead of the full state
+const PATCH_BASE_STATES: i64 = 10;
+
+// Every session and request holds a clone of the drain guard, so the server
+// knows all of them finished once the receiving end reports it closed.
+#[derive(Clone)]
+struct Shutdown {
+    signal: watch::Receiver<bool>,
+    _drain_guard: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    async fn requested(&mut self) {
+        let _ = self.signal.wait_for(|shutting_down| *shutting_down).await;
+    }
+}
+
+#[derive(Clone)]
+struct SessionSettings {
+    secrets_cipher: Option<SecretsCipher>,
+    state_signing_key: Option<SigningKey>,
+    heartbeat: HeartbeatSettings,
+    webhooks: WebhookNotifier,
+    shutdown: Shutdown,
+    sessions: SessionRegistry,
+    duplicate_session_policy: DuplicateSessionPolicy,
+    max_message_size: usize,
+}
+
+#[derive(Debug)]
+enum SessionError {
+    IdleTimeout,
+    Closed,
+    ShuttingDown,
+    Superseded,
+    Incompatible(String),
+    InvalidFrame(MessageDecodeError),
+    Transport(tokio_tungstenite::tungstenite::Error),
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::IdleTimeout => write!(f, "No frames received before the idle timeout"),
+            SessionError::Closed => write!(f, "Connection closed by peer"),
+            SessionError::ShuttingDown => write!(f, "Server is shutting down"),
+            SessionError::Superseded => write!(f, "Superseded by a newer session for the same machine-id"),
+            SessionError::Incompatible(reason) => write!(f, "{reason}"),
+            SessionError::InvalidFrame(err) => write!(f, "{err}"),
+            SessionError::Transport(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+// Control frames only refresh `last_seen`, the caller gets the next data frame.
+async fn receive_message(session: &mut ListenerSession, heartbeat: HeartbeatSettings) -> Result<Message, SessionError> {
+    loop {
+        let message = timeout_at(heartbeat.idle_deadline(session.last_seen), session.ws_stream.next())
+            .await
+            .map_err(|_| SessionError::IdleTimeout)?
+            .ok_or(SessionError::Closed)?
+            .map_err(SessionError::Transport)?;
+
+        session.last_seen = Instant::now();
+
+        match message {
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            Message::Close(_) => return Err(SessionError::Closed),
+            message => return Ok(message),
+        }
+    }
+}
+
+// Replies are matched to pending requests by id, so the agent can answer
+// them in any order. The status agents send on their own while applying an
+// update is stored right away, anything else is skipped.
+async fn dispatch_message(session: &mut ListenerSession, message: Message, database_pool: Pool) -> Result<(), MessageDecodeError> {
+    match session.codec.decode::<ResponseOperations>(message)? {
+        Envelope { in_reply_to: Some(request_id), body, .. } if session.pending_replies.contains_key(&request_id) => {
+            session.pending_replies.insert(request_id, Some(body));
+        },
+        Envelope { in_reply_to: None, body: ResponseOperations::CurrentStatus(current_status), .. } => {
+            if let Err(err) = record_device_status(session.machine_id.clone(), current_status.statuses, database_pool).await {
+                error!("Could not store reported status: {err}");
+            }
+        },
+        envelope => {
+            debug!(message_id = envelope.id, in_reply_to = envelope.in_reply_to, "Skipping message that answers no pending request");
+        },
+    }
+
+    Ok(())
+}
+
+// The request has to be in `pending_replies` before it is sent
+async fn receive_reply(
+    session: &mut ListenerSession,
+    request_id: MessageId,
+    heartbeat: HeartbeatSettings,
+    database_pool: Pool,
+) -> Result<ResponseOperations, SessionError> {
+    loop {
+        if let Some(Some(reply)) = session.pending_replies.get_mut(&request_id).map(Option::take) {
+            session.pending_replies.remove(&request_id);
+
+            return Ok(reply);
+        }
+
+        let message = receive_message(session, heartbeat).await?;
+
+        dispatch_message(session, message, database_pool.clone())
+            .await
+            .map_err(SessionError::InvalidFrame)?;
+    }
+}
+
+// Agents from before the handshake wait for a status request without saying
+// anything, so the hello has to arrive within the idle timeout even if the
+// device keeps pinging.
+async fn receive_hello(session: &mut ListenerSession, heartbeat: HeartbeatSettings) -> Result<Hello, SessionError> {
+    let message = timeout(heartbeat.idle_timeout, receive_message(session, heartbeat))
+        .await
+        .map_err(|_| SessionError::Incompatible(String::from("Expected a hello, the agent may be too old")))??;
+
+    let hello = Hello::try_from(message)
+        .map_err(|err| SessionError::Incompatible(format!("Invalid hello: {err}")))?;
+
+    if !is_supported_protocol(hello.protocol_version) {
+        return Err(SessionError::Incompatible(format!(
+            "Unsupported protocol version {}, expected {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}",
+            hello.protocol_version,
+        )));
+    }
+
+    let hello_response = HelloResponse {
+        protocol_version: PROTOCOL_VERSION,
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        compression: Compression::negotiate(&hello.compression),
+    };
+
+    session.codec.compression = hello_response.compression;
+
+    session.ws_stream.send(hello_response.into())
+        .await
+        .map_err(SessionError::Transport)?;
+
+    Ok(hello)
+}
+
+// Pings the device until the next status poll is due, failing if it stops
+// answering for longer than the idle timeout. Sessions only stop for a
+// shutdown here, so an update in flight is always completed first.
+async fn wait_for_next_poll(
+    session: &mut ListenerSession,
+    heartbeat: HeartbeatSettings,
+    shutdown: &mut Shutdown,
+    database_pool: Pool,
+) -> Result<(), SessionError> {
+    let next_poll = Instant::now() + STATUS_POLL_INTERVAL;
+    let mut ping_interval = interval(heartbeat.interval);
+    let superseded = session.superseded.clone();
+
+    loop {
+        tokio::select! {
+            _ = sleep_until(next_poll) => return Ok(()),
+            _ = sleep_until(heartbeat.idle_deadline(session.last_seen)) => return Err(SessionError::IdleTimeout),
+            _ = shutdown.requested() => return Err(SessionError::ShuttingDown),
+            _ = superseded.notified() => return Err(SessionError::Superseded),
+            _ = ping_interval.tick() => {
+                session.ws_stream
+                    .send(Message::Ping(Bytes::new()))
+                    .await
+                    .map_err(SessionError::Transport)?;
+            },
+            message = session.ws_stream.next() => {
+                match message {
+                    None => return Err(SessionError::Closed),
+                    Some(Err(err)) => return Err(SessionError::Transport(err)),
+                    Some(Ok(Message::Close(_))) => return Err(SessionError::Closed),
+                    Some(Ok(message @ Message::Binary(_))) => {
+                        session.last_seen = Instant::now();
+
+                        if let Err(err) = dispatch_message(session, message, database_pool.clone()).await {
+                            warn!(machine_id = session.machine_id, "Skipping invalid message: {err}");
+                        }
+                    },
+                    Some(Ok(Message::Pong(_))) => {
+                        session.last_seen = Instant::now();
+
+                        if let Err(err) = device_set_last_seen(session.machine_id.clone(), database_pool.clone()).await {
+                            error!("Could not store last seen time: {err}");
+                        }
+                    },
+                    Some(Ok(_)) => session.last_seen = Instant::now(),
+                }
+            },
+        }
+    }
 }
 
 async fn listen_device(
     session: &mut ListenerSession,
     current_state: &mut RequestOperations,
     database_pool: Pool,
-) {
+    mut settings: SessionSettings,
+) -> Result<(), SessionError> {
+    let secrets_cipher = settings.secrets_cipher;
+    let state_signing_key = settings.state_signing_key.clone();
+
     match current_state {
         RequestOperations::StatusRequest => {
+            let status_request = session.message_ids.request(RequestOperations::StatusRequest);
+            let request_id = status_request.id;
+
+            session.pending_replies.insert(request_id, None);
+
             session.ws_stream
-                .send(RequestOperations::StatusRequest.into())
-                .await
-                .expect("Could not send message");
-            
-            let next_from_stream = session.ws_stream
-                .next()
+                .send(session.codec.encode(&status_request))
                 .await
-                .unwrap()
-                .expect("Could not receive message");
+                .map_err(SessionError::Transport)?;
 
-            let status_request_response: CurrentStatusResponse = next_from_stream.into();
+            let ResponseOperations::CurrentStatus(status_request_response) = receive_reply(session, request_id, settings.heartbeat, database_pool.clone()).await?;
             let state_hashes = status_request_response.state_hashes.clone();
 
+            if let Err(err) = device_set_last_seen(session.machine_id.clone(), database_pool.clone()).await {
+                error!("Could not store last seen time: {err}");
+            }
+
+            if let Err(err) = record_device_status(session.machine_id.clone(), status_request_response.statuses.clone(), database_pool.clone()).await {
+                error!("Could not store reported status: {err}");
+            }
+
+            match update_reported_state_hashes(session.machine_id.clone(), state_hashes.clone(), database_pool.clone()).await {
+                Ok(apply_results) => {
+                    for apply_result in apply_results {
+                        let event = if apply_result.succeeded {
+                            WebhookEvent::ApplySucceeded
+                        } else {
+                            WebhookEvent::ApplyFailed
+                        };
+
+                        settings.webhooks.notify(event, apply_result.project_id, serde_json::json!({
+                            "machine_id": session.machine_id,
+                            "environment": apply_result.environment,
+                            "state_id": apply_result.state_id,
+                        }));
+                    }
+                },
+                Err(err) => error!("Could not store reported state hashes: {err}"),
+            }
+
+            let mut remediate_environments = HashSet::new();
+
+            if let Some(state_drift) = status_request_response.drift.clone() {
+                match record_drift(session.machine_id.clone(), state_drift, database_pool.clone()).await {
+                    Ok(drift_reports) => {
+                        for drift_report in drift_reports {
+                            if drift_report.newly_drifted {
+                                settings.webhooks.notify(WebhookEvent::DriftDetected, drift_report.project_id, serde_json::json!({
+                                    "machine_id": session.machine_id,
+                                    "environment": drift_report.environment,
+                                    "drift": drift_report.drift,
+                                }));
+                            }
+
+                            if drift_report.remediate {
+                                remediate_environments.insert(drift_report.environment);
+                            }
+                        }
+                    },
+                    Err(err) => error!("Could not store reported drift: {err}"),
+                }
+            }
+
             let conn = database_pool.get().await.expect("Could not get database connection");
             
             let machine_id = session.machine_id.clone();
 
-            let environments = conn.interact(move |conn| {
+            let (device, device_labels, environments) = conn.interact(move |conn| {
                 let device = devices::table
                     .filter(devices::machine_id.eq(machine_id))
                     .select(Devices::as_select())
@@ -67,40 +352,74 @@ async fn listen_device(
                     panic!("Device not found in database, rejecting connection")
                 }
 
-                let environments: Vec<Environments> = DevicesEnvironments::belonging_to(&device.unwrap())
-                    .inner_join(environments::table)
-                    .select(Environments::as_select())
+                let device = device.unwrap();
+
+                let environments: Vec<(Environments, String)> = DevicesEnvironments::belonging_to(&device)
+                    .inner_join(environments::table.inner_join(projects::table))
+                    .select((Environments::as_select(), projects::name))
                     .load(conn)
                     .expect("Database error");
 
-                environments
-            }).await;
+                let device_labels = load_device_labels(conn, device.id).expect("Database error");
+
+                (device, device_labels, environments)
+            }).await.unwrap();
+
+            let device_variables = DeviceVariables::from_device(&device, device_labels);
 
             let mut environments_to_update = HashMap::new();
 
-            for environment in environments.unwrap() {
+            for (environment, project_name) in environments {
                 println!("{environment:?}");
 
                 let environment_name = environment.name.clone();
+                let environment_id = environment.id;
+                let maintenance_schedule = environment_schedule(&environment);
+
+                // Environments can be enrolled, e.g. by a selector or a join
+                // token, before anything is pushed to them
+                let (latest_state, recent_states) = match environment_latest_states(environment_id, PATCH_BASE_STATES, database_pool.clone()).await {
+                    Ok(Some(states)) => states,
+                    Ok(None) => {
+                        debug!(environment = environment_name, "No state pushed yet, nothing to send");
+                        continue;
+                    },
+                    Err(err) => {
+                        error!(environment = environment_name, "Could not load states: {err}");
+                        continue;
+                    },
+                };
+
+                let environment_secrets = conn.interact(move |conn| {
+                    Secrets::belonging_to(&environment)
+                        .select(Secrets::as_select())
+                        .load::<Secrets>(conn)
+                        .expect("Database error")
+                }).await.unwrap();
+
+                // Status is still recorded above, only the update waits for the window
+                let window_open = match maintenance_schedule {
+                    None => true,
+                    Some(Ok(schedule)) => schedule.is_open(Utc::now()),
+                    Some(Err(err)) => {
+                        error!(environment = environment_name, "Invalid maintenance windows, holding back updates: {err}");
+                        false
+                    },
+                };
 
-                let latest_state = conn.interact(move |conn| {
-                    let latest_state: Option<States> = States::belonging_to(&environment)
-                        .select(States::as_select())
-                        .order(states::id.desc())
-                        .limit(1)
-                        .get_result(conn)
-                        .optional()
-                        .expect("Database error");
-
-                    latest_state
-                }).await;
+                if !window_open && !latest_state.override_window {
+                    debug!(environment = environment_name, "Outside maintenance window, holding back update");
+                    continue;
+                }
 
-                let latest_state_json = latest_state.unwrap().unwrap().json;
+                let latest_state_json = latest_state.json.clone();
 
-                if latest_state_json == "{}" {
+                if latest_state_json == DESTROYED_STATE {
                     let environment_update = EnvironmentUpdate {
                         state: None,
                         operation: EnvironmentUpdateOperation::Destroy,
+                        secrets: HashMap::new(),
+                        signature: state_signature(&project_name, &environment_name, &latest_state, DESTROYED_STATE, state_signing_key.as_ref()),
                     };
 
                     environments_to_update.insert(
@@ -111,21 +430,103 @@ async fn listen_device(
                     continue;
                 }
 
+                let unsupported_kinds = unsupported_resource_kinds(latest_state_json.as_str(), &session.provider_kinds);
+
+                if !unsupported_kinds.is_empty() {
+                    error!(environment = environment_name, "Device has no provider for resource kinds {unsupported_kinds:?}, holding back update");
+                    continue;
+                }
+
+                match DesiredState::declared_version(latest_state_json.as_str()) {
+                    Ok(version) if version > session.max_state_version => {
+                        error!(
+                            environment = environment_name,
+                            "State format version {version} is newer than the {} the agent supports, holding back update",
+                            session.max_state_version,
+                        );
+                        continue;
+                    },
+                    Ok(_) => {},
+                    Err(err) => {
+                        error!(environment = environment_name, "Invalid state, holding back update: {err}");
+                        continue;
+                    },
+                }
+
+                let render = |state_json: &str| render_state(state_json, &device_variables, &environment_secrets, secrets_cipher.as_ref());
+
+                let (latest_state_json, secrets) = match render(latest_state_json.as_str()) {
+                    Ok(rendered_state) => rendered_state,
+                    Err(err) => {
+                        error!(environment = environment_name, "{err}, skipping update");
+                        continue;
+                    }
+                };
+
+                let signature = state_signature(&project_name, &environment_name, &latest_state, latest_state_json.as_str(), state_signing_key.as_ref());
+
+                if signature.is_none() {
+                    debug!(environment = environment_name, "State is not signed, agents with trusted keys will refuse it");
+                }
+
                 let device_environment_hash = state_hashes
                     .get(&environment_name);
 
                 match device_environment_hash {
                     Some(hash) => {
-                        let mut hasher = Md5::new();
-                        hasher.update(latest_state_json.clone());
-                        let latest_state_hash: [u8; 16] = hasher.finalize().into();
+                        let latest_state_hash = hash_state(&latest_state_json);
 
                         println!("state_delta {:?}", &latest_state_hash == device_environment_hash.unwrap());
 
+                        // Patching again from the hash of a patch it could not apply
+                        // would fail the same way
+                        let failed_patch = session.patched_from.remove(&environment_name).as_ref() == Some(hash);
+
                         if &latest_state_hash != hash {
+                            if let Err(err) = record_sent_state(device.id, environment_id, latest_state.id, latest_state_hash, database_pool.clone()).await {
+                                error!("Could not record sent state: {err}");
+                            }
+
+                            let state_patch = if session.protocol_version >= PATCH_PROTOCOL_VERSION && !failed_patch {
+                                state_patch_from(hash, &recent_states, latest_state_json.as_str(), render)
+                            } else {
+                                None
+                            };
+
+                            let environment_update = match state_patch {
+                                Some(state_patch) => {
+                                    debug!(environment = environment_name, "Sending a patch of {} bytes instead of the full state", state_patch.len());
+
+                                    session.patched_from.insert(environment_name.clone(), *hash);
+
+                                    EnvironmentUpdate {
+                                        state: Some(state_patch),
+                                        operation: EnvironmentUpdateOperation::Patch {
+                                            base_hash: *hash,
+                                            target_hash: latest_state_hash,
+                                        },
+                                        secrets,
+                                        signature,
+                                    }
+                                },
+                                None => EnvironmentUpdate {
+                                    state: Some(latest_state_json.clone()),
+                                    operation: EnvironmentUpdateOperation::Update,
+                                    secrets,
+                                    signature,
+                                },
+                            };
+
+                            environments_to_update.insert(
+                                environment_name,
+                                environment_update,
+                            );
+                        } else if remediate_environments.contains(&environment_name) {
                             let environment_update = EnvironmentUpdate {
                                 state: Some(latest_state_json.clone()),
-                                operation: EnvironmentUpdateOperation::Update,
+                                operation: EnvironmentUpdateOperation::Remediate,
+                                secrets,
+                                signature,
                             };
 
                             environments_to_update.insert(
@@ -137,9 +538,17 @@ async fn listen_device(
                     None => {
                         println!("Environment '{}' not found, sending state as is...", environment_name);
 
+                        let latest_state_hash = hash_state(&latest_state_json);
+
+                        if let Err(err) = record_sent_state(device.id, environment_id, latest_state.id, latest_state_hash, database_pool.clone()).await {
+                            error!("Could not record sent state: {err}");
+                        }
+
                         let environment_update = EnvironmentUpdate {
                             state: Some(latest_state_json.clone()),
                             operation: EnvironmentUpdateOperation::Create,
+                            secrets,
+                            signature,
                         };
 
                         environments_to_update.insert(
@@ -150,18 +559,129 @@ async fn listen_device(
                 }
             }
 
-            session.ws_stream.send(RequestOperations::UpdateEnvironmentsRequest(environments_to_update).into())
+            let update_request = session.message_ids.request(RequestOperations::UpdateEnvironmentsRequest(environments_to_update));
+
+            // The agent would refuse it and reconnect, so the update waits
+            // until the limit is raised on both ends
+            let update_message = match session.codec.try_encode(&update_request) {
+                Ok(update_message) => update_message,
+                Err(err) => {
+                    error!(
+                        machine_id = session.machine_id,
+                        "Update of {} bytes exceeds the maximum message size of {}, holding it back",
+                        err.size,
+                        err.max_size,
+                    );
+
+                    return wait_for_next_poll(session, settings.heartbeat, &mut settings.shutdown, database_pool).await;
+                },
+            };
+
+            session.ws_stream.send(update_message)
                 .await
-                .expect("Failed to send update request");
+                .map_err(SessionError::Transport)?;
 
             println!("{status_request_response:?}");
 
-            sleep(Duration::from_millis(5000)).await;
+            wait_for_next_poll(session, settings.heartbeat, &mut settings.shutdown, database_pool).await
         },
         _ => panic!("Invalid request operation")
     }
 }
 
+// The document a device gets for a stored state, with its variables filled
+// in and secret references annotated.
+fn render_state(
+    state_json: &str,
+    device_variables: &DeviceVariables,
+    environment_secrets: &[Secrets],
+    secrets_cipher: Option<&SecretsCipher>,
+) -> Result<(String, HashMap<String, String>), String> {
+    let state_json = interpolate_state(state_json, device_variables)
+        .map_err(|err| format!("Could not interpolate device variables: {err}"))?;
+
+    resolve_secret_references(state_json.as_str(), environment_secrets, secrets_cipher)
+        .map_err(|err| format!("Could not resolve secrets: {err}"))
+}
+
+// Patch to the latest state from the previous one the device reports having,
+// if it is among the recent ones and the patch is worth it.
+fn state_patch_from<F>(device_hash: &[u8; 16], previous_states: &[States], latest_state_json: &str, render: F) -> Option<String>
+where
+    F: Fn(&str) -> Result<(String, HashMap<String, String>), String>,
+{
+    // The agent writes the patched document in canonical form, anything
+    // else would hash differently
+    if !state_patch::is_canonical(latest_state_json) {
+        return None;
+    }
+
+    let (base_state_json, _) = previous_states
+        .iter()
+        .filter_map(|state| render(state.json.as_str()).ok())
+        .find(|(state_json, _)| &hash_state(state_json) == device_hash)?;
+
+    let patch = state_patch::diff(base_state_json.as_str(), latest_state_json).ok()?;
+
+    (patch.len() < latest_state_json.len()).then_some(patch)
+}
+
+// A user signature travels as is when the device gets the stored document
+// unchanged, anything rendered for the device is signed with the server key
+// for the sequence the state was stored with, so agents see states in push
+// order.
+fn state_signature(
+    project: &str,
+    environment: &str,
+    stored_state: &States,
+    sent_state_json: &str,
+    state_signing_key: Option<&SigningKey>,
+) -> Option<StateSignature> {
+    let user_signature = match (&stored_state.signature, &stored_state.signed_by) {
+        (Some(signature), Some(signed_by)) if stored_state.json == sent_state_json => Some(StateSignature {
+            signed_by: signed_by.clone(),
+            signature: signature.clone(),
+            project: project.to_string(),
+            sequence: stored_state.sequence,
+        }),
+        _ => None,
+    };
+
+    user_signature.or_else(|| state_signing_key.map(|key| {
+        sign_state(key, project, environment, stored_state.sequence, sent_state_json)
+    }))
+}
+
+// Pushes may come unsigned, but a signature that does not match the stored
+// document would only be refused later by the agents
+fn verify_push_signature(signature: Option<&StateSignature>, project: &str, environment: &str, state_json: &str) -> Result<(), SignatureError> {
+    match signature {
+        Some(signature) if signature.project != project => Err(SignatureError::OtherProject {
+            expected: project.to_string(),
+            received: signature.project.clone(),
+        }),
+        Some(signature) => verify_signature(signature, environment, state_json).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+// A signature is only good for the next state of the environment, otherwise
+// it could not be told apart from a replay once stored
+fn verify_push_sequence(signature: Option<&StateSignature>, next_sequence: i64) -> Result<(), String> {
+    match signature {
+        Some(signature) if signature.sequence != next_sequence => Err(format!(
+            "State was signed for sequence {}, the environment is at {next_sequence}, push it again",
+            signature.sequence,
+        )),
+        _ => Ok(()),
+    }
+}
+
+enum PushRefusal {
+    Conflicts(Vec<String>),
+    Sequence(String),
+}
+
 #[derive(Debug)]
 enum ListenerType {
     Device,
@@ -176,25 +696,37 @@ struct ListenerSession {
     listener_type: ListenerType,
     bearer_token: String,
     ws_stream: WebSocketStream<TokioIo<Upgraded>>,
+    last_seen: Instant,
+    superseded: Arc<Notify>,
+    // Resource kinds the device announced in its hello
+    provider_kinds: Vec<String>,
+    max_state_version: u32,
+    protocol_version: u32,
+    // Hash a patch was last built from, per environment. A device still
+    // reporting it could not apply the patch and gets the full state.
+    patched_from: HashMap<String, [u8; 16]>,
+    codec: FrameCodec,
+    message_ids: MessageIds,
+    // Requests waiting for the agent to answer, with the reply once it arrives
+    pending_replies: HashMap<MessageId, Option<ResponseOperations>>,
+    _registration: Option<SessionHandle>,
 }
 
-async fn is_device_registered(machine_id: String, database_pool: Pool) -> bool { 
+async fn find_registered_device(machine_id: String, database_pool: Pool) -> Option<Devices> { 
     let conn = database_pool.get().await.expect("Could not get database connection");
 
-    let is_device_none = conn.interact(move |conn| {
-        let device = devices::table
+    let device = conn.interact(move |conn| {
+        devices::table
             .filter(devices::machine_id.eq(machine_id.clone()))
             .select(Devices::as_select())
             .get_result(conn)
             .optional()
-            .expect("Database error");
-
-        return device.is_none();
+            .expect("Database error")
     }).await.expect("Could not fetch device from database");
 
-    info!(device_registered = !is_device_none);
+    info!(device_registered = device.is_some());
 
-    return !is_device_none
+    device
 }
 
 fn is_http_connection(req: &mut Request<Incoming>) -> bool { 
@@ -231,18 +763,38 @@ fn is_http_connection(req: &mut Request<Incoming>) -> bool {
 pub enum ValidationError {
     NoMachineIdSet,
     DeviceNotRegistered,
+    InvalidDeviceToken,
+    InvalidAccessToken,
 }
 
-async fn validate_connection(listener_type: &ListenerType, machine_id: &Option<String>, database_pool: Pool) -> Result<(), ValidationError> {
+async fn validate_connection(listener_type: &ListenerType, machine_id: &Option<String>, bearer_token: &Option<String>, database_pool: Pool) -> Result<(), ValidationError> {
     match listener_type {
         ListenerType::Device => { 
             if machine_id.is_none() { return Err(ValidationError::NoMachineIdSet); };
 
             let machine_id = machine_id.clone().expect("Expected machine id");
 
-            if !is_device_registered(machine_id, database_pool).await { return Err(ValidationError::DeviceNotRegistered); }
+            let Some(device) = find_registered_device(machine_id, database_pool).await else {
+                return Err(ValidationError::DeviceNotRegistered);
+            };
+
+            // Devices without a credential, e.g. registered before tokens existed, can't connect
+            let bearer_token_hash = bearer_token.as_deref().map(hash_token);
+
+            if device.token_hash.is_none() || bearer_token_hash != device.token_hash { return Err(ValidationError::InvalidDeviceToken); }
         },
+        // Pushes need the access token of a user, like the REST routes
         ListenerType::CLI => {
+            let Some(bearer_token) = bearer_token else { return Err(ValidationError::InvalidAccessToken); };
+
+            match user_find_by_token_hash(hash_token(bearer_token), database_pool).await {
+                Ok(Some(_)) => {},
+                Ok(None) => return Err(ValidationError::InvalidAccessToken),
+                Err(err) => {
+                    error!("Could not check access token: {err}");
+                    return Err(ValidationError::InvalidAccessToken);
+                },
+            }
         },
     } 
 
@@ -260,7 +812,40 @@ fn error_response_json(message: &str, status_code: StatusCode) -> Response<http_
     error_response
 }
 
-async fn new_session(mut req: Request<Incoming>, addr: SocketAddr, database_pool: Pool) -> Result<Response<Body>, Infallible> {
+// Two agents with the same machine-id usually means a cloned image, which
+// needs someone to look at it.
+async fn report_duplicate_session(
+    machine_id: String,
+    existing_addr: SocketAddr,
+    new_addr: SocketAddr,
+    action: &str,
+    settings: &SessionSettings,
+    database_pool: Pool,
+) {
+    warn!(
+        machine_id = machine_id,
+        existing_address = existing_addr.to_string(),
+        new_address = new_addr.to_string(),
+        action = action,
+        "Duplicate session for the same machine-id",
+    );
+
+    match device_project_ids(machine_id.clone(), database_pool).await {
+        Ok(project_ids) => {
+            for project_id in project_ids {
+                settings.webhooks.notify(WebhookEvent::DuplicateSession, project_id, serde_json::json!({
+                    "machine_id": machine_id,
+                    "existing_address": existing_addr.to_string(),
+                    "new_address": new_addr.to_string(),
+                    "action": action,
+                }));
+            }
+        },
+        Err(err) => error!("Could not load device projects: {err}"),
+    }
+}
+
+async fn new_session(mut req: Request<Incoming>, addr: SocketAddr, database_pool: Pool, settings: SessionSettings) -> Result<Response<Body>, Infallible> {
     info!("New incoming request");
 
     info!(
@@ -283,11 +868,28 @@ async fn new_session(mut req: Request<Incoming>, addr: SocketAddr, database_pool
         .and_then(|header| header.to_str().ok())
         .and_then(|header| Some(header.to_string()));
 
-    let bearer_token = req.headers()
+    let mut bearer_token = req.headers()
         .get("authorization")
         .and_then(|header| header.to_str().ok())
         .and_then(|header| Some(header.to_string()));
 
+    let join_token = req.headers()
+        .get("join-token")
+        .and_then(|header| header.to_str().ok())
+        .map(|header| header.to_string());
+
+    let device_name = req.headers()
+        .get("device-name")
+        .and_then(|header| header.to_str().ok())
+        .map(|header| header.to_string());
+
+    // Only the CLI offers compression this way, agents do it in the hello
+    let offered_compression: Vec<String> = req.headers()
+        .get(COMPRESSION_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .map(|header| header.split(',').map(|compression| compression.trim().to_string()).collect())
+        .unwrap_or_default();
+
     let listener_type = req.headers()
         .get("machine-type")
         .and_then(|header| header.to_str().ok())
@@ -308,13 +910,49 @@ async fn new_session(mut req: Request<Incoming>, addr: SocketAddr, database_pool
     let validation_result  = validate_connection(
         &listener_type,
         &machine_id,
+        &bearer_token,
         database_pool.clone()
     ).await;
 
+    let mut issued_device_token = None;
+
     match validation_result {
+        Err(ValidationError::DeviceNotRegistered) if join_token.is_some() => {
+            let machine_id = machine_id.clone().expect("Expected machine id");
+
+            let result = join_token_redeem(
+                join_token.unwrap(),
+                machine_id.clone(),
+                device_name.unwrap_or(machine_id.clone()),
+                database_pool.clone(),
+            ).await;
+
+            match result {
+                Ok(device_token) => {
+                    info!(machine_id = machine_id, "Device registered with a join token");
+
+                    bearer_token = Some(device_token.clone());
+                    issued_device_token = Some(device_token);
+                },
+                Err(err) => {
+                    let status_code = match err.downcast_ref::<JoinTokenError>() {
+                        Some(JoinTokenError::Conflicts(_)) => StatusCode::CONFLICT,
+                        _ => StatusCode::FORBIDDEN,
+                    };
+
+                    return Ok(error_response_json(err.to_string().as_str(), status_code));
+                },
+            }
+        },
         Err(ValidationError::DeviceNotRegistered) => {
             return Ok(error_response_json("Device not registered", StatusCode::NOT_FOUND));
         },
+        Err(ValidationError::InvalidDeviceToken) => {
+            return Ok(error_response_json("Invalid device token", StatusCode::FORBIDDEN));
+        },
+        Err(ValidationError::InvalidAccessToken) => {
+            return Ok(error_response_json("Invalid access token", StatusCode::UNAUTHORIZED));
+        },
         Err(ValidationError::NoMachineIdSet) => {
             return Ok(error_response_json("No machine-id set in header", StatusCode::BAD_REQUEST));
         }
@@ -323,9 +961,50 @@ async fn new_session(mut req: Request<Incoming>, addr: SocketAddr, database_pool
 
     if is_http_connection(&mut req) {
         info!(protocol = "HTTP");
-        return Ok(handle_http_connection(&mut req, database_pool).await);
+        return Ok(handle_http_connection(&mut req, database_pool, settings.secrets_cipher, settings.webhooks).await);
     }
 
+    let registration = match listener_type {
+        ListenerType::Device => {
+            let machine_id = machine_id.clone().expect("Expected machine id");
+
+            match settings.sessions.register(&machine_id, addr, settings.duplicate_session_policy) {
+                Ok(registration) => {
+                    if let Some(superseded_addr) = registration.superseded_addr {
+                        report_duplicate_session(machine_id, superseded_addr, addr, "superseded", &settings, database_pool.clone()).await;
+                    }
+
+                    Some(registration)
+                },
+                Err(duplicate_session) => {
+                    report_duplicate_session(machine_id, duplicate_session.existing_addr, addr, "rejected", &settings, database_pool.clone()).await;
+
+                    return Ok(error_response_json(duplicate_session.to_string().as_str(), StatusCode::CONFLICT));
+                },
+            }
+        },
+        ListenerType::CLI => None,
+    };
+
+    let cli_compression = match listener_type {
+        ListenerType::CLI => Compression::negotiate(&offered_compression),
+        ListenerType::Device => None,
+    };
+
+    let codec = FrameCodec {
+        compression: cli_compression,
+        max_message_size: settings.max_message_size,
+    };
+
+    let websocket_config = WebSocketConfig::default()
+        .max_message_size(Some(settings.max_message_size))
+        .max_frame_size(Some(settings.max_message_size));
+
+    let superseded = registration
+        .as_ref()
+        .map(|registration| registration.superseded.clone())
+        .unwrap_or_default();
+
     info!(protocol = "WebSocket");
     debug!("Spawning a new thread...");
 
@@ -338,8 +1017,18 @@ async fn new_session(mut req: Request<Incoming>, addr: SocketAddr, database_pool
                         machine_id: machine_id.expect("Error while retrieving header 'machine-id'"),
                         listener_type: listener_type,
                         bearer_token: bearer_token.expect("Error while retrieving header 'authorization'"),
-                        ws_stream: WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await,
-                }, database_pool.clone())
+                        ws_stream: WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(websocket_config)).await,
+                        last_seen: Instant::now(),
+                        superseded,
+                        provider_kinds: Vec::new(),
+                        max_state_version: STATE_VERSION,
+                        protocol_version: MIN_PROTOCOL_VERSION,
+                        patched_from: HashMap::new(),
+                        codec,
+                        message_ids: MessageIds::default(),
+                        pending_replies: HashMap::new(),
+                        _registration: registration,
+                }, database_pool.clone(), settings)
                 .await;
             }
             Err(e) => println!("Failed to upgrade {}", e),
@@ -355,31 +1044,148 @@ async fn new_session(mut req: Request<Incoming>, addr: SocketAddr, database_pool
     res.headers_mut().append(UPGRADE, websocket);
     res.headers_mut().append(SEC_WEBSOCKET_ACCEPT, derived.unwrap().parse().unwrap());
 
+    if let Some(device_token) = issued_device_token {
+        res.headers_mut().append("device-token", device_token.parse().unwrap());
+    }
+
+    if let Some(compression) = cli_compression {
+        res.headers_mut().append(COMPRESSION_HEADER, HeaderValue::from_static(compression.as_str()));
+    }
+
     Ok(res)
 }
 
-async fn handle_connection(mut session: ListenerSession, database_pool: Pool) {
+async fn handle_connection(mut session: ListenerSession, database_pool: Pool, settings: SessionSettings) {
     match session.listener_type {
         ListenerType::Device => {
             debug!("Listening to device");
             let mut current_state = RequestOperations::StatusRequest;
 
-            loop {
-                listen_device(&mut session, &mut current_state, database_pool.clone()).await;
+            match receive_hello(&mut session, settings.heartbeat).await {
+                Ok(hello) => {
+                    info!(
+                        machine_id = session.machine_id,
+                        protocol_version = hello.protocol_version,
+                        agent_version = hello.agent_version,
+                        provider_kinds = format!("{:?}", hello.provider_kinds),
+                        max_state_version = hello.max_state_version,
+                        "Device connected",
+                    );
+
+                    session.provider_kinds = hello.provider_kinds;
+                    session.max_state_version = hello.max_state_version;
+                    session.protocol_version = hello.protocol_version;
+                },
+                Err(err) => {
+                    warn!(machine_id = session.machine_id, "Refusing device session: {err}");
+
+                    let close_frame = match err {
+                        SessionError::Incompatible(reason) => Some(CloseFrame {
+                            code: CloseCode::Protocol,
+                            reason: reason.into(),
+                        }),
+                        _ => None,
+                    };
+
+                    let _ = session.ws_stream.close(close_frame).await;
+
+                    return;
+                },
             }
-        },
-        ListenerType::CLI => {
-            let next_from_stream = session.ws_stream
-                .next()
-                .await
-                .unwrap()
-                .expect("Could not receive message");
 
-            let message_data = next_from_stream
-                .into_text()
-                .unwrap();
+            if let Err(err) = device_set_online(session.machine_id.clone(), true, database_pool.clone()).await {
+                error!("Could not mark device as online: {err}");
+            }
 
-            let state_operation_message: StateOperationMessage = serde_json::from_str(message_data.as_str()).expect("Could not deserialize");
+            let session_error = loop {
+                if let Err(err) = listen_device(&mut session, &mut current_state, database_pool.clone(), settings.clone()).await {
+                    info!(machine_id = session.machine_id, "Closing device session: {err}");
+                    break err;
+                }
+            };
+
+            let close_frame = match session_error {
+                SessionError::ShuttingDown => Some(CloseFrame {
+                    code: CloseCode::Away,
+                    reason: "Server is shutting down".into(),
+                }),
+                SessionError::Superseded => Some(CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: "Superseded by a newer session".into(),
+                }),
+                SessionError::InvalidFrame(ref err) => Some(CloseFrame {
+                    code: CloseCode::Protocol,
+                    reason: err.to_string().into(),
+                }),
+                _ => None,
+            };
+
+            let _ = session.ws_stream.close(close_frame).await;
+
+            // The newer session already marked the device as online
+            if let SessionError::Superseded = session_error {
+                return;
+            }
+
+            if let Err(err) = device_set_online(session.machine_id.clone(), false, database_pool.clone()).await {
+                error!("Could not mark device as offline: {err}");
+            }
+
+            // The agent reconnects as soon as the server is back, so a restart
+            // is not reported as devices going offline
+            if let SessionError::ShuttingDown = session_error {
+                return;
+            }
+
+            match device_project_ids(session.machine_id.clone(), database_pool.clone()).await {
+                Ok(project_ids) => {
+                    for project_id in project_ids {
+                        settings.webhooks.notify(WebhookEvent::DeviceOffline, project_id, serde_json::json!({
+                            "machine_id": session.machine_id,
+                        }));
+                    }
+                },
+                Err(err) => error!("Could not load device projects: {err}"),
+            }
+        },
+        ListenerType::CLI => {
+            let next_from_stream = match session.ws_stream.next().await {
+                Some(Ok(message)) => message,
+                Some(Err(err)) => {
+                    warn!("Could not receive CLI message: {err}");
+                    return;
+                },
+                None => {
+                    debug!("CLI closed the connection before sending a message");
+                    return;
+                },
+            };
+
+            // Compressed pushes come as binary frames
+            let message_data = match next_from_stream {
+                Message::Binary(data) => decompress(session.codec.compression, data.as_ref(), session.codec.max_message_size)
+                    .map_err(|err| err.to_string())
+                    .and_then(|data| String::from_utf8(data).map_err(|err| err.to_string())),
+                message => message.into_text().map(|text| text.to_string()).map_err(|err| err.to_string()),
+            };
+
+            let state_operation_message = message_data.and_then(|message_data| {
+                serde_json::from_str::<StateOperationMessage>(message_data.as_str())
+                    .map_err(|err| format!("Invalid message: {err}"))
+            });
+
+            let state_operation_message = match state_operation_message {
+                Ok(state_operation_message) => state_operation_message,
+                Err(err) => {
+                    warn!("Could not read CLI message: {err}");
+
+                    let reply = json!({ "msg": err, "data": null });
+                    let _ = session.ws_stream.send(Message::text(reply.to_string())).await;
+                    let _ = session.ws_stream.close(None).await;
+
+                    return;
+                },
+            };
 
             println!("Command: {:?}", state_operation_message.action);
 
@@ -387,7 +1193,31 @@ async fn handle_connection(mut session: ListenerSession, database_pool: Pool) {
 
             match state_operation_message.action {
                 StateAction::Up => {
-                    let _ = conn.interact(move |conn| {
+                    if let Some(Err(err)) = state_operation_message.state.as_deref().map(DesiredState::parse) {
+                        warn!("State refused: {err}");
+
+                        let reply = json!({ "msg": format!("Invalid state: {err}"), "data": null });
+                        let _ = session.ws_stream.send(Message::text(reply.to_string())).await;
+
+                        return;
+                    }
+
+                    // Stored canonical so devices can be sent patches of it,
+                    // which is also the form the user signs
+                    let state = state_operation_message.state
+                        .as_deref()
+                        .map(|state| state_patch::canonical_json(state).unwrap_or(state.to_string()));
+
+                    if let Some(Err(err)) = state.as_deref().map(|state| verify_push_signature(state_operation_message.signature.as_ref(), &state_operation_message.project, &state_operation_message.environment, state)) {
+                        warn!("State refused: {err}");
+
+                        let reply = json!({ "msg": err.to_string(), "data": null });
+                        let _ = session.ws_stream.send(Message::text(reply.to_string())).await;
+
+                        return;
+                    }
+
+                    let pushed_state = conn.interact(move |conn| {
                         let project_result = projects::table
                             .filter(projects::name.eq(state_operation_message.project.clone()))
                             .select(Projects::as_select())
@@ -444,20 +1274,78 @@ async fn handle_connection(mut session: ListenerSession, database_pool: Pool) {
                             state = state_operation_message.state,
                         );
 
-                        insert_into(states::dsl::states)
+                        let state = state.expect("Expected a JSON");
+
+                        // The state is refused if it claims something another
+                        // environment already declares on any enrolled device
+                        let conflicts = environment_conflicts(conn, &environment, state.as_str())
+                            .expect("Could not check resource conflicts");
+
+                        if !conflicts.is_empty() {
+                            return Err(PushRefusal::Conflicts(conflicts));
+                        }
+
+                        let sequence = environment_next_sequence(conn, environment.id)
+                            .expect("Could not number the state");
+
+                        verify_push_sequence(state_operation_message.signature.as_ref(), sequence)
+                            .map_err(PushRefusal::Sequence)?;
+
+                        let state_id: i32 = insert_into(states::dsl::states)
                             .values((
-                                states::json.eq(state_operation_message.state.expect("Expected a JSON").clone()),
+                                states::json.eq(state),
                                 states::environment_id.eq(environment.id),
+                                states::override_window.eq(state_operation_message.override_window),
+                                states::signature.eq(state_operation_message.signature.as_ref().map(|signature| signature.signature.clone())),
+                                states::signed_by.eq(state_operation_message.signature.as_ref().map(|signature| signature.signed_by.clone())),
+                                states::sequence.eq(sequence),
                             ))
-                            .execute(conn).expect("Could not insert state");
+                            .returning(states::id)
+                            .get_result(conn).expect("Could not insert state");
+
+                        Ok((project.id, environment.name, state_id))
                         }).await;
+
+                    let reply = match pushed_state {
+                        Ok(Ok((project_id, environment_name, state_id))) => {
+                            settings.webhooks.notify(WebhookEvent::StatePushed, project_id, serde_json::json!({
+                                "operation": "up",
+                                "environment": environment_name,
+                                "state_id": state_id,
+                            }));
+
+                            json!({ "msg": "State pushed", "data": { "state_id": state_id } })
+                        },
+                        Ok(Err(PushRefusal::Conflicts(conflicts))) => {
+                            warn!(?conflicts, "State refused because of resource conflicts");
+
+                            json!({ "msg": "State conflicts with other environments on enrolled devices", "data": conflicts })
+                        },
+                        Ok(Err(PushRefusal::Sequence(err))) => {
+                            warn!("State refused: {err}");
+
+                            json!({ "msg": err, "data": null })
+                        },
+                        Err(err) => json!({ "msg": err.to_string(), "data": null }),
+                    };
+
+                    let _ = session.ws_stream.send(Message::text(reply.to_string())).await;
                 },
                 StateAction::Preview => {
                     unimplemented!("action not implemented yet");
                 },
                 StateAction::Down => {
+                    if let Err(err) = verify_push_signature(state_operation_message.signature.as_ref(), &state_operation_message.project, &state_operation_message.environment, DESTROYED_STATE) {
+                        warn!("State refused: {err}");
+
+                        let reply = json!({ "msg": err.to_string(), "data": null });
+                        let _ = session.ws_stream.send(Message::text(reply.to_string())).await;
+
+                        return;
+                    }
+
                     // Duplicated code
-                    let _ = conn.interact(move |conn| {
+                    let pushed_state = conn.interact(move |conn| -> Result<(i32, String, i32), String> {
                         let project_result = projects::table
                             .filter(projects::name.eq(state_operation_message.project.clone()))
                             .select(Projects::as_select())
@@ -514,13 +1402,41 @@ async fn handle_connection(mut session: ListenerSession, database_pool: Pool) {
                             state = "",
                         );
 
-                        insert_into(states::dsl::states)
+                        let sequence = environment_next_sequence(conn, environment.id)
+                            .expect("Could not number the state");
+
+                        verify_push_sequence(state_operation_message.signature.as_ref(), sequence)?;
+
+                        let state_id: i32 = insert_into(states::dsl::states)
                             .values((
-                                states::json.eq("{}".to_string()),
+                                states::json.eq(DESTROYED_STATE.to_string()),
                                 states::environment_id.eq(environment.id),
+                                states::signature.eq(state_operation_message.signature.as_ref().map(|signature| signature.signature.clone())),
+                                states::signed_by.eq(state_operation_message.signature.as_ref().map(|signature| signature.signed_by.clone())),
+                                states::sequence.eq(sequence),
                             ))
-                            .execute(conn).expect("Could not insert state");
+                            .returning(states::id)
+                            .get_result(conn).expect("Could not insert state");
+
+                        Ok((project.id, environment.name, state_id))
                         }).await;
+
+                    match pushed_state {
+                        Ok(Ok((project_id, environment_name, state_id))) => {
+                            settings.webhooks.notify(WebhookEvent::StatePushed, project_id, serde_json::json!({
+                                "operation": "down",
+                                "environment": environment_name,
+                                "state_id": state_id,
+                            }));
+                        },
+                        Ok(Err(err)) => {
+                            warn!("State refused: {err}");
+
+                            let reply = json!({ "msg": err, "data": null });
+                            let _ = session.ws_stream.send(Message::text(reply.to_string())).await;
+                        },
+                        Err(err) => error!("Could not push state: {err}"),
+                    }
                 }
             }
         },
@@ -539,7 +1455,9 @@ use hyper::{
 
 use tokio_tungstenite::tungstenite::{
     handshake::derive_accept_key,
-    protocol::Role,
+    protocol::{frame::coding::CloseCode, CloseFrame, Role, WebSocketConfig},
+    Bytes,
+    Message,
 };
 
 type Body = http_body_util::Full<hyper::body::Bytes>;
@@ -556,9 +1474,59 @@ async fn main() {
         .event_format(format)
         .init();
 
+    let cmd = clap::Command::new("server")
+        .bin_name("server")
+        .subcommand(
+            clap::command!("prune").arg(
+                Arg::new("dry-run")
+                    .long("dry-run")
+                    .action(ArgAction::SetTrue),
+            ),
+        )
+        .subcommand(
+            clap::command!("user")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::command!("create")
+                        .arg(clap::arg!(-n --name <NAME>).required(true))
+                        .arg(clap::arg!(-t --token <TOKEN>).help("Access token to use instead of a generated one")),
+                )
+                .subcommand(
+                    clap::command!("delete").arg(clap::arg!(-n --name <NAME>).required(true)),
+                )
+                .subcommand(clap::command!("list")),
+        )
+        .subcommand(
+            clap::command!("device")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::command!("create")
+                        .arg(clap::arg!(-n --name <NAME>).required(true))
+                        .arg(clap::arg!(-i --"machine-id" <UUID>).required(true))
+                        .arg(clap::arg!(-t --token <TOKEN>).help("Device token to use instead of a generated one")),
+                )
+                .subcommand(
+                    clap::command!("delete").arg(clap::arg!(-i --"machine-id" <UUID>).required(true)),
+                ),
+        );
+
+    let matches = cmd.get_matches();
+
     let config: Config = Figment::new()
         .merge(Yaml::file("config.yml"))
-        .join(Env::raw().only(&["PORT", "ADDRESS", "DATABASE_URL"]))
+        .join(Env::raw().only(&[
+            "PORT",
+            "ADDRESS",
+            "DATABASE_URL",
+            "PRUNE_INTERVAL_SECONDS",
+            "SECRETS_KEY",
+            "STATE_SIGNING_KEY",
+            "HEARTBEAT_INTERVAL_SECONDS",
+            "IDLE_TIMEOUT_SECONDS",
+            "SHUTDOWN_TIMEOUT_SECONDS",
+            "DUPLICATE_SESSION_POLICY",
+            "MAX_MESSAGE_SIZE_BYTES",
+        ]))
         .extract().unwrap();
 
     let database_url = config.database_url.expect("Database url is required.");
@@ -567,6 +1535,122 @@ async fn main() {
         .max_size(8)
         .build()
         .unwrap();
+
+    if let Some(("prune", matches)) = matches.subcommand() {
+        let dry_run = matches.get_flag("dry-run");
+
+        let reports = prune_states(dry_run, pool.clone())
+            .await
+            .expect("Could not prune states");
+
+        for report in reports {
+            info!(
+                operation = "prune",
+                dry_run = dry_run,
+                project_id = report.project_id,
+                environment = report.environment,
+                pruned_state_ids = format!("{:?}", report.pruned_state_ids),
+            );
+        }
+
+        return;
+    }
+
+    let admin_result = match matches.subcommand() {
+        Some(("user", matches)) => {
+            let user_message = |matches: &clap::ArgMatches| AdminUserOperationMessage {
+                username: matches.get_one::<String>("name").expect("Expected name").to_string(),
+                access_token: matches.try_get_one::<String>("token").ok().flatten().cloned(),
+            };
+
+            let action = match matches.subcommand() {
+                Some(("create", matches)) => AdminUserAction::CreateUser(user_message(matches)),
+                Some(("delete", matches)) => AdminUserAction::DeleteUser(user_message(matches)),
+                Some(("list", _)) => AdminUserAction::ListUsers,
+                _ => unreachable!("Clap should ensure we don't get here"),
+            };
+
+            Some(run_user_action(action, pool.clone()).await)
+        },
+        Some(("device", matches)) => {
+            let device_message = |matches: &clap::ArgMatches| AdminDeviceOperationMessage {
+                machine_id: matches.get_one::<String>("machine-id").expect("Expected machine-id").to_string(),
+                name: matches.try_get_one::<String>("name").ok().flatten().cloned(),
+                token: matches.try_get_one::<String>("token").ok().flatten().cloned(),
+            };
+
+            let action = match matches.subcommand() {
+                Some(("create", matches)) => AdminDeviceAction::CreateDevice(device_message(matches)),
+                Some(("delete", matches)) => AdminDeviceAction::DeleteDevice(device_message(matches)),
+                _ => unreachable!("Clap should ensure we don't get here"),
+            };
+
+            Some(run_device_action(action, pool.clone()).await)
+        },
+        _ => None,
+    };
+
+    match admin_result {
+        Some(Ok(output)) => {
+            for line in output {
+                println!("{line}");
+            }
+
+            return;
+        },
+        Some(Err(err)) => {
+            error!("{err}");
+            std::process::exit(1);
+        },
+        None => {},
+    }
+
+    let secrets_cipher = config.secrets_key
+        .map(|secrets_key| SecretsCipher::from_base64_key(secrets_key.as_str()).expect("Invalid secrets key"));
+
+    if secrets_cipher.is_none() {
+        info!("No secrets key configured, states referencing secrets will not be sent to devices");
+    }
+
+    let state_signing_key = config.state_signing_key
+        .map(|state_signing_key| signing_key_from_base64(state_signing_key.as_str()).expect("Invalid state signing key"));
+
+    match &state_signing_key {
+        Some(state_signing_key) => info!(public_key = public_key_base64(state_signing_key), "Signing states sent to devices"),
+        None => info!("No state signing key configured, only states signed by users reach devices signed"),
+    }
+
+    let (webhook_notifier, webhook_notifications) = WebhookNotifier::channel();
+    let (shutdown_sender, shutdown_signal) = watch::channel(false);
+    let (drain_guard, mut drained) = mpsc::channel::<()>(1);
+
+    let settings = SessionSettings {
+        secrets_cipher,
+        state_signing_key,
+        webhooks: webhook_notifier,
+        shutdown: Shutdown {
+            signal: shutdown_signal,
+            _drain_guard: drain_guard,
+        },
+        sessions: SessionRegistry::default(),
+        duplicate_session_policy: config.duplicate_session_policy
+            .map(|policy| policy.parse().expect("Invalid duplicate session policy"))
+            .unwrap_or(DuplicateSessionPolicy::Reject),
+        max_message_size: config.max_message_size_bytes.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+        heartbeat: HeartbeatSettings {
+            interval: Duration::from_secs(config.heartbeat_interval_seconds.unwrap_or(15)),
+            idle_timeout: Duration::from_secs(config.idle_timeout_seconds.unwrap_or(45)),
+        },
+    };
+
+    // Sessions from a previous run are gone, devices come back online when they reconnect
+    devices_mark_all_offline(pool.clone())
+        .await
+        .expect("Could not reset device status");
+
+    let prune_interval = Duration::from_secs(config.prune_interval_seconds.unwrap_or(3600));
+    tokio::spawn(run_retention_task(prune_interval, pool.clone()));
+    tokio::spawn(run_webhook_dispatcher(webhook_notifications, pool.clone()));
     
     let address = config.address.unwrap_or("127.0.0.1".into());
     let port = config.port.unwrap_or(9734u64.into());
@@ -578,18 +1662,57 @@ async fn main() {
     let try_socket = TcpListener::bind(full_address).await;
     let listener = try_socket.expect("Failed to bind");
     
-    while let Ok((stream, addr)) = listener.accept().await {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("failed to accept connection: {err:?}");
+                    break;
+                }
+            },
+            _ = sigterm.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+
         let pool_ref = pool.clone();
+        let settings_ref = settings.clone();
+        let mut shutdown = settings.shutdown.clone();
 
         tokio::spawn(async move {
-            let service = service_fn(move |req| new_session(req, addr, pool_ref.clone()));
+            let service = service_fn(move |req| new_session(req, addr, pool_ref.clone(), settings_ref.clone()));
 
             let io = TokioIo::new(stream);
             let conn = http1::Builder::new().serve_connection(io, service).with_upgrades();
+            tokio::pin!(conn);
+
+            // Idle keep-alive connections are closed, requests in flight finish
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = shutdown.requested() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                },
+            };
 
-            if let Err(err) = conn.await {
+            if let Err(err) = result {
                 error!("failed to serve connection: {err:?}");
             }
         });
     }
+
+    info!("Shutting down, no longer accepting connections");
+
+    drop(listener);
+    let _ = shutdown_sender.send(true);
+    drop(settings);
+
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_seconds.unwrap_or(30));
+
+    match timeout(shutdown_timeout, drained.recv()).await {
+        Ok(_) => info!("All sessions closed"),
+        Err(_) => error!("Sessions still open after {shutdown_timeout:?}, exiting anyway"),
+    }
 }
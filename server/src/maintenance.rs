@@ -0,0 +1,188 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, NaiveTime, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+
+use crate::models::Environments;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+#[derive(Debug, PartialEq)]
+pub enum MaintenanceWindowError {
+    InvalidWindow(String),
+    InvalidTimezone(String),
+}
+
+impl fmt::Display for MaintenanceWindowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaintenanceWindowError::InvalidWindow(window) => {
+                write!(f, "Invalid maintenance window '{window}', expected e.g. 'Mon-Fri 22:00-06:00' or 'Sat,Sun 00:00-24:00'")
+            },
+            MaintenanceWindowError::InvalidTimezone(timezone) => write!(f, "Unknown time zone '{timezone}'"),
+        }
+    }
+}
+
+impl std::error::Error for MaintenanceWindowError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceWindow {
+    // Indexed by days from Monday
+    days: [bool; 7],
+    start: u32,
+    end: u32,
+}
+
+fn parse_minutes(time: &str) -> Option<u32> {
+    if time == "24:00" {
+        return Some(MINUTES_PER_DAY);
+    }
+
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .ok()
+        .map(|time| time.hour() * 60 + time.minute())
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = MaintenanceWindowError;
+
+    // Days are a comma separated list of weekdays or ranges of them, followed
+    // by the time range, e.g. `Mon-Fri 22:00-06:00`.
+    fn from_str(window: &str) -> Result<Self, Self::Err> {
+        let invalid_window = || MaintenanceWindowError::InvalidWindow(window.to_string());
+
+        let (days_part, times_part) = window.trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(invalid_window)?;
+
+        let mut days = [false; 7];
+
+        for day_range in days_part.split(',') {
+            let (first, last) = day_range.split_once('-').unwrap_or((day_range, day_range));
+
+            let first: Weekday = first.parse().map_err(|_| invalid_window())?;
+            let last: Weekday = last.parse().map_err(|_| invalid_window())?;
+
+            let mut day = first;
+
+            loop {
+                days[day.num_days_from_monday() as usize] = true;
+
+                if day == last {
+                    break;
+                }
+
+                day = day.succ();
+            }
+        }
+
+        let (start, end) = times_part.trim().split_once('-').ok_or_else(invalid_window)?;
+
+        let start = parse_minutes(start.trim())
+            .filter(|start| *start < MINUTES_PER_DAY)
+            .ok_or_else(invalid_window)?;
+        let end = parse_minutes(end.trim()).ok_or_else(invalid_window)?;
+
+        if start == end {
+            return Err(invalid_window());
+        }
+
+        Ok(MaintenanceWindow { days, start, end })
+    }
+}
+
+impl MaintenanceWindow {
+    // A window ending before it starts runs past midnight, and belongs to the
+    // day it starts on.
+    fn contains(&self, weekday: Weekday, minutes: u32) -> bool {
+        let today = self.days[weekday.num_days_from_monday() as usize];
+        let yesterday = self.days[weekday.pred().num_days_from_monday() as usize];
+
+        if self.start < self.end {
+            today && minutes >= self.start && minutes < self.end
+        } else {
+            (today && minutes >= self.start) || (yesterday && minutes < self.end)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MaintenanceSchedule {
+    windows: Vec<MaintenanceWindow>,
+    timezone: Tz,
+}
+
+impl MaintenanceSchedule {
+    // Windows are stored separated by semicolons, and are evaluated in UTC
+    // unless a time zone is given.
+    pub fn parse(windows: &str, timezone: Option<&str>) -> Result<Self, MaintenanceWindowError> {
+        let timezone = match timezone {
+            Some(timezone) => timezone
+                .parse()
+                .map_err(|_| MaintenanceWindowError::InvalidTimezone(timezone.to_string()))?,
+            None => Tz::UTC,
+        };
+
+        let windows = windows
+            .split(';')
+            .map(str::trim)
+            .filter(|window| !window.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<MaintenanceWindow>, _>>()?;
+
+        Ok(MaintenanceSchedule { windows, timezone })
+    }
+
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        let local_now = now.with_timezone(&self.timezone);
+        let minutes = local_now.hour() * 60 + local_now.minute();
+
+        self.windows.iter().any(|window| window.contains(local_now.weekday(), minutes))
+    }
+}
+
+// Environments without windows accept updates at any time.
+pub fn environment_schedule(environment: &Environments) -> Option<Result<MaintenanceSchedule, MaintenanceWindowError>> {
+    environment.maintenance_windows
+        .as_deref()
+        .map(|windows| MaintenanceSchedule::parse(windows, environment.maintenance_timezone.as_deref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::{MaintenanceSchedule, MaintenanceWindowError};
+
+    fn at(timestamp: &str) -> DateTime<Utc> {
+        timestamp.parse().unwrap()
+    }
+
+    #[test]
+    fn overnight_window_in_timezone() {
+        // Santiago is UTC-3 in October
+        let schedule = MaintenanceSchedule::parse("Mon-Fri 22:00-06:00; Sun 10:00-12:00", Some("America/Santiago")).unwrap();
+
+        // Friday 23:30 and Saturday 05:59 local time
+        assert!(schedule.is_open(at("2026-10-24T02:30:00Z")));
+        assert!(schedule.is_open(at("2026-10-24T08:59:00Z")));
+
+        // Saturday 22:30 local, no window starts on Saturday
+        assert!(!schedule.is_open(at("2026-10-25T01:30:00Z")));
+
+        // Sunday 11:00 local, but 14:00 in UTC
+        assert!(schedule.is_open(at("2026-10-25T14:00:00Z")));
+        assert!(!schedule.is_open(at("2026-10-25T16:00:00Z")));
+
+        assert_eq!(
+            MaintenanceSchedule::parse("Mon-Fri 22:00", None).err(),
+            Some(MaintenanceWindowError::InvalidWindow(String::from("Mon-Fri 22:00"))),
+        );
+        assert_eq!(
+            MaintenanceSchedule::parse("Sat 00:00-24:00", Some("Mars/Olympus")).err(),
+            Some(MaintenanceWindowError::InvalidTimezone(String::from("Mars/Olympus"))),
+        );
+    }
+}
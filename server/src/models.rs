@@ -10,6 +10,28 @@ pub struct States {
     pub json: String,
     pub created_at: NaiveDateTime,
     pub environment_id: i32,
+    pub promoted_from_state_id: Option<i32>,
+    pub override_window: bool,
+    // Signature of the pushing user, see `shared::signing`
+    pub signature: Option<String>,
+    pub signed_by: Option<String>,
+    // Position among the states of the environment, signed along with the project
+    pub sequence: i64,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Associations, Debug)]
+#[diesel(table_name = crate::schema::secrets)]
+#[diesel(belongs_to(Environments, foreign_key = environment_id))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Secrets {
+    pub id: i32,
+    pub name: String,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub revision: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub environment_id: i32,
 }
 
 #[derive(Queryable, Selectable, Identifiable, Debug)]
@@ -21,6 +43,36 @@ pub struct Devices {
     pub name: String,
     pub machine_id: Option<String>,
     pub created_at: NaiveDateTime,
+    pub token_hash: Option<String>,
+    pub online: bool,
+    pub last_seen_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Associations, Debug)]
+#[diesel(table_name = crate::schema::device_labels)]
+#[diesel(belongs_to(Devices, foreign_key = device_id))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct DeviceLabels {
+    pub id: i32,
+    pub key: String,
+    pub value: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub device_id: i32,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Associations, Debug)]
+#[diesel(table_name = crate::schema::join_tokens)]
+#[diesel(belongs_to(Environments, foreign_key = environment_id))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct JoinTokens {
+    pub id: i32,
+    pub token_hash: String,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub environment_id: i32,
 }
 
 #[derive(Queryable, Selectable, Identifiable, PartialEq, Debug)]
@@ -57,6 +109,12 @@ pub struct Environments {
     pub updated_at: Option<NaiveDateTime>,
     pub deleted_at: Option<NaiveDateTime>,
     pub project_id: i32,
+    pub retention_keep_last: Option<i32>,
+    pub retention_max_age_days: Option<i32>,
+    pub label_selector: Option<String>,
+    pub maintenance_windows: Option<String>,
+    pub maintenance_timezone: Option<String>,
+    pub drift_mode: String,
 }
 
 #[derive(Identifiable, Queryable, Selectable, Associations, Debug)]
@@ -71,6 +129,15 @@ pub struct DevicesEnvironments {
     pub deleted_at: Option<NaiveDateTime>,
     pub device_id: i32,
     pub environment_id: i32,
+    pub reported_state_hash: Option<String>,
+    pub sent_state_id: Option<i32>,
+    pub sent_state_hash: Option<String>,
+    pub via_selector: bool,
+    pub apply_pending: bool,
+    pub drift: Option<String>,
+    pub drift_checked_at: Option<NaiveDateTime>,
+    pub status: Option<String>,
+    pub status_updated_at: Option<NaiveDateTime>,
 }
 
 #[derive(Identifiable, Queryable, Selectable, Associations, Debug)]
@@ -86,3 +153,33 @@ pub struct UsersProjects {
     pub user_id: i32,
     pub project_id: i32,
 }
+
+#[derive(Queryable, Selectable, Identifiable, Associations, Debug, Clone)]
+#[diesel(belongs_to(Projects, foreign_key = project_id))]
+#[diesel(table_name = crate::schema::webhooks)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Webhooks {
+    pub id: i32,
+    pub url: String,
+    pub events: String,
+    pub secret: String,
+    pub created_at: NaiveDateTime,
+    pub project_id: i32,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Associations, Debug)]
+#[diesel(belongs_to(Webhooks, foreign_key = webhook_id))]
+#[diesel(table_name = crate::schema::webhook_deliveries)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct WebhookDeliveries {
+    pub id: i32,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i32,
+    pub delivered: bool,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub webhook_id: i32,
+}
@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use shared::desired_state::{DesiredState, ResourceSpec};
+use shared::rest_dtos::ChangeKind;
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StateChange {
+    pub urn: String,
+    pub change: ChangeKind,
+}
+
+fn resources_by_urn(state_json: &str) -> BTreeMap<String, ResourceSpec> {
+    let state = DesiredState::from_json(state_json).unwrap_or_default();
+
+    state.resources
+        .into_iter()
+        .map(|resource| (resource.urn.to_string(), resource))
+        .collect()
+}
+
+// Resource level changes needed to go from the current state to the new one,
+// ordered by URN.
+pub fn state_diff(current_json: &str, new_json: &str) -> Vec<StateChange> {
+    let current = resources_by_urn(current_json);
+    let new = resources_by_urn(new_json);
+
+    let mut changes: Vec<StateChange> = new
+        .iter()
+        .filter_map(|(urn, resource)| {
+            let change = match current.get(urn) {
+                None => ChangeKind::Added,
+                Some(current_resource) if current_resource != resource => ChangeKind::Changed,
+                Some(_) => return None,
+            };
+
+            Some(StateChange { urn: urn.clone(), change })
+        })
+        .chain(current
+            .keys()
+            .filter(|urn| !new.contains_key(*urn))
+            .map(|urn| StateChange { urn: urn.clone(), change: ChangeKind::Removed }))
+        .collect();
+
+    changes.sort_by(|a, b| a.urn.cmp(&b.urn));
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{state_diff, ChangeKind, StateChange};
+
+    #[test]
+    fn diff_by_urn() {
+        let staging = r#"{"resources":[
+            {"urn":"ovejas.system::User::admin","parameters":{"name":"admin","uid":1000}},
+            {"urn":"ovejas.system::File::motd","parameters":{"path":"/etc/motd","content":"v2"}},
+            {"urn":"ovejas.system::File::issue","parameters":{"path":"/etc/issue","content":"hi"}}
+        ]}"#;
+
+        let prod = r#"{"resources":[
+            {"urn":"ovejas.system::User::admin","parameters":{"name":"admin","uid":1000}},
+            {"urn":"ovejas.system::File::motd","parameters":{"path":"/etc/motd","content":"v1"}},
+            {"urn":"ovejas.system::User::legacy","parameters":{"name":"legacy","uid":1001}}
+        ]}"#;
+
+        assert_eq!(state_diff(prod, staging), vec![
+            StateChange { urn: String::from("ovejas.system::File::issue"), change: ChangeKind::Added },
+            StateChange { urn: String::from("ovejas.system::File::motd"), change: ChangeKind::Changed },
+            StateChange { urn: String::from("ovejas.system::User::legacy"), change: ChangeKind::Removed },
+        ]);
+
+        assert!(state_diff(staging, staging).is_empty());
+        assert_eq!(state_diff("{}", staging).len(), 3);
+    }
+}
@@ -1,37 +1,87 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
 
+use chrono::{NaiveDateTime, Utc};
 use diesel::prelude::*;
 use deadpool_diesel::sqlite::Pool;
 use diesel::result::Error::NotFound;
 
-use crate::schema::{devices, environments, environments_devices, users, projects};
-use crate::models::{Projects, Environments, Devices};
+use crate::conflicts::{find_conflicts, resource_claims, ResourceConflict};
+use crate::drift::DriftMode;
+use crate::interpolation::{interpolate_state, DeviceVariables};
+use crate::labels::LabelSelector;
+use crate::promotion::{state_diff, StateChange};
+use crate::schema::{device_labels, devices, environments, environments_devices, join_tokens, users, projects, secrets, states, users_projects, webhook_deliveries, webhooks};
+use crate::models::{DeviceLabels, Projects, Environments, Devices, DevicesEnvironments, JoinTokens, Secrets, States, Users, WebhookDeliveries, Webhooks};
+use crate::secrets::{SecretError, SecretsCipher};
+use crate::tokens::{generate_token, hash_token};
+use crate::state::hash_to_hex;
+use tracing::warn;
+use shared::request_operations::{DeviceStatus, ResourceDrift};
+use crate::webhooks::{build_payload, is_subscribed, WebhookEvent};
 
 
 
-pub async fn device_create(device_name: String, machine_id: String, database_pool: Pool) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn device_create(
+    device_name: String,
+    machine_id: String,
+    token_hash: String,
+    database_pool: Pool
+) -> Result<(), Box<dyn std::error::Error>> {
     let conn = database_pool.get().await.expect("Could not get database connection");
 
     conn.interact(move |conn| {
-        diesel::insert_into(devices::table)
-            .values((devices::name.eq(device_name), devices::machine_id.eq(machine_id)))
-            .execute(conn)
+        conn.transaction(|conn| -> Result<(), diesel::result::Error> {
+            diesel::insert_into(devices::table)
+                .values((
+                    devices::name.eq(device_name),
+                    devices::machine_id.eq(machine_id),
+                    devices::token_hash.eq(token_hash),
+                ))
+                .execute(conn)?;
+
+            // A new device has no labels, but `!=` selectors can already match it
+            reconcile_selector_memberships(conn)
+        })
     }).await??;
 
     Ok(())
 }
 
-pub async fn device_delete(machine_id: String, database_pool: Pool) -> Result<(), Box<dyn std::error::Error>> {
+// Also removes the device from its environments and drops its labels.
+pub async fn device_delete(machine_id: String, database_pool: Pool) -> Result<usize, Box<dyn std::error::Error>> {
     let conn = database_pool.get().await.expect("Could not get database connection");
 
-    conn.interact(move |conn| {
-        let devices = devices::table;
+    let deleted = conn.interact(move |conn| {
+        conn.transaction(|conn| -> Result<usize, diesel::result::Error> {
+            let device_ids: Vec<i32> = devices::table
+                .filter(devices::machine_id.eq(machine_id))
+                .select(devices::id)
+                .load(conn)?;
 
-        diesel::delete(devices.filter(devices::machine_id.like(machine_id)))
-            .execute(conn)
+            diesel::delete(environments_devices::table.filter(environments_devices::device_id.eq_any(&device_ids)))
+                .execute(conn)?;
+
+            diesel::delete(device_labels::table.filter(device_labels::device_id.eq_any(&device_ids)))
+                .execute(conn)?;
+
+            diesel::delete(devices::table.filter(devices::id.eq_any(&device_ids)))
+                .execute(conn)
+        })
     }).await??;
 
-    Ok(())
+    Ok(deleted)
+}
+
+pub async fn device_exists(machine_id: String, database_pool: Pool) -> Result<bool, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let exists = conn.interact(move |conn| {
+        diesel::select(diesel::dsl::exists(devices::table.filter(devices::machine_id.eq(machine_id))))
+            .get_result(conn)
+    }).await??;
+
+    Ok(exists)
 }
 
 pub async fn user_create(user_name: String, access_token: String, database_pool: Pool) -> Result<(), Box<dyn std::error::Error>> {
@@ -46,21 +96,123 @@ pub async fn user_create(user_name: String, access_token: String, database_pool:
     Ok(())
 }
 
+pub async fn user_exists(user_name: String, database_pool: Pool) -> Result<bool, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let exists = conn.interact(move |conn| {
+        diesel::select(diesel::dsl::exists(users::table.filter(users::name.eq(user_name))))
+            .get_result(conn)
+    }).await??;
+
+    Ok(exists)
+}
+
+// Also removes the user from its projects.
+pub async fn user_delete(user_name: String, database_pool: Pool) -> Result<usize, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let deleted = conn.interact(move |conn| {
+        conn.transaction(|conn| -> Result<usize, diesel::result::Error> {
+            let user_ids: Vec<i32> = users::table
+                .filter(users::name.eq(user_name))
+                .select(users::id)
+                .load(conn)?;
+
+            diesel::delete(users_projects::table.filter(users_projects::user_id.eq_any(&user_ids)))
+                .execute(conn)?;
+
+            diesel::delete(users::table.filter(users::id.eq_any(&user_ids)))
+                .execute(conn)
+        })
+    }).await??;
+
+    Ok(deleted)
+}
+
+// The CLI authenticates with the access token of a user, only its hash is stored
+pub async fn user_find_by_token_hash(token_hash: String, database_pool: Pool) -> Result<Option<Users>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let user = conn.interact(move |conn| {
+        users::table
+            .filter(users::access_token.eq(token_hash))
+            .filter(users::deleted_at.is_null())
+            .select(Users::as_select())
+            .first(conn)
+            .optional()
+    }).await??;
+
+    Ok(user)
+}
+
+pub async fn user_list(database_pool: Pool) -> Result<Vec<Users>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let users = conn.interact(move |conn| {
+        users::table
+            .select(Users::as_select())
+            .order(users::name.asc())
+            .load(conn)
+    }).await??;
+
+    Ok(users)
+}
+
+fn find_environment(
+    conn: &mut SqliteConnection,
+    project_name: String,
+    environment_name: String,
+) -> Result<Environments, diesel::result::Error> {
+    let project: Projects = projects::table
+        .filter(projects::name.eq(project_name))
+        .select(Projects::as_select())
+        .get_result(conn)?;
+
+    environments::table
+        .filter(environments::name.eq(environment_name))
+        .filter(environments::project_id.eq(project.id))
+        .select(Environments::as_select())
+        .get_result(conn)
+}
+
 enum RepositoryError {
     ProjectNotFound,
     EnvironmentNotFound,
     DeviceNotFound,
 }
 
+#[derive(Debug)]
+pub enum EnrollError {
+    Conflicts(Vec<ResourceConflict>),
+    Database(diesel::result::Error),
+}
+
+impl std::fmt::Display for EnrollError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnrollError::Conflicts(conflicts) => write!(f, "Environment conflicts with {} resource(s) already on the device", conflicts.len()),
+            EnrollError::Database(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for EnrollError {}
+
+impl From<diesel::result::Error> for EnrollError {
+    fn from(err: diesel::result::Error) -> Self {
+        EnrollError::Database(err)
+    }
+}
+
 pub async fn enroll_device_into_environment(
     machine_id: String,
     project_name: String,
     environment_name: String,
     database_pool: Pool
-) -> Result<(), diesel::result::Error> {
+) -> Result<(), EnrollError> {
     let conn = database_pool.get().await.expect("Could not get database connection");
 
-    let result = conn.interact(|conn| -> Result<(), diesel::result::Error> {
+    let result = conn.interact(|conn| -> Result<(), EnrollError> {
         let project_result = projects::table
             .filter(projects::name.eq(project_name))
             .select(Projects::as_select())
@@ -68,7 +220,7 @@ pub async fn enroll_device_into_environment(
 
         let project: Projects = match project_result {
             Ok(project) => project,
-            Err(err) => return Err(err),
+            Err(err) => return Err(err.into()),
         };
 
         let environment_result = environments::table
@@ -79,7 +231,7 @@ pub async fn enroll_device_into_environment(
 
         let environment: Environments = match environment_result {
             Ok(environment) => environment,
-            Err(err) => return Err(err),
+            Err(err) => return Err(err.into()),
         };
 
         let device_result = devices::table
@@ -89,9 +241,27 @@ pub async fn enroll_device_into_environment(
 
         let device: Devices = match device_result {
             Ok(device) => device,
-            Err(err) => return Err(err),
+            Err(err) => return Err(err.into()),
         };
 
+        let latest_state = latest_state_json(conn, &environment)?;
+        let conflicts = device_conflicts(conn, &device, &environment, latest_state.as_str())?;
+
+        if !conflicts.is_empty() {
+            return Err(EnrollError::Conflicts(conflicts));
+        }
+
+        // A device already selected by labels becomes an explicit member
+        let updated_rows = diesel::update(environments_devices::table)
+            .filter(environments_devices::device_id.eq(device.id))
+            .filter(environments_devices::environment_id.eq(environment.id))
+            .set(environments_devices::via_selector.eq(false))
+            .execute(conn)?;
+
+        if updated_rows > 0 {
+            return Ok(());
+        }
+
         let insert_result = diesel::insert_into(environments_devices::table)
             .values((
                 environments_devices::device_id.eq(device.id),
@@ -99,7 +269,7 @@ pub async fn enroll_device_into_environment(
             )).execute(conn);
 
         if let Err(err) = insert_result {
-            Err(err)
+            Err(err.into())
         } else {
             Ok(())
         }
@@ -107,3 +277,1475 @@ pub async fn enroll_device_into_environment(
 
     return result;
 }
+
+// An empty list of windows removes the restriction.
+pub async fn environment_set_maintenance_windows(
+    project_name: String,
+    environment_name: String,
+    windows: Vec<String>,
+    timezone: Option<String>,
+    database_pool: Pool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let maintenance_windows = Some(windows.join("; ")).filter(|windows| !windows.is_empty());
+
+    conn.interact(move |conn| -> Result<(), diesel::result::Error> {
+        let environment = find_environment(conn, project_name, environment_name)?;
+
+        diesel::update(environments::table.find(environment.id))
+            .set((
+                environments::maintenance_windows.eq(&maintenance_windows),
+                environments::maintenance_timezone.eq(maintenance_windows.as_ref().and(timezone)),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }).await??;
+
+    Ok(())
+}
+
+pub async fn environment_set_retention(
+    project_name: String,
+    environment_name: String,
+    keep_last: Option<i32>,
+    max_age_days: Option<i32>,
+    database_pool: Pool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(move |conn| -> Result<(), diesel::result::Error> {
+        let environment = find_environment(conn, project_name, environment_name)?;
+
+        diesel::update(environments::table.find(environment.id))
+            .set((
+                environments::retention_keep_last.eq(keep_last),
+                environments::retention_max_age_days.eq(max_age_days),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }).await??;
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct StateApplyResult {
+    pub project_id: i32,
+    pub environment: String,
+    pub state_id: Option<i32>,
+    pub succeeded: bool,
+}
+
+// The device applies an update before answering the next status request, so
+// a pending state that is not reported back by then failed to apply.
+pub async fn update_reported_state_hashes(
+    machine_id: String,
+    state_hashes: HashMap<String, [u8; 16]>,
+    database_pool: Pool
+) -> Result<Vec<StateApplyResult>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let apply_results = conn.interact(move |conn| -> Result<Vec<StateApplyResult>, diesel::result::Error> {
+        let device: Devices = devices::table
+            .filter(devices::machine_id.eq(machine_id))
+            .select(Devices::as_select())
+            .get_result(conn)?;
+
+        let enrollments: Vec<(DevicesEnvironments, Environments)> = environments_devices::table
+            .inner_join(environments::table)
+            .filter(environments_devices::device_id.eq(device.id))
+            .select((DevicesEnvironments::as_select(), Environments::as_select()))
+            .load(conn)?;
+
+        let mut apply_results = Vec::new();
+
+        for (enrollment, environment) in enrollments {
+            let reported_state_hash = state_hashes
+                .get(&environment.name)
+                .map(hash_to_hex);
+
+            if let Some(sent_state_hash) = &enrollment.sent_state_hash {
+                let applied = reported_state_hash.as_ref() == Some(sent_state_hash);
+                let newly_applied = applied && enrollment.reported_state_hash.as_ref() != Some(sent_state_hash);
+
+                if newly_applied || (enrollment.apply_pending && !applied) {
+                    apply_results.push(StateApplyResult {
+                        project_id: environment.project_id,
+                        environment: environment.name.clone(),
+                        state_id: enrollment.sent_state_id,
+                        succeeded: applied,
+                    });
+                }
+            }
+
+            diesel::update(environments_devices::table.find(enrollment.id))
+                .set((
+                    environments_devices::reported_state_hash.eq(reported_state_hash),
+                    environments_devices::apply_pending.eq(false),
+                ))
+                .execute(conn)?;
+        }
+
+        Ok(apply_results)
+    }).await??;
+
+    Ok(apply_results)
+}
+
+#[derive(Debug)]
+pub struct DriftReport {
+    pub project_id: i32,
+    pub environment: String,
+    pub drift: Vec<ResourceDrift>,
+    pub newly_drifted: bool,
+    pub remediate: bool,
+}
+
+// Stores the drift the device found for each of its environments and returns
+// the environments that are drifted.
+pub async fn record_drift(
+    machine_id: String,
+    state_drift: HashMap<String, Vec<ResourceDrift>>,
+    database_pool: Pool
+) -> Result<Vec<DriftReport>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let drift_reports = conn.interact(move |conn| -> Result<Vec<DriftReport>, diesel::result::Error> {
+        let device: Devices = devices::table
+            .filter(devices::machine_id.eq(machine_id))
+            .select(Devices::as_select())
+            .get_result(conn)?;
+
+        let enrollments: Vec<(DevicesEnvironments, Environments)> = environments_devices::table
+            .inner_join(environments::table)
+            .filter(environments_devices::device_id.eq(device.id))
+            .select((DevicesEnvironments::as_select(), Environments::as_select()))
+            .load(conn)?;
+
+        let mut drift_reports = Vec::new();
+
+        for (enrollment, environment) in enrollments {
+            let Some(drift) = state_drift.get(&environment.name) else {
+                continue;
+            };
+
+            let was_drifted = enrollment.drift
+                .as_deref()
+                .and_then(|drift| serde_json::from_str::<Vec<ResourceDrift>>(drift).ok())
+                .is_some_and(|drift| !drift.is_empty());
+
+            diesel::update(environments_devices::table.find(enrollment.id))
+                .set((
+                    environments_devices::drift.eq(serde_json::to_string(drift).expect("Could not serialize drift")),
+                    environments_devices::drift_checked_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(conn)?;
+
+            if !drift.is_empty() {
+                drift_reports.push(DriftReport {
+                    project_id: environment.project_id,
+                    environment: environment.name.clone(),
+                    drift: drift.clone(),
+                    newly_drifted: !was_drifted,
+                    remediate: environment.drift_mode.parse() == Ok(DriftMode::Remediate),
+                });
+            }
+        }
+
+        Ok(drift_reports)
+    }).await??;
+
+    Ok(drift_reports)
+}
+
+// Stores the status the device reported for each of its environments.
+// `status_updated_at` only moves when the status changes, so it is when the
+// environment got into its current status.
+pub async fn record_device_status(
+    machine_id: String,
+    statuses: HashMap<String, DeviceStatus>,
+    database_pool: Pool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(move |conn| -> Result<(), diesel::result::Error> {
+        let device: Devices = devices::table
+            .filter(devices::machine_id.eq(machine_id))
+            .select(Devices::as_select())
+            .get_result(conn)?;
+
+        let enrollments: Vec<(DevicesEnvironments, Environments)> = environments_devices::table
+            .inner_join(environments::table)
+            .filter(environments_devices::device_id.eq(device.id))
+            .select((DevicesEnvironments::as_select(), Environments::as_select()))
+            .load(conn)?;
+
+        for (enrollment, environment) in enrollments {
+            let Some(status) = statuses.get(&environment.name) else {
+                continue;
+            };
+
+            let status = serde_json::to_string(status).expect("Could not serialize status");
+
+            if enrollment.status.as_ref() == Some(&status) {
+                continue;
+            }
+
+            diesel::update(environments_devices::table.find(enrollment.id))
+                .set((
+                    environments_devices::status.eq(status),
+                    environments_devices::status_updated_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(conn)?;
+        }
+
+        Ok(())
+    }).await??;
+
+    Ok(())
+}
+
+pub async fn environment_set_drift_mode(
+    project_name: String,
+    environment_name: String,
+    drift_mode: DriftMode,
+    database_pool: Pool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(move |conn| -> Result<(), diesel::result::Error> {
+        let environment = find_environment(conn, project_name, environment_name)?;
+
+        diesel::update(environments::table.find(environment.id))
+            .set(environments::drift_mode.eq(drift_mode.as_str()))
+            .execute(conn)?;
+
+        Ok(())
+    }).await??;
+
+    Ok(())
+}
+
+// Devices of the environment with their last drift report, devices that
+// never checked are left out.
+pub async fn environment_drift(
+    project_name: String,
+    environment_name: String,
+    database_pool: Pool
+) -> Result<Vec<(Devices, DevicesEnvironments)>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let device_drift = conn.interact(move |conn| -> Result<Vec<(Devices, DevicesEnvironments)>, diesel::result::Error> {
+        let environment = find_environment(conn, project_name, environment_name)?;
+
+        DevicesEnvironments::belonging_to(&environment)
+            .inner_join(devices::table)
+            .filter(environments_devices::drift.is_not_null())
+            .select((Devices::as_select(), DevicesEnvironments::as_select()))
+            .order(devices::name.asc())
+            .load(conn)
+    }).await??;
+
+    Ok(device_drift)
+}
+
+// Every device of the environment, with the last status it reported
+// The server numbers the states of each environment, the sequence orders
+// them for the agents whoever signs them
+pub fn environment_next_sequence(conn: &mut SqliteConnection, environment_id: i32) -> QueryResult<i64> {
+    let latest_sequence: Option<i64> = states::table
+        .filter(states::environment_id.eq(environment_id))
+        .select(diesel::dsl::max(states::sequence))
+        .get_result(conn)?;
+
+    Ok(latest_sequence.unwrap_or_default() + 1)
+}
+
+// What the next state pushed to the environment has to be signed with, the
+// first one creates the environment
+pub async fn environment_sequence(
+    project_name: String,
+    environment_name: String,
+    database_pool: Pool
+) -> Result<i64, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let sequence = conn.interact(move |conn| -> QueryResult<i64> {
+        match find_environment(conn, project_name, environment_name).optional()? {
+            Some(environment) => environment_next_sequence(conn, environment.id),
+            None => Ok(1),
+        }
+    }).await??;
+
+    Ok(sequence)
+}
+
+pub async fn environment_status(
+    project_name: String,
+    environment_name: String,
+    database_pool: Pool
+) -> Result<Vec<(Devices, DevicesEnvironments)>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let device_status = conn.interact(move |conn| -> Result<Vec<(Devices, DevicesEnvironments)>, diesel::result::Error> {
+        let environment = find_environment(conn, project_name, environment_name)?;
+
+        DevicesEnvironments::belonging_to(&environment)
+            .inner_join(devices::table)
+            .select((Devices::as_select(), DevicesEnvironments::as_select()))
+            .order(devices::name.asc())
+            .load(conn)
+    }).await??;
+
+    Ok(device_status)
+}
+
+// Encrypted here, the ciphertext is bound to the environment and the revision
+// it is stored with
+pub async fn secret_set(
+    project_name: String,
+    environment_name: String,
+    name: String,
+    value: String,
+    secrets_cipher: SecretsCipher,
+    database_pool: Pool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(move |conn| {
+        conn.transaction(|conn| -> Result<(), SecretError> {
+            let environment = find_environment(conn, project_name, environment_name)?;
+
+            let existing_secret: Option<Secrets> = Secrets::belonging_to(&environment)
+                .filter(secrets::name.eq(name.clone()))
+                .select(Secrets::as_select())
+                .get_result(conn)
+                .optional()?;
+
+            match existing_secret {
+                Some(secret) => {
+                    let revision = secret.revision + 1;
+                    let (nonce, ciphertext) = secrets_cipher.encrypt(environment.id, &name, revision, &value)?;
+
+                    diesel::update(secrets::table.find(secret.id))
+                        .set((
+                            secrets::nonce.eq(nonce),
+                            secrets::ciphertext.eq(ciphertext),
+                            secrets::revision.eq(revision),
+                            secrets::updated_at.eq(Utc::now().naive_utc()),
+                        ))
+                        .execute(conn)?;
+                },
+                None => {
+                    let (nonce, ciphertext) = secrets_cipher.encrypt(environment.id, &name, 1, &value)?;
+
+                    diesel::insert_into(secrets::table)
+                        .values((
+                            secrets::name.eq(name),
+                            secrets::nonce.eq(nonce),
+                            secrets::ciphertext.eq(ciphertext),
+                            secrets::revision.eq(1),
+                            secrets::environment_id.eq(environment.id),
+                        ))
+                        .execute(conn)?;
+                },
+            }
+
+            Ok(())
+        })
+    }).await??;
+
+    Ok(())
+}
+
+pub async fn secret_list(
+    project_name: String,
+    environment_name: String,
+    database_pool: Pool
+) -> Result<Vec<Secrets>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let secrets = conn.interact(move |conn| -> Result<Vec<Secrets>, diesel::result::Error> {
+        let environment = find_environment(conn, project_name, environment_name)?;
+
+        Secrets::belonging_to(&environment)
+            .select(Secrets::as_select())
+            .order(secrets::name.asc())
+            .load(conn)
+    }).await??;
+
+    Ok(secrets)
+}
+
+pub async fn secret_delete(
+    project_name: String,
+    environment_name: String,
+    name: String,
+    database_pool: Pool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(move |conn| -> Result<(), diesel::result::Error> {
+        let environment = find_environment(conn, project_name, environment_name)?;
+
+        let deleted_rows = diesel::delete(
+            secrets::table
+                .filter(secrets::environment_id.eq(environment.id))
+                .filter(secrets::name.eq(name))
+        ).execute(conn)?;
+
+        if deleted_rows == 0 {
+            return Err(NotFound);
+        }
+
+        Ok(())
+    }).await??;
+
+    Ok(())
+}
+
+// Resending the same state after a failed apply doesn't mark it as pending
+// again, so a failure is only reported once per state.
+pub async fn record_sent_state(
+    device_id: i32,
+    environment_id: i32,
+    state_id: i32,
+    state_hash: [u8; 16],
+    database_pool: Pool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(move |conn| {
+        let state_hash = hash_to_hex(&state_hash);
+
+        diesel::update(environments_devices::table)
+            .filter(environments_devices::device_id.eq(device_id))
+            .filter(environments_devices::environment_id.eq(environment_id))
+            .filter(
+                environments_devices::sent_state_hash.is_null()
+                    .or(environments_devices::sent_state_hash.ne(state_hash.clone()))
+            )
+            .set(environments_devices::apply_pending.eq(true))
+            .execute(conn)?;
+
+        diesel::update(environments_devices::table)
+            .filter(environments_devices::device_id.eq(device_id))
+            .filter(environments_devices::environment_id.eq(environment_id))
+            .set((
+                environments_devices::sent_state_id.eq(state_id),
+                environments_devices::sent_state_hash.eq(state_hash),
+            ))
+            .execute(conn)
+    }).await??;
+
+    Ok(())
+}
+
+pub fn load_device_labels(conn: &mut SqliteConnection, device_id: i32) -> Result<HashMap<String, String>, diesel::result::Error> {
+    let labels: Vec<(String, String)> = device_labels::table
+        .filter(device_labels::device_id.eq(device_id))
+        .select((device_labels::key, device_labels::value))
+        .load(conn)?;
+
+    Ok(labels.into_iter().collect())
+}
+
+fn latest_state_json(conn: &mut SqliteConnection, environment: &Environments) -> Result<String, diesel::result::Error> {
+    let latest_state: Option<String> = States::belonging_to(environment)
+        .select(states::json)
+        .order(states::id.desc())
+        .first(conn)
+        .optional()?;
+
+    Ok(latest_state.unwrap_or(String::from("{}")))
+}
+
+// Checks a state for an environment against the latest states of every other
+// environment the device is enrolled in, after applying the device variables.
+pub fn device_conflicts(
+    conn: &mut SqliteConnection,
+    device: &Devices,
+    environment: &Environments,
+    state_json: &str,
+) -> Result<Vec<ResourceConflict>, diesel::result::Error> {
+    let device_variables = DeviceVariables::from_device(device, load_device_labels(conn, device.id)?);
+
+    let interpolate = |state_json: &str| {
+        interpolate_state(state_json, &device_variables).unwrap_or(state_json.to_string())
+    };
+
+    let project_name: String = projects::table
+        .find(environment.project_id)
+        .select(projects::name)
+        .get_result(conn)?;
+
+    let claims = resource_claims(
+        format!("{project_name}/{}", environment.name).as_str(),
+        interpolate(state_json).as_str(),
+    );
+
+    if claims.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let other_environments: Vec<(Environments, String)> = environments_devices::table
+        .inner_join(environments::table.inner_join(projects::table))
+        .filter(environments_devices::device_id.eq(device.id))
+        .filter(environments_devices::environment_id.ne(environment.id))
+        .select((Environments::as_select(), projects::name))
+        .load(conn)?;
+
+    let mut other_claims = Vec::new();
+
+    for (other_environment, other_project_name) in other_environments {
+        let other_state = latest_state_json(conn, &other_environment)?;
+
+        other_claims.extend(resource_claims(
+            format!("{other_project_name}/{}", other_environment.name).as_str(),
+            interpolate(other_state.as_str()).as_str(),
+        ));
+    }
+
+    Ok(find_conflicts(&claims, &other_claims))
+}
+
+// Conflicts a new state for the environment would cause on any of its
+// enrolled devices, prefixed with the device name.
+pub fn environment_conflicts(
+    conn: &mut SqliteConnection,
+    environment: &Environments,
+    state_json: &str,
+) -> Result<Vec<String>, diesel::result::Error> {
+    let enrolled_devices: Vec<Devices> = DevicesEnvironments::belonging_to(environment)
+        .inner_join(devices::table)
+        .select(Devices::as_select())
+        .load(conn)?;
+
+    let mut conflicts = Vec::new();
+
+    for device in enrolled_devices {
+        conflicts.extend(device_conflicts(conn, &device, environment, state_json)?
+            .iter()
+            .map(|conflict| format!("{}: {conflict}", device.name)));
+    }
+
+    Ok(conflicts)
+}
+
+#[derive(Debug)]
+pub enum PromoteError {
+    MissingSourceState,
+    SourceChanged { current_state_id: i32 },
+    Conflicts(Vec<String>),
+    Database(diesel::result::Error),
+}
+
+impl std::fmt::Display for PromoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PromoteError::MissingSourceState => write!(f, "Source environment has no state to promote"),
+            PromoteError::SourceChanged { current_state_id } => {
+                write!(f, "Source environment changed since the preview, its latest state is now {current_state_id}")
+            },
+            PromoteError::Conflicts(conflicts) => write!(f, "Promoted state conflicts with {} resource(s) on enrolled devices", conflicts.len()),
+            PromoteError::Database(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for PromoteError {}
+
+impl From<diesel::result::Error> for PromoteError {
+    fn from(err: diesel::result::Error) -> Self {
+        PromoteError::Database(err)
+    }
+}
+
+#[derive(Debug)]
+pub struct Promotion {
+    pub project_id: i32,
+    pub source_state_id: i32,
+    pub changes: Vec<StateChange>,
+    pub promoted_state_id: Option<i32>,
+}
+
+// Copies the latest state of one environment verbatim into another one of
+// the same project. With `dry_run` only the changes are computed, and
+// `expected_source_state_id` makes sure what gets promoted is what was previewed.
+pub async fn environment_promote(
+    project_name: String,
+    from_environment: String,
+    to_environment: String,
+    expected_source_state_id: Option<i32>,
+    dry_run: bool,
+    database_pool: Pool
+) -> Result<Promotion, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let promotion = conn.interact(move |conn| -> Result<Promotion, PromoteError> {
+        conn.transaction(|conn| {
+            let source = find_environment(conn, project_name, from_environment)?;
+
+            let source_state: States = States::belonging_to(&source)
+                .select(States::as_select())
+                .order(states::id.desc())
+                .first(conn)
+                .optional()?
+                .ok_or(PromoteError::MissingSourceState)?;
+
+            if expected_source_state_id.is_some_and(|state_id| state_id != source_state.id) {
+                return Err(PromoteError::SourceChanged { current_state_id: source_state.id });
+            }
+
+            let target_result: Option<Environments> = environments::table
+                .filter(environments::name.eq(&to_environment))
+                .filter(environments::project_id.eq(source.project_id))
+                .select(Environments::as_select())
+                .get_result(conn)
+                .optional()?;
+
+            let target_state = match &target_result {
+                Some(target) => latest_state_json(conn, target)?,
+                None => String::from("{}"),
+            };
+
+            let mut promotion = Promotion {
+                project_id: source.project_id,
+                source_state_id: source_state.id,
+                changes: state_diff(target_state.as_str(), source_state.json.as_str()),
+                promoted_state_id: None,
+            };
+
+            if dry_run {
+                return Ok(promotion);
+            }
+
+            let target = match target_result {
+                Some(target) => target,
+                None => diesel::insert_into(environments::table)
+                    .values((
+                        environments::name.eq(&to_environment),
+                        environments::project_id.eq(source.project_id),
+                    ))
+                    .returning(Environments::as_returning())
+                    .get_result(conn)?,
+            };
+
+            let conflicts = environment_conflicts(conn, &target, source_state.json.as_str())?;
+
+            if !conflicts.is_empty() {
+                return Err(PromoteError::Conflicts(conflicts));
+            }
+
+            let promoted_state_id: i32 = diesel::insert_into(states::table)
+                .values((
+                    states::json.eq(&source_state.json),
+                    states::environment_id.eq(target.id),
+                    states::promoted_from_state_id.eq(source_state.id),
+                    states::sequence.eq(environment_next_sequence(conn, target.id)?),
+                ))
+                .returning(states::id)
+                .get_result(conn)?;
+
+            promotion.promoted_state_id = Some(promoted_state_id);
+
+            Ok(promotion)
+        })
+    }).await??;
+
+    Ok(promotion)
+}
+
+// Devices enrolled through a selector are tracked with `via_selector`, so they
+// can be removed once they stop matching without touching explicit enrollments.
+fn reconcile_selector_memberships(conn: &mut SqliteConnection) -> Result<(), diesel::result::Error> {
+    let environments: Vec<Environments> = environments::table
+        .select(Environments::as_select())
+        .load(conn)?;
+
+    let devices: Vec<Devices> = devices::table
+        .select(Devices::as_select())
+        .load(conn)?;
+
+    let mut labels_by_device: HashMap<i32, HashMap<String, String>> = HashMap::new();
+
+    for label in device_labels::table.select(DeviceLabels::as_select()).load(conn)? {
+        labels_by_device
+            .entry(label.device_id)
+            .or_default()
+            .insert(label.key, label.value);
+    }
+
+    let no_labels = HashMap::new();
+
+    for environment in environments {
+        let selector: Option<LabelSelector> = environment.label_selector
+            .as_ref()
+            .and_then(|selector| selector.parse().ok());
+
+        let enrollments: Vec<DevicesEnvironments> = environments_devices::table
+            .filter(environments_devices::environment_id.eq(environment.id))
+            .select(DevicesEnvironments::as_select())
+            .load(conn)?;
+
+        for device in devices.iter() {
+            let device_labels = labels_by_device.get(&device.id).unwrap_or(&no_labels);
+
+            let is_selected = selector
+                .as_ref()
+                .map(|selector| selector.matches(device_labels))
+                .unwrap_or(false);
+
+            let enrollment = enrollments
+                .iter()
+                .find(|enrollment| enrollment.device_id == device.id);
+
+            match (is_selected, enrollment) {
+                (true, None) => {
+                    // Membership follows the labels, so conflicts are only reported
+                    let latest_state = latest_state_json(conn, &environment)?;
+
+                    for conflict in device_conflicts(conn, device, &environment, latest_state.as_str())? {
+                        warn!(device = device.name, environment = environment.name, "Selected device has a conflict: {conflict}");
+                    }
+
+                    diesel::insert_into(environments_devices::table)
+                        .values((
+                            environments_devices::device_id.eq(device.id),
+                            environments_devices::environment_id.eq(environment.id),
+                            environments_devices::via_selector.eq(true),
+                        ))
+                        .execute(conn)?;
+                },
+                (false, Some(enrollment)) if enrollment.via_selector => {
+                    diesel::delete(environments_devices::table.find(enrollment.id))
+                        .execute(conn)?;
+                },
+                _ => {},
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn device_set_labels(
+    machine_id: String,
+    labels_to_set: HashMap<String, String>,
+    labels_to_remove: Vec<String>,
+    database_pool: Pool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(move |conn| {
+        conn.transaction(|conn| -> Result<(), diesel::result::Error> {
+            let device: Devices = devices::table
+                .filter(devices::machine_id.eq(machine_id))
+                .select(Devices::as_select())
+                .get_result(conn)?;
+
+            diesel::delete(
+                device_labels::table
+                    .filter(device_labels::device_id.eq(device.id))
+                    .filter(device_labels::key.eq_any(labels_to_remove))
+            ).execute(conn)?;
+
+            for (key, value) in labels_to_set {
+                let updated_rows = diesel::update(device_labels::table)
+                    .filter(device_labels::device_id.eq(device.id))
+                    .filter(device_labels::key.eq(key.clone()))
+                    .set((
+                        device_labels::value.eq(value.clone()),
+                        device_labels::updated_at.eq(Utc::now().naive_utc()),
+                    ))
+                    .execute(conn)?;
+
+                if updated_rows == 0 {
+                    diesel::insert_into(device_labels::table)
+                        .values((
+                            device_labels::key.eq(key),
+                            device_labels::value.eq(value),
+                            device_labels::device_id.eq(device.id),
+                        ))
+                        .execute(conn)?;
+                }
+            }
+
+            reconcile_selector_memberships(conn)
+        })
+    }).await??;
+
+    Ok(())
+}
+
+pub async fn environment_set_label_selector(
+    project_name: String,
+    environment_name: String,
+    label_selector: Option<String>,
+    database_pool: Pool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(move |conn| {
+        conn.transaction(|conn| -> Result<(), diesel::result::Error> {
+            let environment = find_environment(conn, project_name, environment_name)?;
+
+            diesel::update(environments::table.find(environment.id))
+                .set(environments::label_selector.eq(label_selector))
+                .execute(conn)?;
+
+            reconcile_selector_memberships(conn)
+        })
+    }).await??;
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum JoinTokenError {
+    InvalidToken,
+    Expired,
+    Exhausted,
+    Conflicts(Vec<String>),
+    Database(diesel::result::Error),
+}
+
+impl std::fmt::Display for JoinTokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinTokenError::InvalidToken => write!(f, "Invalid join token"),
+            JoinTokenError::Expired => write!(f, "Join token expired"),
+            JoinTokenError::Exhausted => write!(f, "Join token has no uses left"),
+            JoinTokenError::Conflicts(conflicts) => {
+                write!(f, "Environment conflicts with resources already on the device: {}", conflicts.join(", "))
+            },
+            JoinTokenError::Database(err) => write!(f, "Database error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for JoinTokenError {}
+
+impl From<diesel::result::Error> for JoinTokenError {
+    fn from(err: diesel::result::Error) -> Self {
+        JoinTokenError::Database(err)
+    }
+}
+
+pub async fn join_token_create(
+    project_name: String,
+    environment_name: String,
+    expires_at: NaiveDateTime,
+    max_uses: i32,
+    database_pool: Pool
+) -> Result<String, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let token = generate_token();
+    let token_hash = hash_token(token.as_str());
+
+    conn.interact(move |conn| -> Result<(), diesel::result::Error> {
+        let environment = find_environment(conn, project_name, environment_name)?;
+
+        diesel::insert_into(join_tokens::table)
+            .values((
+                join_tokens::token_hash.eq(token_hash),
+                join_tokens::max_uses.eq(max_uses),
+                join_tokens::expires_at.eq(expires_at),
+                join_tokens::environment_id.eq(environment.id),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }).await??;
+
+    Ok(token)
+}
+
+// Creates the device, enrolls it into the environment of the token and
+// returns the credential the device has to use from now on.
+pub async fn join_token_redeem(
+    join_token: String,
+    machine_id: String,
+    device_name: String,
+    database_pool: Pool
+) -> Result<String, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let device_token = generate_token();
+    let device_token_hash = hash_token(device_token.as_str());
+
+    conn.interact(move |conn| {
+        conn.transaction(|conn| -> Result<(), JoinTokenError> {
+            let token: JoinTokens = join_tokens::table
+                .filter(join_tokens::token_hash.eq(hash_token(join_token.as_str())))
+                .select(JoinTokens::as_select())
+                .get_result(conn)
+                .optional()?
+                .ok_or(JoinTokenError::InvalidToken)?;
+
+            if token.expires_at <= Utc::now().naive_utc() {
+                return Err(JoinTokenError::Expired);
+            }
+
+            if token.uses >= token.max_uses {
+                return Err(JoinTokenError::Exhausted);
+            }
+
+            diesel::update(join_tokens::table.find(token.id))
+                .set(join_tokens::uses.eq(token.uses + 1))
+                .execute(conn)?;
+
+            let device: Devices = diesel::insert_into(devices::table)
+                .values((
+                    devices::name.eq(device_name),
+                    devices::machine_id.eq(machine_id),
+                    devices::token_hash.eq(device_token_hash),
+                ))
+                .returning(Devices::as_returning())
+                .get_result(conn)?;
+
+            diesel::insert_into(environments_devices::table)
+                .values((
+                    environments_devices::device_id.eq(device.id),
+                    environments_devices::environment_id.eq(token.environment_id),
+                ))
+                .execute(conn)?;
+
+            reconcile_selector_memberships(conn)?;
+
+            // Same check as `/enroll_device`, against the environments the new
+            // device was just selected into. Refusing rolls back the whole redeem.
+            let environment: Environments = environments::table
+                .find(token.environment_id)
+                .select(Environments::as_select())
+                .get_result(conn)?;
+
+            let latest_state = latest_state_json(conn, &environment)?;
+            let conflicts = device_conflicts(conn, &device, &environment, latest_state.as_str())?;
+
+            if !conflicts.is_empty() {
+                return Err(JoinTokenError::Conflicts(conflicts.iter().map(ToString::to_string).collect()));
+            }
+
+            Ok(())
+        })
+    }).await??;
+
+    Ok(device_token)
+}
+
+pub async fn device_set_online(machine_id: String, online: bool, database_pool: Pool) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(move |conn| {
+        diesel::update(devices::table)
+            .filter(devices::machine_id.eq(machine_id))
+            .set((
+                devices::online.eq(online),
+                devices::last_seen_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+    }).await??;
+
+    Ok(())
+}
+
+pub async fn device_set_last_seen(machine_id: String, database_pool: Pool) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(move |conn| {
+        diesel::update(devices::table)
+            .filter(devices::machine_id.eq(machine_id))
+            .set(devices::last_seen_at.eq(Utc::now().naive_utc()))
+            .execute(conn)
+    }).await??;
+
+    Ok(())
+}
+
+pub async fn devices_mark_all_offline(database_pool: Pool) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(|conn| {
+        diesel::update(devices::table)
+            .set(devices::online.eq(false))
+            .execute(conn)
+    }).await??;
+
+    Ok(())
+}
+
+pub async fn device_project_ids(machine_id: String, database_pool: Pool) -> Result<Vec<i32>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let project_ids = conn.interact(move |conn| {
+        environments_devices::table
+            .inner_join(devices::table)
+            .inner_join(environments::table)
+            .filter(devices::machine_id.eq(machine_id))
+            .select(environments::project_id)
+            .distinct()
+            .load(conn)
+    }).await??;
+
+    Ok(project_ids)
+}
+
+pub async fn webhook_create(
+    project_name: String,
+    url: String,
+    events: Vec<WebhookEvent>,
+    secret: String,
+    database_pool: Pool
+) -> Result<i32, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let webhook_id = conn.interact(move |conn| -> Result<i32, diesel::result::Error> {
+        let project: Projects = projects::table
+            .filter(projects::name.eq(project_name))
+            .select(Projects::as_select())
+            .get_result(conn)?;
+
+        let events: Vec<&str> = events.iter().map(WebhookEvent::as_str).collect();
+
+        diesel::insert_into(webhooks::table)
+            .values((
+                webhooks::url.eq(url),
+                webhooks::events.eq(events.join(",")),
+                webhooks::secret.eq(secret),
+                webhooks::project_id.eq(project.id),
+            ))
+            .returning(webhooks::id)
+            .get_result(conn)
+    }).await??;
+
+    Ok(webhook_id)
+}
+
+pub async fn webhook_list(project_name: String, database_pool: Pool) -> Result<Vec<Webhooks>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let webhooks = conn.interact(move |conn| -> Result<Vec<Webhooks>, diesel::result::Error> {
+        let project: Projects = projects::table
+            .filter(projects::name.eq(project_name))
+            .select(Projects::as_select())
+            .get_result(conn)?;
+
+        Webhooks::belonging_to(&project)
+            .select(Webhooks::as_select())
+            .order(webhooks::id.asc())
+            .load(conn)
+    }).await??;
+
+    Ok(webhooks)
+}
+
+fn find_webhook(conn: &mut SqliteConnection, project_name: String, webhook_id: i32) -> Result<Webhooks, diesel::result::Error> {
+    webhooks::table
+        .inner_join(projects::table)
+        .filter(projects::name.eq(project_name))
+        .filter(webhooks::id.eq(webhook_id))
+        .select(Webhooks::as_select())
+        .get_result(conn)
+}
+
+pub async fn webhook_delete(project_name: String, webhook_id: i32, database_pool: Pool) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(move |conn| {
+        conn.transaction(|conn| -> Result<(), diesel::result::Error> {
+            let webhook = find_webhook(conn, project_name, webhook_id)?;
+
+            diesel::delete(WebhookDeliveries::belonging_to(&webhook)).execute(conn)?;
+            diesel::delete(webhooks::table.find(webhook.id)).execute(conn)?;
+
+            Ok(())
+        })
+    }).await??;
+
+    Ok(())
+}
+
+pub async fn webhook_delivery_list(
+    project_name: String,
+    webhook_id: i32,
+    limit: i64,
+    database_pool: Pool
+) -> Result<Vec<WebhookDeliveries>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let deliveries = conn.interact(move |conn| -> Result<Vec<WebhookDeliveries>, diesel::result::Error> {
+        let webhook = find_webhook(conn, project_name, webhook_id)?;
+
+        WebhookDeliveries::belonging_to(&webhook)
+            .select(WebhookDeliveries::as_select())
+            .order(webhook_deliveries::id.desc())
+            .limit(limit)
+            .load(conn)
+    }).await??;
+
+    Ok(deliveries)
+}
+
+// Creates a pending delivery for every webhook of the project subscribed to
+// the event. The payload is stored so retries send exactly the same body.
+pub async fn webhook_deliveries_create(
+    project_id: i32,
+    event: WebhookEvent,
+    data: serde_json::Value,
+    database_pool: Pool
+) -> Result<Vec<(Webhooks, WebhookDeliveries)>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let deliveries = conn.interact(move |conn| {
+        conn.transaction(|conn| -> Result<Vec<(Webhooks, WebhookDeliveries)>, diesel::result::Error> {
+            let project: Projects = projects::table
+                .find(project_id)
+                .select(Projects::as_select())
+                .get_result(conn)?;
+
+            let subscribed_webhooks: Vec<Webhooks> = Webhooks::belonging_to(&project)
+                .select(Webhooks::as_select())
+                .load(conn)?
+                .into_iter()
+                .filter(|webhook| is_subscribed(webhook, event))
+                .collect();
+
+            let payload = build_payload(event, project.name.as_str(), &data);
+            let mut deliveries = Vec::new();
+
+            for webhook in subscribed_webhooks {
+                let delivery: WebhookDeliveries = diesel::insert_into(webhook_deliveries::table)
+                    .values((
+                        webhook_deliveries::event.eq(event.as_str()),
+                        webhook_deliveries::payload.eq(payload.clone()),
+                        webhook_deliveries::webhook_id.eq(webhook.id),
+                    ))
+                    .returning(WebhookDeliveries::as_returning())
+                    .get_result(conn)?;
+
+                deliveries.push((webhook, delivery));
+            }
+
+            Ok(deliveries)
+        })
+    }).await??;
+
+    Ok(deliveries)
+}
+
+pub async fn webhook_delivery_update(
+    delivery_id: i32,
+    attempts: i32,
+    delivered: bool,
+    response_status: Option<i32>,
+    error: Option<String>,
+    database_pool: Pool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    conn.interact(move |conn| {
+        diesel::update(webhook_deliveries::table.find(delivery_id))
+            .set((
+                webhook_deliveries::attempts.eq(attempts),
+                webhook_deliveries::delivered.eq(delivered),
+                webhook_deliveries::response_status.eq(response_status),
+                webhook_deliveries::error.eq(error),
+                webhook_deliveries::updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+    }).await??;
+
+    Ok(())
+}
+
+// Deliveries left with attempts to spare when the server stopped
+pub async fn webhook_deliveries_pending(max_attempts: i32, database_pool: Pool) -> Result<Vec<(Webhooks, WebhookDeliveries)>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let deliveries = conn.interact(move |conn| {
+        webhook_deliveries::table
+            .inner_join(webhooks::table)
+            .filter(webhook_deliveries::delivered.eq(false))
+            .filter(webhook_deliveries::attempts.lt(max_attempts))
+            .select((Webhooks::as_select(), WebhookDeliveries::as_select()))
+            .order(webhook_deliveries::id.asc())
+            .load(conn)
+    }).await??;
+
+    Ok(deliveries)
+}
+
+// The latest state of the environment, followed by up to `patch_bases` older
+// ones a patch can start from, newest first. None until a state is pushed.
+pub async fn environment_latest_states(
+    environment_id: i32,
+    patch_bases: i64,
+    database_pool: Pool
+) -> Result<Option<(States, Vec<States>)>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let mut recent_states: Vec<States> = conn.interact(move |conn| {
+        states::table
+            .filter(states::environment_id.eq(environment_id))
+            .select(States::as_select())
+            .order(states::id.desc())
+            .limit(patch_bases + 1)
+            .load(conn)
+    }).await??;
+
+    if recent_states.is_empty() {
+        return Ok(None);
+    }
+
+    let latest_state = recent_states.remove(0);
+
+    Ok(Some((latest_state, recent_states)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use deadpool_diesel::sqlite::{Manager, Pool, Runtime};
+    use diesel::prelude::*;
+    use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
+    use chrono::{TimeDelta, Utc};
+
+    use crate::models::{Devices, DevicesEnvironments, JoinTokens};
+    use crate::schema::{devices, environments, environments_devices, join_tokens, projects, states};
+    use crate::tokens::hash_token;
+
+    use super::{
+        device_create, environment_latest_states, environment_sequence, join_token_create, join_token_redeem, webhook_create, webhook_deliveries_create, webhook_deliveries_pending,
+        webhook_delivery_update, JoinTokenError,
+    };
+    use crate::webhooks::WebhookEvent;
+
+    const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+    // A single in-memory connection, so every query sees the same database
+    pub(crate) async fn test_pool() -> Pool {
+        let pool = Pool::builder(Manager::new(":memory:", Runtime::Tokio1))
+            .max_size(1)
+            .build()
+            .unwrap();
+
+        let conn = pool.get().await.unwrap();
+        conn.interact(|conn| conn.run_pending_migrations(MIGRATIONS).map(|_| ())).await.unwrap().unwrap();
+
+        pool
+    }
+
+    // Creates the project if needed and returns the id of the new environment
+    pub(crate) async fn create_environment(pool: &Pool, project_name: &str, environment_name: &str, label_selector: Option<&str>) -> i32 {
+        let (project_name, environment_name) = (project_name.to_string(), environment_name.to_string());
+        let label_selector = label_selector.map(String::from);
+        let conn = pool.get().await.unwrap();
+
+        conn.interact(move |conn| -> QueryResult<i32> {
+            let project_id: i32 = match projects::table.filter(projects::name.eq(&project_name)).select(projects::id).first(conn).optional()? {
+                Some(project_id) => project_id,
+                None => diesel::insert_into(projects::table)
+                    .values(projects::name.eq(&project_name))
+                    .returning(projects::id)
+                    .get_result(conn)?,
+            };
+
+            diesel::insert_into(environments::table)
+                .values((
+                    environments::name.eq(environment_name),
+                    environments::project_id.eq(project_id),
+                    environments::label_selector.eq(label_selector),
+                ))
+                .returning(environments::id)
+                .get_result(conn)
+        }).await.unwrap().unwrap()
+    }
+
+    pub(crate) async fn enrollments(pool: &Pool, environment_id: i32) -> Vec<DevicesEnvironments> {
+        let conn = pool.get().await.unwrap();
+
+        conn.interact(move |conn| {
+            environments_devices::table
+                .filter(environments_devices::environment_id.eq(environment_id))
+                .select(DevicesEnvironments::as_select())
+                .load(conn)
+        }).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn new_device_joins_matching_selector_environments() {
+        let pool = test_pool().await;
+
+        let not_canary = create_environment(&pool, "p", "stable", Some("ring!=canary")).await;
+        let canary = create_environment(&pool, "p", "canary", Some("ring=canary")).await;
+
+        device_create(String::from("d1"), String::from("m1"), hash_token("t1"), pool.clone()).await.unwrap();
+
+        let enrollments_not_canary = enrollments(&pool, not_canary).await;
+
+        assert_eq!(enrollments_not_canary.len(), 1);
+        assert!(enrollments_not_canary[0].via_selector);
+        assert!(enrollments(&pool, canary).await.is_empty());
+    }
+
+    pub(crate) async fn devices(pool: &Pool) -> Vec<Devices> {
+        let conn = pool.get().await.unwrap();
+
+        conn.interact(|conn| devices::table.select(Devices::as_select()).load(conn)).await.unwrap().unwrap()
+    }
+
+    async fn join_token_uses(pool: &Pool) -> i32 {
+        let conn = pool.get().await.unwrap();
+
+        conn.interact(|conn| join_tokens::table.select(JoinTokens::as_select()).first(conn)).await.unwrap().unwrap().uses
+    }
+
+    #[tokio::test]
+    async fn join_token_redeem_enrolls_until_exhausted() {
+        let pool = test_pool().await;
+        let environment_id = create_environment(&pool, "p", "prod", None).await;
+        let expires_at = Utc::now().naive_utc() + TimeDelta::hours(1);
+
+        let join_token = join_token_create(String::from("p"), String::from("prod"), expires_at, 2, pool.clone()).await.unwrap();
+
+        let device_token = join_token_redeem(join_token.clone(), String::from("m1"), String::from("d1"), pool.clone()).await.unwrap();
+        join_token_redeem(join_token.clone(), String::from("m2"), String::from("d2"), pool.clone()).await.unwrap();
+
+        let result = join_token_redeem(join_token.clone(), String::from("m3"), String::from("d3"), pool.clone()).await;
+        assert!(matches!(result.unwrap_err().downcast_ref::<JoinTokenError>(), Some(JoinTokenError::Exhausted)));
+
+        let result = join_token_redeem(String::from("unknown"), String::from("m3"), String::from("d3"), pool.clone()).await;
+        assert!(matches!(result.unwrap_err().downcast_ref::<JoinTokenError>(), Some(JoinTokenError::InvalidToken)));
+
+        // Refused redeems leave no device behind and don't count as uses
+        let devices = devices(&pool).await;
+
+        assert_eq!(join_token_uses(&pool).await, 2);
+        assert_eq!(devices.iter().map(|device| device.machine_id.as_deref()).collect::<Vec<_>>(), [Some("m1"), Some("m2")]);
+        assert_eq!(devices[0].token_hash, Some(hash_token(device_token.as_str())));
+        assert_eq!(enrollments(&pool, environment_id).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn conflicting_join_token_redeem_is_rolled_back() {
+        let pool = test_pool().await;
+        let prod = create_environment(&pool, "web", "prod", None).await;
+        let metrics = create_environment(&pool, "metrics", "prod", Some("ring!=canary")).await;
+
+        let conn = pool.get().await.unwrap();
+        conn.interact(move |conn| {
+            diesel::insert_into(states::table)
+                .values(vec![
+                    (states::environment_id.eq(prod), states::json.eq(r#"{"resources":[{"urn":"ovejas.system::User::admin","parameters":{"name":"admin"}}]}"#)),
+                    (states::environment_id.eq(metrics), states::json.eq(r#"{"resources":[{"urn":"ovejas.system::User::root","parameters":{"name":"admin"}}]}"#)),
+                ])
+                .execute(conn)
+        }).await.unwrap().unwrap();
+        drop(conn);
+
+        let join_token = join_token_create(String::from("web"), String::from("prod"), Utc::now().naive_utc() + TimeDelta::hours(1), 1, pool.clone()).await.unwrap();
+
+        let result = join_token_redeem(join_token, String::from("m1"), String::from("d1"), pool.clone()).await;
+
+        assert!(matches!(result.unwrap_err().downcast_ref::<JoinTokenError>(), Some(JoinTokenError::Conflicts(conflicts)) if conflicts.len() == 1));
+        assert_eq!(join_token_uses(&pool).await, 0);
+        assert!(devices(&pool).await.is_empty());
+        assert!(enrollments(&pool, metrics).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_join_token_is_refused() {
+        let pool = test_pool().await;
+        create_environment(&pool, "p", "prod", None).await;
+        let expires_at = Utc::now().naive_utc() - TimeDelta::minutes(1);
+
+        let join_token = join_token_create(String::from("p"), String::from("prod"), expires_at, 5, pool.clone()).await.unwrap();
+
+        let result = join_token_redeem(join_token, String::from("m1"), String::from("d1"), pool.clone()).await;
+
+        assert!(matches!(result.unwrap_err().downcast_ref::<JoinTokenError>(), Some(JoinTokenError::Expired)));
+        assert_eq!(join_token_uses(&pool).await, 0);
+        assert!(devices(&pool).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pending_webhook_deliveries_are_resumed() {
+        let pool = test_pool().await;
+        create_environment(&pool, "web", "prod", None).await;
+
+        webhook_create(String::from("web"), String::from("http://127.0.0.1:1/hooks"), vec![WebhookEvent::StatePushed], String::from("shh"), pool.clone())
+            .await
+            .unwrap();
+
+        let mut delivery_ids = Vec::new();
+
+        for _ in 0..3 {
+            let deliveries = webhook_deliveries_create(1, WebhookEvent::StatePushed, serde_json::json!({}), pool.clone()).await.unwrap();
+            delivery_ids.push(deliveries[0].1.id);
+        }
+
+        // Delivered, out of attempts, and interrupted after two attempts
+        webhook_delivery_update(delivery_ids[0], 1, true, Some(200), None, pool.clone()).await.unwrap();
+        webhook_delivery_update(delivery_ids[1], 5, false, None, Some(String::from("refused")), pool.clone()).await.unwrap();
+        webhook_delivery_update(delivery_ids[2], 2, false, None, Some(String::from("refused")), pool.clone()).await.unwrap();
+
+        let pending = webhook_deliveries_pending(5, pool.clone()).await.unwrap();
+
+        assert_eq!(pending.iter().map(|(_, delivery)| (delivery.id, delivery.attempts)).collect::<Vec<_>>(), [(delivery_ids[2], 2)]);
+    }
+
+    #[tokio::test]
+    async fn environment_without_states_has_nothing_to_send() {
+        let pool = test_pool().await;
+        let environment_id = create_environment(&pool, "web", "prod", None).await;
+
+        assert!(environment_latest_states(environment_id, 1, pool.clone()).await.unwrap().is_none());
+
+        let conn = pool.get().await.unwrap();
+        conn.interact(move |conn| {
+            diesel::insert_into(states::table)
+                .values(vec![
+                    (states::environment_id.eq(environment_id), states::json.eq(r#"{"resources":[]}"#)),
+                    (states::environment_id.eq(environment_id), states::json.eq(r#"{"resources":[],"version":1}"#)),
+                    (states::environment_id.eq(environment_id), states::json.eq(r#"{"resources":[],"version":2}"#)),
+                ])
+                .execute(conn)
+        }).await.unwrap().unwrap();
+        drop(conn);
+
+        let (latest_state, patch_bases) = environment_latest_states(environment_id, 1, pool.clone()).await.unwrap().unwrap();
+
+        assert_eq!(latest_state.id, 3);
+        assert_eq!(patch_bases.iter().map(|state| state.id).collect::<Vec<_>>(), [2]);
+    }
+
+    #[tokio::test]
+    async fn states_are_numbered_per_environment() {
+        let pool = test_pool().await;
+        let prod_id = create_environment(&pool, "web", "prod", None).await;
+        let staging_id = create_environment(&pool, "web", "staging", None).await;
+
+        assert_eq!(environment_sequence("web".into(), "prod".into(), pool.clone()).await.unwrap(), 1);
+        assert_eq!(environment_sequence("web".into(), "qa".into(), pool.clone()).await.unwrap(), 1);
+
+        let conn = pool.get().await.unwrap();
+        conn.interact(move |conn| {
+            diesel::insert_into(states::table)
+                .values(vec![
+                    (states::environment_id.eq(prod_id), states::json.eq(r#"{"resources":[]}"#), states::sequence.eq(1)),
+                    (states::environment_id.eq(prod_id), states::json.eq(r#"{"resources":[],"version":1}"#), states::sequence.eq(2)),
+                    (states::environment_id.eq(staging_id), states::json.eq(r#"{"resources":[]}"#), states::sequence.eq(1)),
+                ])
+                .execute(conn)
+        }).await.unwrap().unwrap();
+        drop(conn);
+
+        assert_eq!(environment_sequence("web".into(), "prod".into(), pool.clone()).await.unwrap(), 3);
+        assert_eq!(environment_sequence("web".into(), "staging".into(), pool.clone()).await.unwrap(), 2);
+    }
+}
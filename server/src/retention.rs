@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use deadpool_diesel::sqlite::Pool;
+use diesel::prelude::*;
+use tokio::time::Duration;
+use tracing::{error, info};
+
+use crate::models::{DevicesEnvironments, Environments, States};
+use crate::schema::{environments, environments_devices, states};
+use crate::state::{hash_state, hash_to_hex};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetentionPolicy {
+    pub keep_last: Option<i32>,
+    pub max_age_days: Option<i32>,
+}
+
+impl RetentionPolicy {
+    pub fn from_environment(environment: &Environments) -> Self {
+        RetentionPolicy {
+            keep_last: environment.retention_keep_last,
+            max_age_days: environment.retention_max_age_days,
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.keep_last.is_some() || self.max_age_days.is_some()
+    }
+}
+
+#[derive(Debug)]
+pub struct PruneReport {
+    pub project_id: i32,
+    pub environment: String,
+    pub pruned_state_ids: Vec<i32>,
+}
+
+#[derive(Debug, Default)]
+pub struct ReportedStates {
+    pub hashes: HashSet<String>,
+    pub state_ids: HashSet<i32>,
+}
+
+// A state is kept if any rule of the policy keeps it. The latest state is the
+// desired state of the environment, so it is never pruned, and neither is any
+// state a device still reports as applied. States sent with per-device values
+// don't hash like the stored document, so those are matched by id.
+pub fn states_to_prune(
+    states: &[States],
+    policy: &RetentionPolicy,
+    reported_states: &ReportedStates,
+    now: NaiveDateTime,
+) -> Vec<i32> {
+    if !policy.is_set() {
+        return vec![];
+    }
+
+    let mut states: Vec<&States> = states.iter().collect();
+    states.sort_by_key(|state| std::cmp::Reverse(state.id));
+
+    let oldest_allowed = policy.max_age_days
+        .map(|days| now - TimeDelta::days(days.into()));
+
+    states
+        .iter()
+        .enumerate()
+        .filter(|(position, state)| {
+            if *position == 0 {
+                return false;
+            }
+
+            if let Some(keep_last) = policy.keep_last {
+                if (*position as i64) < keep_last.into() {
+                    return false;
+                }
+            }
+
+            if let Some(oldest_allowed) = oldest_allowed {
+                if state.created_at >= oldest_allowed {
+                    return false;
+                }
+            }
+
+            !reported_states.state_ids.contains(&state.id)
+                && !reported_states.hashes.contains(&hash_to_hex(&hash_state(&state.json)))
+        })
+        .map(|(_, state)| state.id)
+        .collect()
+}
+
+pub async fn prune_states(dry_run: bool, database_pool: Pool) -> Result<Vec<PruneReport>, Box<dyn std::error::Error>> {
+    let conn = database_pool.get().await.expect("Could not get database connection");
+
+    let reports = conn.interact(move |conn| {
+        conn.transaction(|conn| -> Result<Vec<PruneReport>, diesel::result::Error> {
+            let environments: Vec<Environments> = environments::table
+                .select(Environments::as_select())
+                .load(conn)?;
+
+            let now = Utc::now().naive_utc();
+            let mut reports = Vec::new();
+
+            for environment in environments {
+                let policy = RetentionPolicy::from_environment(&environment);
+
+                if !policy.is_set() {
+                    continue;
+                }
+
+                let states: Vec<States> = States::belonging_to(&environment)
+                    .select(States::as_select())
+                    .load(conn)?;
+
+                let enrollments: Vec<DevicesEnvironments> = environments_devices::table
+                    .filter(environments_devices::environment_id.eq(environment.id))
+                    .select(DevicesEnvironments::as_select())
+                    .load(conn)?;
+
+                let mut reported_states = ReportedStates::default();
+
+                for enrollment in enrollments {
+                    let Some(reported_state_hash) = enrollment.reported_state_hash else {
+                        continue;
+                    };
+
+                    if let Some(sent_state_id) = enrollment.sent_state_id {
+                        if enrollment.sent_state_hash.as_ref() == Some(&reported_state_hash) {
+                            reported_states.state_ids.insert(sent_state_id);
+                        }
+                    }
+
+                    reported_states.hashes.insert(reported_state_hash);
+                }
+
+                let pruned_state_ids = states_to_prune(&states, &policy, &reported_states, now);
+
+                if pruned_state_ids.is_empty() {
+                    continue;
+                }
+
+                if !dry_run {
+                    diesel::delete(states::table.filter(states::id.eq_any(pruned_state_ids.clone())))
+                        .execute(conn)?;
+                }
+
+                reports.push(PruneReport {
+                    project_id: environment.project_id,
+                    environment: environment.name,
+                    pruned_state_ids,
+                });
+            }
+
+            Ok(reports)
+        })
+    }).await??;
+
+    Ok(reports)
+}
+
+pub async fn run_retention_task(interval: Duration, database_pool: Pool) {
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        match prune_states(false, database_pool.clone()).await {
+            Ok(reports) => {
+                for report in reports {
+                    info!(
+                        operation = "prune",
+                        project_id = report.project_id,
+                        environment = report.environment,
+                        pruned_states = report.pruned_state_ids.len(),
+                    );
+                }
+            },
+            Err(err) => error!("Failed to prune states: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use chrono::{NaiveDateTime, TimeDelta};
+
+    use crate::models::States;
+    use crate::state::{hash_state, hash_to_hex};
+
+    use super::{states_to_prune, ReportedStates, RetentionPolicy};
+
+    fn state(id: i32, json: &str, created_at: NaiveDateTime) -> States {
+        States {
+            id,
+            json: json.to_string(),
+            created_at,
+            environment_id: 1,
+            promoted_from_state_id: None,
+            override_window: false,
+            signature: None,
+            signed_by: None,
+            sequence: i64::from(id),
+        }
+    }
+
+    #[test]
+    fn prune_keeps_last_and_reported_states() {
+        let now = NaiveDateTime::parse_from_str("2025-03-10 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+
+        let states = vec![
+            state(1, r#"{"version":1}"#, now - TimeDelta::days(30)),
+            state(2, r#"{"version":2}"#, now - TimeDelta::days(20)),
+            state(3, r#"{"version":3}"#, now - TimeDelta::days(10)),
+            state(4, r#"{"version":4}"#, now - TimeDelta::days(5)),
+            state(5, r#"{"version":5}"#, now - TimeDelta::days(1)),
+        ];
+
+        let reported_states = ReportedStates {
+            hashes: HashSet::from([hash_to_hex(&hash_state(r#"{"version":1}"#))]),
+            state_ids: HashSet::from([3]),
+        };
+
+        let keep_last = RetentionPolicy { keep_last: Some(2), max_age_days: None };
+        assert_eq!(states_to_prune(&states, &keep_last, &reported_states, now), vec![2]);
+
+        let max_age = RetentionPolicy { keep_last: None, max_age_days: Some(7) };
+        assert_eq!(states_to_prune(&states, &max_age, &reported_states, now), vec![2]);
+
+        let both = RetentionPolicy { keep_last: Some(1), max_age_days: Some(15) };
+        assert_eq!(states_to_prune(&states, &both, &reported_states, now), vec![2]);
+
+        // State 3 is only kept because a device reported its id
+        let reported_hashes = ReportedStates { hashes: reported_states.hashes.clone(), state_ids: HashSet::new() };
+        assert_eq!(states_to_prune(&states, &keep_last, &reported_hashes, now), vec![3, 2]);
+
+        let unset = RetentionPolicy { keep_last: None, max_age_days: None };
+        assert!(states_to_prune(&states, &unset, &reported_states, now).is_empty());
+    }
+}
@@ -1,11 +1,25 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    device_labels (id) {
+        id -> Integer,
+        key -> Text,
+        value -> Text,
+        created_at -> Timestamp,
+        updated_at -> Nullable<Timestamp>,
+        device_id -> Integer,
+    }
+}
+
 diesel::table! {
     devices (id) {
         id -> Integer,
         name -> Text,
         created_at -> Timestamp,
         machine_id -> Nullable<Text>,
+        token_hash -> Nullable<Text>,
+        online -> Bool,
+        last_seen_at -> Nullable<Timestamp>,
     }
 }
 
@@ -17,6 +31,12 @@ diesel::table! {
         updated_at -> Nullable<Timestamp>,
         deleted_at -> Nullable<Timestamp>,
         project_id -> Integer,
+        retention_keep_last -> Nullable<Integer>,
+        retention_max_age_days -> Nullable<Integer>,
+        label_selector -> Nullable<Text>,
+        maintenance_windows -> Nullable<Text>,
+        maintenance_timezone -> Nullable<Text>,
+        drift_mode -> Text,
     }
 }
 
@@ -28,6 +48,27 @@ diesel::table! {
         deleted_at -> Nullable<Timestamp>,
         environment_id -> Integer,
         device_id -> Integer,
+        reported_state_hash -> Nullable<Text>,
+        sent_state_id -> Nullable<Integer>,
+        sent_state_hash -> Nullable<Text>,
+        via_selector -> Bool,
+        apply_pending -> Bool,
+        drift -> Nullable<Text>,
+        drift_checked_at -> Nullable<Timestamp>,
+        status -> Nullable<Text>,
+        status_updated_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    join_tokens (id) {
+        id -> Integer,
+        token_hash -> Text,
+        max_uses -> Integer,
+        uses -> Integer,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+        environment_id -> Integer,
     }
 }
 
@@ -41,12 +82,30 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    secrets (id) {
+        id -> Integer,
+        name -> Text,
+        nonce -> Binary,
+        ciphertext -> Binary,
+        revision -> Integer,
+        created_at -> Timestamp,
+        updated_at -> Nullable<Timestamp>,
+        environment_id -> Integer,
+    }
+}
+
 diesel::table! {
     states (id) {
         id -> Integer,
         json -> Text,
         created_at -> Timestamp,
         environment_id -> Integer,
+        promoted_from_state_id -> Nullable<Integer>,
+        override_window -> Bool,
+        signature -> Nullable<Text>,
+        signed_by -> Nullable<Text>,
+        sequence -> BigInt,
     }
 }
 
@@ -72,19 +131,55 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Integer,
+        event -> Text,
+        payload -> Text,
+        attempts -> Integer,
+        delivered -> Bool,
+        response_status -> Nullable<Integer>,
+        error -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Nullable<Timestamp>,
+        webhook_id -> Integer,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Integer,
+        url -> Text,
+        events -> Text,
+        secret -> Text,
+        created_at -> Timestamp,
+        project_id -> Integer,
+    }
+}
+
+diesel::joinable!(device_labels -> devices (device_id));
 diesel::joinable!(environments -> projects (project_id));
 diesel::joinable!(environments_devices -> devices (device_id));
 diesel::joinable!(environments_devices -> environments (environment_id));
+diesel::joinable!(join_tokens -> environments (environment_id));
+diesel::joinable!(secrets -> environments (environment_id));
 diesel::joinable!(states -> environments (environment_id));
 diesel::joinable!(users_projects -> projects (project_id));
 diesel::joinable!(users_projects -> users (user_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
+diesel::joinable!(webhooks -> projects (project_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    device_labels,
     devices,
     environments,
     environments_devices,
+    join_tokens,
     projects,
+    secrets,
     states,
     users,
     users_projects,
+    webhook_deliveries,
+    webhooks,
 );
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde_json::Value;
+use shared::secrets::{visit_secret_references, SECRET_REVISION};
+
+use crate::models::Secrets;
+
+#[derive(Debug)]
+pub enum SecretError {
+    KeyNotConfigured,
+    InvalidKey,
+    EncryptionFailed(String),
+    DecryptionFailed(String),
+    NotFound(String),
+    InvalidState(String),
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretError::KeyNotConfigured => write!(f, "Secrets key is not configured on the server"),
+            SecretError::InvalidKey => write!(f, "Secrets key must be 32 bytes encoded in base64"),
+            SecretError::EncryptionFailed(name) => write!(f, "Could not encrypt secret '{name}'"),
+            SecretError::DecryptionFailed(name) => write!(f, "Could not decrypt secret '{name}'"),
+            SecretError::NotFound(name) => write!(f, "Secret '{name}' not found"),
+            SecretError::InvalidState(err) => write!(f, "Invalid state: {err}"),
+            SecretError::Database(err) => write!(f, "Database error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+impl From<diesel::result::Error> for SecretError {
+    fn from(err: diesel::result::Error) -> Self {
+        SecretError::Database(err)
+    }
+}
+
+fn associated_data(environment_id: i32, name: &str, revision: i32) -> Vec<u8> {
+    format!("{environment_id}\0{name}\0{revision}").into_bytes()
+}
+
+#[derive(Clone)]
+pub struct SecretsCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SecretsCipher {
+    pub fn from_base64_key(key: &str) -> Result<Self, SecretError> {
+        let key = BASE64_STANDARD.decode(key.trim())
+            .map_err(|_| SecretError::InvalidKey)?;
+
+        if key.len() != 32 {
+            return Err(SecretError::InvalidKey);
+        }
+
+        Ok(SecretsCipher {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key.as_slice())),
+        })
+    }
+
+    // The environment, name and revision of the secret are used as associated
+    // data, so a ciphertext cannot be moved to another environment or name,
+    // or an older one put back, without failing to decrypt.
+    pub fn encrypt(&self, environment_id: i32, name: &str, revision: i32, value: &str) -> Result<(Vec<u8>, Vec<u8>), SecretError> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let ciphertext = self.cipher
+            .encrypt(&nonce, Payload { msg: value.as_bytes(), aad: &associated_data(environment_id, name, revision) })
+            .map_err(|_| SecretError::EncryptionFailed(name.to_string()))?;
+
+        Ok((nonce.to_vec(), ciphertext))
+    }
+
+    pub fn decrypt(&self, secret: &Secrets) -> Result<String, SecretError> {
+        let decryption_failed = || SecretError::DecryptionFailed(secret.name.clone());
+
+        if secret.nonce.len() != 12 {
+            return Err(decryption_failed());
+        }
+
+        let plaintext = self.cipher
+            .decrypt(
+                Nonce::from_slice(secret.nonce.as_slice()),
+                Payload {
+                    msg: secret.ciphertext.as_slice(),
+                    aad: &associated_data(secret.environment_id, &secret.name, secret.revision),
+                },
+            )
+            .map_err(|_| decryption_failed())?;
+
+        String::from_utf8(plaintext).map_err(|_| decryption_failed())
+    }
+}
+
+// Secret references are annotated with the revision of the secret instead of
+// its value, so rotating a secret changes the hash of the state sent to the
+// device while the plaintext travels apart from the document.
+pub fn resolve_secret_references(
+    state_json: &str,
+    secrets: &[Secrets],
+    cipher: Option<&SecretsCipher>,
+) -> Result<(String, HashMap<String, String>), SecretError> {
+    let mut state: Value = serde_json::from_str(state_json)
+        .map_err(|err| SecretError::InvalidState(err.to_string()))?;
+
+    let mut resolved_secrets = HashMap::new();
+
+    visit_secret_references(&mut state, &mut |name, reference| -> Result<(), SecretError> {
+        let cipher = cipher.ok_or(SecretError::KeyNotConfigured)?;
+
+        let secret = secrets
+            .iter()
+            .find(|secret| secret.name == name)
+            .ok_or(SecretError::NotFound(name.to_string()))?;
+
+        reference[SECRET_REVISION] = secret.revision.into();
+        resolved_secrets.insert(name.to_string(), cipher.decrypt(secret)?);
+
+        Ok(())
+    })?;
+
+    if resolved_secrets.is_empty() {
+        return Ok((state_json.to_string(), resolved_secrets));
+    }
+
+    Ok((state.to_string(), resolved_secrets))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use crate::models::Secrets;
+
+    use super::{resolve_secret_references, SecretsCipher};
+
+    #[test]
+    fn resolve_annotates_revision_and_decrypts() {
+        let cipher = SecretsCipher::from_base64_key("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=").unwrap();
+        let (nonce, ciphertext) = cipher.encrypt(1, "api_key", 3, "hunter2").unwrap();
+
+        let secret = |environment_id, revision| Secrets {
+            id: 1,
+            name: String::from("api_key"),
+            nonce: nonce.clone(),
+            ciphertext: ciphertext.clone(),
+            revision,
+            created_at: NaiveDateTime::default(),
+            updated_at: None,
+            environment_id,
+        };
+
+        // Copied to another environment, or put back after a rotation
+        assert!(cipher.decrypt(&secret(2, 3)).is_err());
+        assert!(cipher.decrypt(&secret(1, 4)).is_err());
+
+        let secrets = vec![secret(1, 3)];
+
+        let state = r#"{"resources":[{"urn":"ovejas.docker::Image::app","parameters":{"environment":{"API_KEY":{"$secret":"api_key"}}}}]}"#;
+
+        let (resolved_state, resolved_secrets) = resolve_secret_references(state, &secrets, Some(&cipher)).unwrap();
+
+        assert!(!resolved_state.contains("hunter2"));
+        assert!(resolved_state.contains(r#"{"$secret":"api_key","revision":3}"#));
+        assert_eq!(resolved_secrets.get("api_key").map(String::as_str), Some("hunter2"));
+
+        let plain_state = r#"{"resources": []}"#;
+        let (unchanged_state, _) = resolve_secret_references(plain_state, &secrets, None).unwrap();
+
+        assert_eq!(unchanged_state, plain_state);
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateSessionPolicy {
+    Reject,
+    Supersede,
+}
+
+impl FromStr for DuplicateSessionPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "reject" => Ok(DuplicateSessionPolicy::Reject),
+            "supersede" => Ok(DuplicateSessionPolicy::Supersede),
+            _ => Err(format!("Invalid duplicate session policy '{policy}', expected 'reject' or 'supersede'")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatSettings {
+    pub interval: Duration,
+    pub idle_timeout: Duration,
+}
+
+impl HeartbeatSettings {
+    // Any frame counts as a sign of life, pongs to our pings included
+    pub fn idle_deadline(&self, last_seen: Instant) -> Instant {
+        last_seen + self.idle_timeout
+    }
+
+    pub fn is_idle(&self, last_seen: Instant, now: Instant) -> bool {
+        now >= self.idle_deadline(last_seen)
+    }
+}
+
+#[derive(Debug)]
+pub struct DuplicateSession {
+    pub existing_addr: SocketAddr,
+}
+
+impl fmt::Display for DuplicateSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "A session for this machine-id is already open from {}", self.existing_addr)
+    }
+}
+
+impl std::error::Error for DuplicateSession {}
+
+struct LiveSession {
+    id: u64,
+    remote_addr: SocketAddr,
+    superseded: Arc<Notify>,
+}
+
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<HashMap<String, LiveSession>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+// Removes the session from the registry when dropped, unless a newer session
+// for the same machine already took its place.
+pub struct SessionHandle {
+    registry: SessionRegistry,
+    machine_id: String,
+    id: u64,
+    pub superseded: Arc<Notify>,
+    pub superseded_addr: Option<SocketAddr>,
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        let mut sessions = self.registry.sessions.lock().expect("Session registry poisoned");
+
+        if sessions.get(&self.machine_id).is_some_and(|session| session.id == self.id) {
+            sessions.remove(&self.machine_id);
+        }
+    }
+}
+
+impl SessionRegistry {
+    pub fn register(
+        &self,
+        machine_id: &str,
+        remote_addr: SocketAddr,
+        policy: DuplicateSessionPolicy,
+    ) -> Result<SessionHandle, DuplicateSession> {
+        let mut sessions = self.sessions.lock().expect("Session registry poisoned");
+
+        let superseded_addr = match (sessions.get(machine_id), policy) {
+            (Some(existing), DuplicateSessionPolicy::Reject) => {
+                return Err(DuplicateSession { existing_addr: existing.remote_addr });
+            },
+            (Some(existing), DuplicateSessionPolicy::Supersede) => {
+                existing.superseded.notify_one();
+
+                Some(existing.remote_addr)
+            },
+            (None, _) => None,
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let superseded = Arc::new(Notify::new());
+
+        sessions.insert(machine_id.to_string(), LiveSession {
+            id,
+            remote_addr,
+            superseded: superseded.clone(),
+        });
+
+        Ok(SessionHandle {
+            registry: self.clone(),
+            machine_id: machine_id.to_string(),
+            id,
+            superseded,
+            superseded_addr,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::time::{Duration, Instant};
+
+    use super::{DuplicateSessionPolicy, HeartbeatSettings, SessionRegistry};
+
+    #[test]
+    fn session_is_idle_after_timeout_without_frames() {
+        let heartbeat = HeartbeatSettings { interval: Duration::from_secs(15), idle_timeout: Duration::from_secs(45) };
+        let last_seen = Instant::now();
+
+        // A device answering every ping is seen at least once per interval
+        assert!(!heartbeat.is_idle(last_seen, last_seen + heartbeat.interval));
+        assert!(!heartbeat.is_idle(last_seen, last_seen + Duration::from_secs(44)));
+        assert!(heartbeat.is_idle(last_seen, last_seen + Duration::from_secs(45)));
+
+        let pong_seen = last_seen + Duration::from_secs(30);
+        assert!(!heartbeat.is_idle(pong_seen, last_seen + Duration::from_secs(60)));
+        assert_eq!(heartbeat.idle_deadline(pong_seen), last_seen + Duration::from_secs(75));
+    }
+
+    #[test]
+    fn duplicate_sessions_follow_policy() {
+        let registry = SessionRegistry::default();
+        let first_addr: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let second_addr: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+
+        let first = registry.register("m1", first_addr, DuplicateSessionPolicy::Reject).unwrap();
+
+        let rejected = registry.register("m1", second_addr, DuplicateSessionPolicy::Reject);
+        assert_eq!(rejected.err().map(|duplicate| duplicate.existing_addr), Some(first_addr));
+
+        let second = registry.register("m1", second_addr, DuplicateSessionPolicy::Supersede).unwrap();
+        assert_eq!(second.superseded_addr, Some(first_addr));
+
+        // The superseded session closing doesn't unregister its replacement
+        drop(first);
+        assert!(registry.register("m1", first_addr, DuplicateSessionPolicy::Reject).is_err());
+
+        drop(second);
+        assert!(registry.register("m1", first_addr, DuplicateSessionPolicy::Reject).is_ok());
+    }
+}
@@ -1,5 +1,7 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
+use md5::{Md5, Digest};
 use serde_json::Value;
+use shared::desired_state::DesiredState;
 
 pub struct StateDelta {
     pub not_in_remote: Vec<String>,
@@ -30,3 +32,45 @@ impl StateDelta {
         }
     }
 }
+
+pub fn hash_state(json: &str) -> [u8; 16] {
+    let mut hasher = Md5::new();
+    hasher.update(json);
+
+    hasher.finalize().into()
+}
+
+pub fn hash_to_hex(hash: &[u8; 16]) -> String {
+    hash.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// Resource kinds in the state, e.g. `User` for `ovejas.system::User::admin`,
+// that the device has no provider for.
+pub fn unsupported_resource_kinds(json: &str, provider_kinds: &[String]) -> BTreeSet<String> {
+    let state = DesiredState::from_json(json).unwrap_or_default();
+
+    state.resources
+        .iter()
+        .map(|resource| resource.urn.kind())
+        .filter(|kind| !provider_kinds.iter().any(|provider_kind| provider_kind == kind))
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unsupported_resource_kinds;
+
+    #[test]
+    fn kinds_without_provider() {
+        let state = r#"{"resources":[
+            {"urn":"ovejas.system::User::admin","parameters":{"name":"admin"}},
+            {"urn":"ovejas.system::File::motd","parameters":{"path":"/etc/motd"}}
+        ]}"#;
+
+        let provider_kinds = vec![String::from("User")];
+
+        assert_eq!(unsupported_resource_kinds(state, &provider_kinds).into_iter().collect::<Vec<_>>(), vec!["File"]);
+        assert!(unsupported_resource_kinds("{}", &provider_kinds).is_empty());
+    }
+}
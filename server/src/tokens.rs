@@ -0,0 +1,17 @@
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+pub fn generate_token() -> String {
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+
+    token.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// Only the hash of a token is stored, the token itself is shown once.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
@@ -0,0 +1,320 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::Utc;
+use deadpool_diesel::sqlite::Pool;
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use serde_json::Value;
+use sha2::Sha256;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{sleep, Duration};
+use tracing::{error, warn};
+
+use crate::models::{WebhookDeliveries, Webhooks};
+use crate::repository::{webhook_deliveries_create, webhook_deliveries_pending, webhook_delivery_update};
+
+pub const MAX_DELIVERY_ATTEMPTS: i32 = 5;
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookEvent {
+    StatePushed,
+    ApplySucceeded,
+    ApplyFailed,
+    DeviceOffline,
+    DuplicateSession,
+    DriftDetected,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::StatePushed => "state.pushed",
+            WebhookEvent::ApplySucceeded => "device.apply_succeeded",
+            WebhookEvent::ApplyFailed => "device.apply_failed",
+            WebhookEvent::DeviceOffline => "device.offline",
+            WebhookEvent::DuplicateSession => "device.duplicate_session",
+            WebhookEvent::DriftDetected => "device.drift_detected",
+        }
+    }
+}
+
+impl fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UnknownWebhookEvent(pub String);
+
+impl fmt::Display for UnknownWebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown webhook event '{}', expected one of state.pushed, device.apply_succeeded, device.apply_failed, device.offline, device.duplicate_session, device.drift_detected",
+            self.0,
+        )
+    }
+}
+
+impl std::error::Error for UnknownWebhookEvent {}
+
+impl FromStr for WebhookEvent {
+    type Err = UnknownWebhookEvent;
+
+    fn from_str(event: &str) -> Result<Self, Self::Err> {
+        match event.trim() {
+            "state.pushed" => Ok(WebhookEvent::StatePushed),
+            "device.apply_succeeded" => Ok(WebhookEvent::ApplySucceeded),
+            "device.apply_failed" => Ok(WebhookEvent::ApplyFailed),
+            "device.offline" => Ok(WebhookEvent::DeviceOffline),
+            "device.duplicate_session" => Ok(WebhookEvent::DuplicateSession),
+            "device.drift_detected" => Ok(WebhookEvent::DriftDetected),
+            _ => Err(UnknownWebhookEvent(event.to_string())),
+        }
+    }
+}
+
+// Subscriptions store their events as a comma separated list.
+pub fn is_subscribed(webhook: &Webhooks, event: WebhookEvent) -> bool {
+    webhook.events
+        .split(',')
+        .any(|subscribed_event| subscribed_event.trim() == event.as_str())
+}
+
+pub fn build_payload(event: WebhookEvent, project_name: &str, data: &Value) -> String {
+    serde_json::json!({
+        "event": event.as_str(),
+        "project": project_name,
+        "timestamp": Utc::now().naive_utc().to_string(),
+        "data": data,
+    }).to_string()
+}
+
+// Receivers verify a delivery by computing the HMAC-SHA256 of the raw body
+// with the shared secret and comparing it with the `x-ovejas-signature` header.
+pub fn sign_payload(secret: &str, payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+
+    mac.update(payload);
+
+    let signature: String = mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    format!("sha256={signature}")
+}
+
+#[derive(Debug)]
+pub struct DeliveryOutcome {
+    pub response_status: Option<u16>,
+    pub error: Option<String>,
+}
+
+pub async fn send_delivery(client: &Client, webhook: &Webhooks, delivery: &WebhookDeliveries) -> DeliveryOutcome {
+    let response = client
+        .post(webhook.url.as_str())
+        .header("content-type", "application/json")
+        .header("x-ovejas-event", delivery.event.as_str())
+        .header("x-ovejas-delivery", delivery.id.to_string())
+        .header("x-ovejas-signature", sign_payload(&webhook.secret, delivery.payload.as_bytes()))
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => DeliveryOutcome {
+            response_status: Some(response.status().as_u16()),
+            error: None,
+        },
+        Ok(response) => DeliveryOutcome {
+            response_status: Some(response.status().as_u16()),
+            error: Some(format!("Receiver answered with {}", response.status())),
+        },
+        Err(err) => DeliveryOutcome {
+            response_status: None,
+            error: Some(err.to_string()),
+        },
+    }
+}
+
+// Starts after the attempts already recorded, e.g. before a restart
+async fn deliver_with_retries(client: Client, webhook: Webhooks, delivery: WebhookDeliveries, database_pool: Pool) {
+    for attempt in (delivery.attempts + 1)..=MAX_DELIVERY_ATTEMPTS {
+        let outcome = send_delivery(&client, &webhook, &delivery).await;
+        let delivered = outcome.error.is_none();
+
+        let recorded = webhook_delivery_update(
+            delivery.id,
+            attempt,
+            delivered,
+            outcome.response_status.map(i32::from),
+            outcome.error.clone(),
+            database_pool.clone(),
+        ).await.map_err(|err| err.to_string());
+
+        if let Err(err) = recorded {
+            error!(delivery_id = delivery.id, "Could not record webhook delivery: {err}");
+        }
+
+        if delivered {
+            return;
+        }
+
+        warn!(
+            webhook_id = webhook.id,
+            delivery_id = delivery.id,
+            attempt,
+            "Webhook delivery failed: {}",
+            outcome.error.unwrap_or_default(),
+        );
+
+        if attempt < MAX_DELIVERY_ATTEMPTS {
+            sleep(RETRY_BASE_DELAY * 2u32.pow((attempt - 1) as u32)).await;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WebhookNotification {
+    pub event: WebhookEvent,
+    pub project_id: i32,
+    pub data: Value,
+}
+
+// Sessions only queue notifications, deliveries and their retries run in the
+// dispatcher so a slow receiver never holds up a device.
+#[derive(Clone)]
+pub struct WebhookNotifier {
+    sender: UnboundedSender<WebhookNotification>,
+}
+
+impl WebhookNotifier {
+    pub fn channel() -> (Self, UnboundedReceiver<WebhookNotification>) {
+        let (sender, receiver) = unbounded_channel();
+
+        (WebhookNotifier { sender }, receiver)
+    }
+
+    pub fn notify(&self, event: WebhookEvent, project_id: i32, data: Value) {
+        if self.sender.send(WebhookNotification { event, project_id, data }).is_err() {
+            error!(event = event.as_str(), "Webhook dispatcher is not running, dropping notification");
+        }
+    }
+}
+
+pub async fn run_webhook_dispatcher(mut receiver: UnboundedReceiver<WebhookNotification>, database_pool: Pool) {
+    let client = Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .build()
+        .expect("Could not build webhook client");
+
+    // Retries only live in the tasks, the ones cut short by a restart start
+    // again from the stored deliveries
+    match webhook_deliveries_pending(MAX_DELIVERY_ATTEMPTS, database_pool.clone()).await {
+        Ok(deliveries) => {
+            for (webhook, delivery) in deliveries {
+                tokio::spawn(deliver_with_retries(client.clone(), webhook, delivery, database_pool.clone()));
+            }
+        },
+        Err(err) => error!("Could not load pending webhook deliveries: {err}"),
+    }
+
+    while let Some(notification) = receiver.recv().await {
+        let deliveries = webhook_deliveries_create(
+            notification.project_id,
+            notification.event,
+            notification.data,
+            database_pool.clone(),
+        ).await;
+
+        let deliveries = match deliveries {
+            Ok(deliveries) => deliveries,
+            Err(err) => {
+                error!(event = notification.event.as_str(), "Could not queue webhook deliveries: {err}");
+                continue;
+            }
+        };
+
+        for (webhook, delivery) in deliveries {
+            tokio::spawn(deliver_with_retries(client.clone(), webhook, delivery, database_pool.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+    use reqwest::Client;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::models::{WebhookDeliveries, Webhooks};
+
+    use super::{is_subscribed, send_delivery, sign_payload, WebhookEvent};
+
+    #[tokio::test]
+    async fn delivery_is_signed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // Stand-in receiver that answers once and hands back the raw request
+        let receiver = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+
+            while !String::from_utf8_lossy(&request).ends_with("}") {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+
+            stream.write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+
+            String::from_utf8(request).unwrap()
+        });
+
+        let webhook = Webhooks {
+            id: 1,
+            url: format!("http://{address}/hooks"),
+            events: String::from("state.pushed, device.offline"),
+            secret: String::from("shh"),
+            created_at: NaiveDateTime::default(),
+            project_id: 1,
+        };
+
+        let delivery = WebhookDeliveries {
+            id: 7,
+            event: String::from("state.pushed"),
+            payload: String::from(r#"{"event":"state.pushed"}"#),
+            attempts: 0,
+            delivered: false,
+            response_status: None,
+            error: None,
+            created_at: NaiveDateTime::default(),
+            updated_at: None,
+            webhook_id: 1,
+        };
+
+        assert!(is_subscribed(&webhook, WebhookEvent::DeviceOffline));
+        assert!(!is_subscribed(&webhook, WebhookEvent::ApplyFailed));
+
+        let outcome = send_delivery(&Client::new(), &webhook, &delivery).await;
+        let request = receiver.await.unwrap().to_lowercase();
+
+        assert_eq!(outcome.response_status, Some(204));
+        assert!(outcome.error.is_none());
+        assert!(request.starts_with("post /hooks"));
+        assert!(request.contains("x-ovejas-delivery: 7"));
+        assert!(request.contains(&format!("x-ovejas-signature: {}", sign_payload("shh", delivery.payload.as_bytes()))));
+        assert_eq!("device.drift_detected".parse(), Ok(WebhookEvent::DriftDetected));
+        assert!("rollout.halted".parse::<WebhookEvent>().is_err());
+    }
+}
@@ -3,14 +3,16 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum AdminUserAction {
-    CreateUser,
-    DeleteUser,
+    CreateUser(AdminUserOperationMessage),
+    DeleteUser(AdminUserOperationMessage),
+    ListUsers,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AdminUserOperationMessage {
     pub username: String,
-    pub password: Option<String>,
+    // Generated when creating a user without one
+    pub access_token: Option<String>,
 }
 
 impl From<AdminUserOperationMessage> for Message {
@@ -24,14 +26,16 @@ impl From<AdminUserOperationMessage> for Message {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum AdminDeviceAction {
-    CreateDevice,
-    DeleteDevice,
+    CreateDevice(AdminDeviceOperationMessage),
+    DeleteDevice(AdminDeviceOperationMessage),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AdminDeviceOperationMessage {
     pub machine_id: String,
-    pub token: String,
+    pub name: Option<String>,
+    // Generated when creating a device without one
+    pub token: Option<String>,
 }
 
 impl From<AdminDeviceOperationMessage> for Message {
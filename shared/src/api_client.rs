@@ -0,0 +1,203 @@
+use std::fmt;
+
+use reqwest::{Method, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::rest_dtos::{
+    DeviceCreateDTO, DeviceCreatedDTO, DeviceDeleteDTO, DeviceDriftDTO, DeviceLabelsDTO, DeviceStatusDTO, EnrollDeviceDTO, EnvironmentDriftDTO,
+    EnvironmentDriftModeDTO, EnvironmentMaintenanceDTO, EnvironmentPromoteDTO, EnvironmentRetentionDTO, EnvironmentSelectorDTO, EnvironmentSequenceDTO,
+    EnvironmentStatusDTO, JoinTokenCreateDTO, JoinTokenDTO, PromotionDTO, ResponseDTO, SecretDTO, SecretDeleteDTO, SecretListDTO,
+    SecretSetDTO, UserCreateDTO, UserDeleteDTO, WebhookCreateDTO, WebhookCreatedDTO, WebhookDTO, WebhookDeleteDTO, WebhookDeliveryDTO,
+    WebhookDeliveryListDTO, WebhookListDTO,
+};
+
+// The REST endpoints are only open to the CLI, authenticated with the access
+// token of a user that the server checks before running any route
+const MACHINE_TYPE: &str = "cli";
+
+#[derive(Debug)]
+pub enum ApiError {
+    // No response, e.g. the server is down
+    Transport(String),
+    // Error status, with the `msg` and `data` the server replied with
+    Server { status: u16, msg: String, data: Value },
+    // The reply is not the `{msg, data}` expected from the endpoint
+    Decode { status: u16, reason: String },
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Transport(err) => write!(f, "Could not reach the server: {err}"),
+            ApiError::Server { status, msg, .. } => write!(f, "Server replied with {status}: {msg}"),
+            ApiError::Decode { status, reason } => write!(f, "Unexpected reply from the server ({status}): {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+fn endpoint_url(base_url: &str, path: &str) -> String {
+    format!("{}{path}", base_url.trim_end_matches('/'))
+}
+
+fn parse_response<T: DeserializeOwned>(status: StatusCode, body: &[u8]) -> Result<ResponseDTO<T>, ApiError> {
+    let decode_error = |err: serde_json::Error| ApiError::Decode { status: status.as_u16(), reason: err.to_string() };
+
+    if !status.is_success() {
+        let response: ResponseDTO<Value> = serde_json::from_slice(body).map_err(decode_error)?;
+
+        return Err(ApiError::Server {
+            status: status.as_u16(),
+            msg: response.msg,
+            data: response.data,
+        });
+    }
+
+    serde_json::from_slice(body).map_err(decode_error)
+}
+
+// `base_url` is the address of the server, e.g. `http://127.0.0.1:9734`
+#[derive(Clone)]
+pub struct ApiClient {
+    base_url: String,
+    cli_token: String,
+    client: reqwest::Client,
+}
+
+impl ApiClient {
+    pub fn new(base_url: impl Into<String>, cli_token: impl Into<String>) -> Self {
+        ApiClient {
+            base_url: base_url.into(),
+            cli_token: cli_token.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    async fn send<B: Serialize, T: DeserializeOwned>(&self, method: Method, path: &str, body: &B) -> Result<ResponseDTO<T>, ApiError> {
+        let response = self.client
+            .request(method, endpoint_url(&self.base_url, path))
+            .json(body)
+            .header("machine-type", MACHINE_TYPE)
+            .header("Authorization", self.cli_token.as_str())
+            .send()
+            .await
+            .map_err(|err| ApiError::Transport(err.to_string()))?;
+
+        let status = response.status();
+        let body = response.bytes().await.map_err(|err| ApiError::Transport(err.to_string()))?;
+
+        parse_response(status, &body)
+    }
+}
+
+// Same as `ApiClient`, for callers without an async runtime
+#[derive(Clone)]
+pub struct BlockingApiClient {
+    base_url: String,
+    cli_token: String,
+    client: reqwest::blocking::Client,
+}
+
+impl BlockingApiClient {
+    pub fn new(base_url: impl Into<String>, cli_token: impl Into<String>) -> Self {
+        BlockingApiClient {
+            base_url: base_url.into(),
+            cli_token: cli_token.into(),
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+
+    fn send<B: Serialize, T: DeserializeOwned>(&self, method: Method, path: &str, body: &B) -> Result<ResponseDTO<T>, ApiError> {
+        let response = self.client
+            .request(method, endpoint_url(&self.base_url, path))
+            .json(body)
+            .header("machine-type", MACHINE_TYPE)
+            .header("Authorization", self.cli_token.as_str())
+            .send()
+            .map_err(|err| ApiError::Transport(err.to_string()))?;
+
+        let status = response.status();
+        let body = response.bytes().map_err(|err| ApiError::Transport(err.to_string()))?;
+
+        parse_response(status, &body)
+    }
+}
+
+// One method per endpoint on both clients
+macro_rules! endpoints {
+    ($($name:ident($request:ty) -> $data:ty = $method:ident $path:literal;)*) => {
+        impl ApiClient {
+            $(
+                pub async fn $name(&self, request: &$request) -> Result<ResponseDTO<$data>, ApiError> {
+                    self.send(Method::$method, $path, request).await
+                }
+            )*
+        }
+
+        impl BlockingApiClient {
+            $(
+                pub fn $name(&self, request: &$request) -> Result<ResponseDTO<$data>, ApiError> {
+                    self.send(Method::$method, $path, request)
+                }
+            )*
+        }
+    };
+}
+
+endpoints! {
+    device_create(DeviceCreateDTO) -> DeviceCreatedDTO = POST "/device";
+    device_delete(DeviceDeleteDTO) -> () = DELETE "/device";
+    device_set_labels(DeviceLabelsDTO) -> () = POST "/device/labels";
+    user_create(UserCreateDTO) -> () = POST "/user";
+    user_delete(UserDeleteDTO) -> () = DELETE "/user";
+    enroll_device(EnrollDeviceDTO) -> () = POST "/enroll_device";
+    environment_set_retention(EnvironmentRetentionDTO) -> () = POST "/environment/retention";
+    environment_set_maintenance(EnvironmentMaintenanceDTO) -> () = POST "/environment/maintenance";
+    environment_set_drift_mode(EnvironmentDriftModeDTO) -> () = POST "/environment/drift-mode";
+    environment_drift(EnvironmentDriftDTO) -> Vec<DeviceDriftDTO> = GET "/environment/drift";
+    environment_status(EnvironmentStatusDTO) -> Vec<DeviceStatusDTO> = GET "/environment/status";
+    environment_sequence(EnvironmentSequenceDTO) -> i64 = GET "/environment/sequence";
+    environment_promote(EnvironmentPromoteDTO) -> PromotionDTO = POST "/environment/promote";
+    environment_set_selector(EnvironmentSelectorDTO) -> () = POST "/environment/selector";
+    join_token_create(JoinTokenCreateDTO) -> JoinTokenDTO = POST "/join_token";
+    secret_set(SecretSetDTO) -> () = POST "/secret";
+    secret_list(SecretListDTO) -> Vec<SecretDTO> = GET "/secrets";
+    secret_delete(SecretDeleteDTO) -> () = DELETE "/secret";
+    webhook_create(WebhookCreateDTO) -> WebhookCreatedDTO = POST "/webhook";
+    webhook_list(WebhookListDTO) -> Vec<WebhookDTO> = GET "/webhooks";
+    webhook_delete(WebhookDeleteDTO) -> () = DELETE "/webhook";
+    webhook_delivery_list(WebhookDeliveryListDTO) -> Vec<WebhookDeliveryDTO> = GET "/webhook/deliveries";
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::{parse_response, ApiError};
+    use crate::rest_dtos::{JoinTokenDTO, ResponseDTO};
+
+    #[test]
+    fn parse_response_envelope() {
+        let response: ResponseDTO<JoinTokenDTO> = parse_response(
+            StatusCode::OK,
+            br#"{"msg":"Join token created","data":{"token":"abc","expires_at":"2026-10-20 10:00:00"}}"#,
+        ).unwrap();
+
+        assert_eq!(response.data.token, "abc");
+
+        let response: ResponseDTO<()> = parse_response(StatusCode::OK, br#"{"msg":"Created device successfully","data":null}"#).unwrap();
+        assert_eq!(response.msg, "Created device successfully");
+
+        assert!(matches!(
+            parse_response::<()>(StatusCode::CONFLICT, br#"{"msg":"Environment conflicts","data":["a::User::x"]}"#),
+            Err(ApiError::Server { status: 409, ref msg, ref data }) if msg == "Environment conflicts" && data[0] == "a::User::x",
+        ));
+
+        assert!(matches!(
+            parse_response::<JoinTokenDTO>(StatusCode::OK, br#"{"msg":"","data":null}"#),
+            Err(ApiError::Decode { status: 200, .. }),
+        ));
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+// Applies to a whole message, before decompression and after it
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+// Header the CLI uses to offer compression when opening the socket, the
+// server answers with the same header if it accepts.
+pub const COMPRESSION_HEADER: &str = "ovejas-compression";
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+}
+
+// In order of preference
+pub const SUPPORTED_COMPRESSIONS: &[Compression] = &[Compression::Zstd];
+
+impl Compression {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    // The first of ours the peer also offered
+    pub fn negotiate<S: AsRef<str>>(offered: &[S]) -> Option<Compression> {
+        SUPPORTED_COMPRESSIONS
+            .iter()
+            .copied()
+            .find(|compression| offered.iter().any(|offered| offered.as_ref() == compression.as_str()))
+    }
+}
+
+impl FromStr for Compression {
+    type Err = ();
+
+    fn from_str(compression: &str) -> Result<Self, Self::Err> {
+        match compression {
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CompressionError {
+    TooLarge { max_size: usize },
+    Invalid(String),
+}
+
+impl fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressionError::TooLarge { max_size } => write!(f, "Message is larger than {max_size} bytes once decompressed"),
+            CompressionError::Invalid(err) => write!(f, "Could not decompress message: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for CompressionError {}
+
+pub fn compress(compression: Option<Compression>, data: Vec<u8>) -> Vec<u8> {
+    match compression {
+        None => data,
+        Some(Compression::Zstd) => zstd::bulk::compress(&data, ZSTD_LEVEL).expect("Could not compress"),
+    }
+}
+
+// Stops at `max_size`, so a small frame can't expand into an arbitrarily
+// large message.
+pub fn decompress(compression: Option<Compression>, data: &[u8], max_size: usize) -> Result<Vec<u8>, CompressionError> {
+    match compression {
+        None => Ok(data.to_vec()),
+        Some(Compression::Zstd) => {
+            let decompressed_size = zstd::zstd_safe::get_frame_content_size(data)
+                .map_err(|_| CompressionError::Invalid(String::from("not a zstd frame")))?;
+
+            if decompressed_size.is_some_and(|decompressed_size| decompressed_size > max_size as u64) {
+                return Err(CompressionError::TooLarge { max_size });
+            }
+
+            zstd::bulk::decompress(data, max_size).map_err(|err| CompressionError::Invalid(err.to_string()))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, Compression, CompressionError};
+
+    #[test]
+    fn bounded_decompression() {
+        let state = r#"{"urn":"ovejas.system::User::admin","parameters":{"name":"admin"}}"#.repeat(1000).into_bytes();
+        let compressed = compress(Some(Compression::Zstd), state.clone());
+
+        assert!(compressed.len() < state.len() / 10);
+        assert_eq!(decompress(Some(Compression::Zstd), &compressed, state.len()).unwrap(), state);
+
+        assert!(matches!(
+            decompress(Some(Compression::Zstd), &compressed, state.len() - 1),
+            Err(CompressionError::TooLarge { .. }),
+        ));
+
+        assert!(matches!(decompress(Some(Compression::Zstd), b"plain", 100), Err(CompressionError::Invalid(_))));
+
+        assert_eq!(Compression::negotiate(&["gzip", "zstd"]), Some(Compression::Zstd));
+        assert_eq!(Compression::negotiate(&["gzip"]), None);
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Debug, PartialEq)]
+pub struct UrnError(String);
+
+impl fmt::Display for UrnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid URN '{}', expected '<namespace>::<kind>::<name>'", self.0)
+    }
+}
+
+impl std::error::Error for UrnError {}
+
+// Identifies a resource, e.g. `ovejas.system::User::admin`. The kind picks
+// the provider that applies it on the device.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Urn {
+    namespace: String,
+    kind: String,
+    name: String,
+}
+
+impl Urn {
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl FromStr for Urn {
+    type Err = UrnError;
+
+    fn from_str(urn: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = urn.split("::").collect();
+
+        match parts.as_slice() {
+            [namespace, kind, name] if parts.iter().all(|part| !part.is_empty()) => Ok(Urn {
+                namespace: namespace.to_string(),
+                kind: kind.to_string(),
+                name: name.to_string(),
+            }),
+            _ => Err(UrnError(urn.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for Urn {
+    type Error = UrnError;
+
+    fn try_from(urn: String) -> Result<Self, Self::Error> {
+        urn.parse()
+    }
+}
+
+impl From<Urn> for String {
+    fn from(urn: Urn) -> Self {
+        urn.to_string()
+    }
+}
+
+impl fmt::Display for Urn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}::{}::{}", self.namespace, self.kind, self.name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ResourceSpec {
+    pub urn: Urn,
+    #[serde(default)]
+    pub parameters: Map<String, Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<Urn>,
+    // Outputs of other resources this one uses, resolved by the SDK
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub results: Option<Map<String, Value>>,
+}
+
+// Version of the state format written by this build. Documents from older
+// versions are upgraded when parsed, newer ones are refused.
+pub const STATE_VERSION: u32 = 1;
+
+type Upgrader = fn(&mut Map<String, Value>);
+
+// The upgrader at index `n` takes a document from version `n` to `n + 1`
+const UPGRADERS: [Upgrader; STATE_VERSION as usize] = [upgrade_from_v0];
+
+// Version 0 are the documents written before the format was versioned. Some
+// carry a `schema_version` key instead, which was never read by anything.
+fn upgrade_from_v0(document: &mut Map<String, Value>) {
+    document.remove("schema_version");
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    Parse(String),
+    UnsupportedVersion(u32),
+    DuplicateUrn(String),
+    UnknownDependency { urn: String, depends_on: String },
+    DependencyCycle(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Parse(err) => write!(f, "Could not parse state: {err}"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "State format version {version} is newer than the supported version {STATE_VERSION}, an upgrade is needed to read it")
+            },
+            StateError::DuplicateUrn(urn) => write!(f, "URN '{urn}' is declared more than once"),
+            StateError::UnknownDependency { urn, depends_on } => {
+                write!(f, "'{urn}' depends on '{depends_on}', which is not in the state")
+            },
+            StateError::DependencyCycle(urn) => write!(f, "'{urn}' is part of a dependency cycle"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+// The document written by the SDK and applied by the agent, one per environment.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DesiredState {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub resources: Vec<ResourceSpec>,
+}
+
+impl Default for DesiredState {
+    fn default() -> Self {
+        DesiredState {
+            version: STATE_VERSION,
+            created_at: None,
+            resources: Vec::new(),
+        }
+    }
+}
+
+fn parse_document(json: &str) -> Result<Map<String, Value>, StateError> {
+    serde_json::from_str(json).map_err(|err| StateError::Parse(err.to_string()))
+}
+
+fn document_version(document: &Map<String, Value>) -> Result<u32, StateError> {
+    match document.get("version") {
+        None => Ok(0),
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| StateError::Parse(format!("invalid version {version}"))),
+    }
+}
+
+impl DesiredState {
+    // Version the document was written with, before any upgrade.
+    pub fn declared_version(json: &str) -> Result<u32, StateError> {
+        document_version(&parse_document(json)?)
+    }
+
+    // Parses a document from any supported version, without validating it.
+    pub fn from_json(json: &str) -> Result<Self, StateError> {
+        let mut document = parse_document(json)?;
+        let version = document_version(&document)?;
+
+        if version > STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        for upgrader in &UPGRADERS[version as usize..] {
+            upgrader(&mut document);
+        }
+
+        document.insert(String::from("version"), Value::from(STATE_VERSION));
+
+        serde_json::from_value(Value::Object(document)).map_err(|err| StateError::Parse(err.to_string()))
+    }
+
+    // Parses, upgrades and validates a state document.
+    pub fn parse(json: &str) -> Result<Self, StateError> {
+        let state = DesiredState::from_json(json)?;
+
+        state.validate()?;
+
+        Ok(state)
+    }
+
+    // URNs are unique and every dependency is a resource of the same state,
+    // without cycles.
+    pub fn validate(&self) -> Result<(), StateError> {
+        let mut urns = HashSet::new();
+
+        for resource in &self.resources {
+            if !urns.insert(&resource.urn) {
+                return Err(StateError::DuplicateUrn(resource.urn.to_string()));
+            }
+        }
+
+        for resource in &self.resources {
+            if let Some(depends_on) = resource.depends_on.iter().find(|depends_on| !urns.contains(depends_on)) {
+                return Err(StateError::UnknownDependency {
+                    urn: resource.urn.to_string(),
+                    depends_on: depends_on.to_string(),
+                });
+            }
+        }
+
+        // Resources are removed once all their dependencies were, whatever
+        // is left depends on itself through some path
+        let mut pending: HashMap<&Urn, &Vec<Urn>> = self.resources
+            .iter()
+            .map(|resource| (&resource.urn, &resource.depends_on))
+            .collect();
+
+        loop {
+            let ready: Vec<&Urn> = pending
+                .iter()
+                .filter(|(_, depends_on)| depends_on.iter().all(|urn| !pending.contains_key(urn)))
+                .map(|(urn, _)| *urn)
+                .collect();
+
+            if ready.is_empty() {
+                break;
+            }
+
+            for urn in ready {
+                pending.remove(urn);
+            }
+        }
+
+        match pending.keys().min() {
+            Some(urn) => Err(StateError::DependencyCycle(urn.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    pub fn resource(&self, urn: &Urn) -> Option<&ResourceSpec> {
+        self.resources.iter().find(|resource| &resource.urn == urn)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Could not serialize")
+    }
+}
+
+impl FromStr for DesiredState {
+    type Err = StateError;
+
+    fn from_str(json: &str) -> Result<Self, Self::Err> {
+        DesiredState::parse(json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DesiredState, StateError, Urn, STATE_VERSION};
+
+    #[test]
+    fn parse_and_validate() {
+        let state = DesiredState::parse(r#"{
+            "version": 1,
+            "created_at": "2026-10-19 10:00:00.000000",
+            "resources": [
+                {"urn": "ovejas.system::Group::admins", "parameters": {"name": "admins", "gid": 1000}, "depends_on": []},
+                {"urn": "ovejas.system::User::admin", "parameters": {"name": "admin", "uid": 1000}, "depends_on": ["ovejas.system::Group::admins"]}
+            ]
+        }"#).unwrap();
+
+        let urn: Urn = "ovejas.system::User::admin".parse().unwrap();
+        assert_eq!(urn.kind(), "User");
+        assert_eq!(state.resource(&urn).unwrap().parameters["uid"], 1000);
+
+        assert!(matches!(DesiredState::parse(r#"{"resources":[{"urn":"admin"}]}"#), Err(StateError::Parse(_))));
+
+        assert!(matches!(
+            DesiredState::parse(r#"{"resources":[{"urn":"a::User::x"},{"urn":"a::User::x"}]}"#),
+            Err(StateError::DuplicateUrn(_)),
+        ));
+
+        assert!(matches!(
+            DesiredState::parse(r#"{"resources":[{"urn":"a::User::x","depends_on":["a::Group::y"]}]}"#),
+            Err(StateError::UnknownDependency { .. }),
+        ));
+
+        assert!(matches!(
+            DesiredState::parse(r#"{"resources":[
+                {"urn":"a::User::x","depends_on":["a::Group::y"]},
+                {"urn":"a::Group::y","depends_on":["a::User::x"]}
+            ]}"#),
+            Err(StateError::DependencyCycle(_)),
+        ));
+
+        assert_eq!(DesiredState::parse("{}").unwrap(), DesiredState::default());
+    }
+
+    #[test]
+    fn upgrade_older_versions() {
+        let unversioned = r#"{"schema_version":1,"resources":[{"urn":"ovejas.system::User::admin","parameters":{"uid":1000}}]}"#;
+
+        assert_eq!(DesiredState::declared_version(unversioned).unwrap(), 0);
+
+        let state = DesiredState::parse(unversioned).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.resources.len(), 1);
+
+        let newer = format!(r#"{{"version":{},"resources":[]}}"#, STATE_VERSION + 1);
+
+        assert!(matches!(
+            DesiredState::parse(newer.as_str()),
+            Err(StateError::UnsupportedVersion(version)) if version == STATE_VERSION + 1,
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+use tokio_tungstenite::tungstenite::Message;
+use serde::{Deserialize, Serialize};
+
+use crate::compression::Compression;
+use crate::request_operations::{decode_text, MessageDecodeError};
+
+fn first_state_version() -> u32 {
+    1
+}
+
+// Bumped whenever `Envelope`, `RequestOperations` or `ResponseOperations`
+// change in a way older peers can't decode. Version 2 wraps every message
+// in an `Envelope`, version 3 adds `EnvironmentUpdateOperation::Patch` and
+// version 4 signs every `EnvironmentUpdate`, version 5 reports a
+// `DeviceStatus` per environment and version 6 signs the project and the
+// sequence of each state.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+// First protocol version whose agents can apply patches
+pub const PATCH_PROTOCOL_VERSION: u32 = 3;
+
+// Oldest agent protocol the server still talks to. Older agents can't
+// decode the `StateSignature` of an update.
+pub const MIN_PROTOCOL_VERSION: u32 = 6;
+
+// First frame sent by the agent after connecting. The hello is exchanged as
+// JSON so peers can read it whatever their protocol version is.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub agent_version: String,
+    // Resource kinds the agent has a provider for, e.g. `User`
+    pub provider_kinds: Vec<String>,
+    // Newest `DesiredState` format the agent can read
+    #[serde(default = "first_state_version")]
+    pub max_state_version: u32,
+    // Compression formats the agent can read, by preference
+    #[serde(default)]
+    pub compression: Vec<String>,
+}
+
+// Sent by the server when it accepts the hello. Incompatible agents get a
+// close frame with the reason instead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HelloResponse {
+    pub protocol_version: u32,
+    pub server_version: String,
+    // Applies to every frame after the hello, in both directions
+    #[serde(default)]
+    pub compression: Option<Compression>,
+}
+
+pub fn is_supported_protocol(protocol_version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version)
+}
+
+impl From<Hello> for Message {
+    fn from(orig: Hello) -> Self {
+        let serialized = serde_json::to_string(&orig).expect("Could not serialize");
+        Message::Text(serialized.into())
+    }
+}
+
+impl TryFrom<Message> for Hello {
+    type Error = MessageDecodeError;
+
+    fn try_from(orig: Message) -> Result<Self, Self::Error> {
+        decode_text(orig)
+    }
+}
+
+impl From<HelloResponse> for Message {
+    fn from(orig: HelloResponse) -> Self {
+        let serialized = serde_json::to_string(&orig).expect("Could not serialize");
+        Message::Text(serialized.into())
+    }
+}
+
+impl TryFrom<Message> for HelloResponse {
+    type Error = MessageDecodeError;
+
+    fn try_from(orig: Message) -> Result<Self, Self::Error> {
+        decode_text(orig)
+    }
+}
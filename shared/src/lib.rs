@@ -1,3 +1,12 @@
+pub mod admin_operations;
+#[cfg(feature = "client")]
+pub mod api_client;
+pub mod compression;
+pub mod desired_state;
+pub mod handshake;
 pub mod request_operations;
 pub mod state_operations;
 pub mod rest_dtos;
+pub mod secrets;
+pub mod signing;
+pub mod state_patch;
@@ -1,71 +1,337 @@
 use std::collections::HashMap;
+use std::fmt;
 
 use chrono::NaiveDateTime;
 use tokio_tungstenite::tungstenite::Message;
-use serde::{Serialize, Deserialize};
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
+
+use crate::compression::{compress, decompress, Compression, CompressionError, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::signing::StateSignature;
+
+#[derive(Debug)]
+pub enum MessageDecodeError {
+    // e.g. a text frame where a binary one was expected
+    WrongFrameKind { expected: &'static str, received: &'static str },
+    Decode(String),
+    // Usually a peer on a newer protocol version
+    UnknownVariant(String),
+    TooLarge { size: usize, max_size: usize },
+    // The frame fits but decompressing it stops at `max_size`
+    TooLargeDecompressed { max_size: usize },
+    Decompress(CompressionError),
+}
+
+impl fmt::Display for MessageDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageDecodeError::WrongFrameKind { expected, received } => write!(f, "Expected a {expected} frame, received a {received} frame"),
+            MessageDecodeError::Decode(err) => write!(f, "Could not decode message: {err}"),
+            MessageDecodeError::UnknownVariant(err) => write!(f, "Unknown message variant: {err}"),
+            MessageDecodeError::TooLarge { size, max_size } => write!(f, "Message of {size} bytes exceeds the maximum of {max_size}"),
+            MessageDecodeError::TooLargeDecompressed { max_size } => write!(f, "Message exceeds the maximum of {max_size} bytes once decompressed"),
+            MessageDecodeError::Decompress(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for MessageDecodeError {}
+
+impl From<bincode::Error> for MessageDecodeError {
+    fn from(err: bincode::Error) -> Self {
+        match *err {
+            // serde reports out of range enum indexes as "expected variant index 0 <= i < N"
+            bincode::ErrorKind::Custom(err) if err.contains("variant index") => MessageDecodeError::UnknownVariant(err),
+            err => MessageDecodeError::Decode(err.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for MessageDecodeError {
+    fn from(err: serde_json::Error) -> Self {
+        match err.to_string() {
+            err if err.starts_with("unknown variant") => MessageDecodeError::UnknownVariant(err),
+            err => MessageDecodeError::Decode(err),
+        }
+    }
+}
+
+pub fn frame_kind(message: &Message) -> &'static str {
+    match message {
+        Message::Text(_) => "text",
+        Message::Binary(_) => "binary",
+        Message::Ping(_) => "ping",
+        Message::Pong(_) => "pong",
+        Message::Close(_) => "close",
+        Message::Frame(_) => "raw",
+    }
+}
+
+pub fn decode_text<T: DeserializeOwned>(message: Message) -> Result<T, MessageDecodeError> {
+    match message {
+        Message::Text(data) => Ok(serde_json::from_str(data.as_str())?),
+        message => Err(MessageDecodeError::WrongFrameKind { expected: "text", received: frame_kind(&message) }),
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum EnvironmentUpdateOperation {
     Create,
     Update,
     Destroy,
+    // Re-applies the resources of the current state that drifted
+    Remediate,
+    // `state` is a JSON patch (RFC 6902) from the document the agent has,
+    // hashing to `base_hash`, to the one hashing to `target_hash`
+    Patch { base_hash: [u8; 16], target_hash: [u8; 16] },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EnvironmentUpdate {
     pub state: Option<String>,
     pub operation: EnvironmentUpdateOperation,
+    pub secrets: HashMap<String, String>,
+    // Covers the whole target state, for a patch the document once patched
+    pub signature: Option<StateSignature>,
 }
 
+pub type MessageId = u64;
+
+// Every frame after the hello is wrapped in an envelope, so replies can be
+// matched to their request and other messages can share the connection.
 #[derive(Serialize, Deserialize, Debug)]
-pub enum RequestOperations {
-    StatusRequest,
-    UpdateEnvironmentsRequest(HashMap<String, EnvironmentUpdate>),
+pub struct Envelope<T> {
+    pub id: MessageId,
+    // Set when the message answers a request from the peer
+    pub in_reply_to: Option<MessageId>,
+    pub body: T,
 }
 
-impl From<RequestOperations> for Message {
-    fn from(orig: RequestOperations) -> Self {
-        let serialized = bincode::serialize(&orig).expect("Could not serialize");
-        Message::Binary(serialized.into())
+impl From<CompressionError> for MessageDecodeError {
+    fn from(err: CompressionError) -> Self {
+        match err {
+            CompressionError::TooLarge { max_size } => MessageDecodeError::TooLargeDecompressed { max_size },
+            err => MessageDecodeError::Decompress(err),
+        }
     }
 }
 
-impl From<Message> for RequestOperations {
-    fn from(orig: Message) -> Self {
-        let data = orig.into_data();
-        let deserialized: RequestOperations = bincode::deserialize(data.as_ref()).expect("Could not deserialize");
+// Turns envelopes into frames and back, using the compression agreed on in
+// the hello.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    pub compression: Option<Compression>,
+    pub max_message_size: usize,
+}
 
-        deserialized
+impl Default for FrameCodec {
+    fn default() -> Self {
+        FrameCodec {
+            compression: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub struct MessageTooLarge {
+    pub size: usize,
+    pub max_size: usize,
+}
+
+impl fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Message of {} bytes exceeds the maximum of {}", self.size, self.max_size)
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+impl FrameCodec {
+    pub fn encode<T: Serialize>(&self, envelope: &Envelope<T>) -> Message {
+        let serialized = bincode::serialize(envelope).expect("Could not serialize");
+
+        Message::Binary(compress(self.compression, serialized).into())
+    }
+
+    // For messages that may not fit. The peer's `decode` limits both the frame
+    // and the decompressed message, so both sizes are checked here.
+    pub fn try_encode<T: Serialize>(&self, envelope: &Envelope<T>) -> Result<Message, MessageTooLarge> {
+        let serialized = bincode::serialize(envelope).expect("Could not serialize");
+        let size = serialized.len();
+
+        if size > self.max_message_size {
+            return Err(MessageTooLarge { size, max_size: self.max_message_size });
+        }
+
+        let compressed = compress(self.compression, serialized);
+
+        if compressed.len() > self.max_message_size {
+            return Err(MessageTooLarge { size: compressed.len(), max_size: self.max_message_size });
+        }
+
+        Ok(Message::Binary(compressed.into()))
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, message: Message) -> Result<Envelope<T>, MessageDecodeError> {
+        let data = match message {
+            Message::Binary(data) => data,
+            message => return Err(MessageDecodeError::WrongFrameKind { expected: "binary", received: frame_kind(&message) }),
+        };
+
+        if data.len() > self.max_message_size {
+            return Err(MessageDecodeError::TooLarge { size: data.len(), max_size: self.max_message_size });
+        }
+
+        let data = decompress(self.compression, data.as_ref(), self.max_message_size)?;
+
+        Ok(bincode::deserialize(data.as_ref())?)
+    }
+}
+
+// Ids only need to be unique per connection and sender.
+#[derive(Debug, Default)]
+pub struct MessageIds {
+    last_id: MessageId,
+}
+
+impl MessageIds {
+    pub fn next_id(&mut self) -> MessageId {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    pub fn request<T>(&mut self, body: T) -> Envelope<T> {
+        Envelope { id: self.next_id(), in_reply_to: None, body }
+    }
+
+    pub fn reply<T>(&mut self, in_reply_to: MessageId, body: T) -> Envelope<T> {
+        Envelope { id: self.next_id(), in_reply_to: Some(in_reply_to), body }
+    }
+}
+
+// Sent by the server
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RequestOperations {
+    StatusRequest,
+    UpdateEnvironmentsRequest(HashMap<String, EnvironmentUpdate>),
+}
+
+// Sent by the agent. While applying an update it also sends a
+// `CurrentStatus` on its own, without `in_reply_to`, after each resource.
 #[derive(Serialize, Deserialize, Debug)]
+pub enum ResponseOperations {
+    CurrentStatus(CurrentStatusResponse),
+}
+
+// Lifecycle of an environment on the agent. `Idle` until the agent gets an
+// update for it after starting.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum DeviceStatus {
     Idle,
-    InProgress,
-    Ready,
-    // RolledBack,
+    Applying { applied: u32, total: u32 },
+    Converged,
+    Failed { reason: String },
+    // The update failed part way and the changes already made were reverted
+    RolledBack { reason: String },
+}
+
+impl fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceStatus::Idle => write!(f, "idle"),
+            DeviceStatus::Applying { applied, total } => write!(f, "applying ({applied}/{total})"),
+            DeviceStatus::Converged => write!(f, "converged"),
+            DeviceStatus::Failed { reason } => write!(f, "failed: {reason}"),
+            DeviceStatus::RolledBack { reason } => write!(f, "rolled back: {reason}"),
+        }
+    }
+}
+
+// Difference between a resource in a state file and what the provider reads
+// from the system.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ResourceDrift {
+    Missing { urn: String },
+    Changed { urn: String, parameter: String, expected: String, actual: String },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CurrentStatusResponse {
-    pub status: DeviceStatus,
+    pub statuses: HashMap<String, DeviceStatus>,
     pub timestamp: String,
     pub state_hashes: HashMap<String, [u8; 16]>,
+    // Only set when the device checked for drift since the last status request
+    pub drift: Option<HashMap<String, Vec<ResourceDrift>>>,
 }
 
-impl From<CurrentStatusResponse> for Message {
-    fn from(orig: CurrentStatusResponse) -> Self {
-        let serialized = bincode::serialize(&orig).expect("Could not serialize");
-        Message::Binary(serialized.into())
+#[cfg(test)]
+mod tests {
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::compression::Compression;
+
+    use super::{Envelope, FrameCodec, MessageDecodeError, MessageIds, MessageTooLarge, RequestOperations};
+
+    type Request = Envelope<RequestOperations>;
+
+    #[test]
+    fn bad_frames_are_errors() {
+        let codec = FrameCodec::default();
+        let mut message_ids = MessageIds::default();
+
+        let status_request = codec.encode(&message_ids.request(RequestOperations::StatusRequest));
+        assert!(matches!(
+            codec.decode::<RequestOperations>(status_request),
+            Ok(Envelope { id: 1, in_reply_to: None, body: RequestOperations::StatusRequest }),
+        ));
+
+        assert!(matches!(
+            codec.decode::<RequestOperations>(Message::text("StatusRequest")),
+            Err(MessageDecodeError::WrongFrameKind { expected: "binary", received: "text" }),
+        ));
+
+        // Message id 1, no reply id and variant index 7, all little endian
+        let mut unknown_variant = vec![1, 0, 0, 0, 0, 0, 0, 0, 0];
+        unknown_variant.extend([7, 0, 0, 0]);
+
+        assert!(matches!(
+            codec.decode::<RequestOperations>(Message::binary(unknown_variant)),
+            Err(MessageDecodeError::UnknownVariant(_)),
+        ));
+
+        assert!(matches!(
+            codec.decode::<RequestOperations>(Message::binary(vec![1, 0])),
+            Err(MessageDecodeError::Decode(_)),
+        ));
+
+        let small_codec = FrameCodec { max_message_size: 8, ..codec };
+
+        assert!(matches!(
+            small_codec.decode::<RequestOperations>(codec.encode(&message_ids.request(RequestOperations::StatusRequest))),
+            Err(MessageDecodeError::TooLarge { size: 13, max_size: 8 }),
+        ));
+
+        let zstd_codec = FrameCodec { compression: Some(Compression::Zstd), ..codec };
+        let compressed: Request = zstd_codec.decode(zstd_codec.encode(&message_ids.request(RequestOperations::StatusRequest))).unwrap();
+        assert_eq!(compressed.id, 3);
+
+        let reply = message_ids.reply(1, RequestOperations::StatusRequest);
+        assert_eq!((reply.id, reply.in_reply_to), (4, Some(1)));
     }
-}
 
-impl From<Message> for CurrentStatusResponse {
-    fn from(orig: Message) -> Self {
-        let data = orig.into_data();
-        let deserialized: CurrentStatusResponse = bincode::deserialize(data.as_ref()).expect("Could not deserialize");
+    #[test]
+    fn size_limit_applies_to_the_decompressed_message() {
+        let mut message_ids = MessageIds::default();
+        let update = Envelope { id: 1, in_reply_to: None, body: "a".repeat(4096) };
+
+        let codec = FrameCodec { compression: Some(Compression::Zstd), max_message_size: 1024 };
+        let frame = FrameCodec { max_message_size: 8192, ..codec }.encode(&update);
+
+        // Small enough compressed, too large once decompressed
+        assert!(frame.len() < codec.max_message_size);
+        assert!(matches!(codec.decode::<String>(frame), Err(MessageDecodeError::TooLargeDecompressed { max_size: 1024 })));
+        assert_eq!(codec.try_encode(&update).err(), Some(MessageTooLarge { size: 4113, max_size: 1024 }));
 
-        deserialized
+        assert!(codec.try_encode(&message_ids.request(RequestOperations::StatusRequest)).is_ok());
     }
 }
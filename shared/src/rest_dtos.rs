@@ -1,5 +1,9 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::request_operations::DeviceStatus;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeviceDeleteDTO {
     pub name: String,
@@ -11,6 +15,11 @@ pub struct DeviceCreateDTO {
     pub machine_id: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceCreatedDTO {
+    pub token: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserCreateDTO {
     pub name: String,
@@ -28,3 +37,207 @@ pub struct EnrollDeviceDTO {
     pub project_name: String,
     pub environment_name: String,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnvironmentRetentionDTO {
+    pub project_name: String,
+    pub environment_name: String,
+    pub keep_last: Option<i32>,
+    pub max_age_days: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnvironmentPromoteDTO {
+    pub project_name: String,
+    pub from_environment: String,
+    pub to_environment: String,
+    pub source_state_id: Option<i32>,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecretSetDTO {
+    pub project_name: String,
+    pub environment_name: String,
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecretListDTO {
+    pub project_name: String,
+    pub environment_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecretDeleteDTO {
+    pub project_name: String,
+    pub environment_name: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceLabelsDTO {
+    pub machine_id: String,
+    pub set: HashMap<String, String>,
+    pub remove: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnvironmentSelectorDTO {
+    pub project_name: String,
+    pub environment_name: String,
+    pub label_selector: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnvironmentMaintenanceDTO {
+    pub project_name: String,
+    pub environment_name: String,
+    pub windows: Vec<String>,
+    pub timezone: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnvironmentDriftModeDTO {
+    pub project_name: String,
+    pub environment_name: String,
+    pub mode: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnvironmentDriftDTO {
+    pub project_name: String,
+    pub environment_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnvironmentStatusDTO {
+    pub project_name: String,
+    pub environment_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnvironmentSequenceDTO {
+    pub project_name: String,
+    pub environment_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JoinTokenCreateDTO {
+    pub project_name: String,
+    pub environment_name: String,
+    pub expires_in_hours: i64,
+    pub max_uses: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookCreateDTO {
+    pub project_name: String,
+    pub url: String,
+    pub events: Vec<String>,
+    pub secret: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookListDTO {
+    pub project_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookDeleteDTO {
+    pub project_name: String,
+    pub id: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookDeliveryListDTO {
+    pub project_name: String,
+    pub id: i32,
+}
+
+// Every endpoint replies with `{"msg": ..., "data": ...}`, `data` being one
+// of the DTOs below or null.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseDTO<T> {
+    pub msg: String,
+    pub data: T,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JoinTokenDTO {
+    pub token: String,
+    pub expires_at: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateChangeDTO {
+    pub urn: String,
+    pub change: ChangeKind,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PromotionDTO {
+    pub source_state_id: i32,
+    pub promoted_state_id: Option<i32>,
+    pub changes: Vec<StateChangeDTO>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceDriftDTO {
+    pub name: String,
+    pub machine_id: Option<String>,
+    pub checked_at: Option<String>,
+    pub drift: Option<serde_json::Value>,
+}
+
+// `status` is None until the device reports one for the environment
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceStatusDTO {
+    pub name: String,
+    pub machine_id: Option<String>,
+    pub online: bool,
+    pub status: Option<DeviceStatus>,
+    pub status_updated_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecretDTO {
+    pub name: String,
+    pub revision: i32,
+    pub created_at: String,
+    pub updated_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookCreatedDTO {
+    pub id: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookDTO {
+    pub id: i32,
+    pub url: String,
+    pub events: Vec<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookDeliveryDTO {
+    pub id: i32,
+    pub event: String,
+    pub attempts: i32,
+    pub delivered: bool,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub updated_at: Option<String>,
+}
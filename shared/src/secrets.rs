@@ -0,0 +1,35 @@
+use serde_json::Value;
+
+pub const SECRET_MARKER: &str = "$secret";
+pub const SECRET_REVISION: &str = "revision";
+
+pub fn secret_reference_name(value: &Value) -> Option<&str> {
+    value.as_object()?.get(SECRET_MARKER)?.as_str()
+}
+
+pub fn visit_secret_references<E>(
+    value: &mut Value,
+    visit: &mut dyn FnMut(&str, &mut Value) -> Result<(), E>,
+) -> Result<(), E> {
+    if let Some(name) = secret_reference_name(value) {
+        let name = name.to_string();
+
+        return visit(name.as_str(), value);
+    }
+
+    match value {
+        Value::Array(values) => {
+            for value in values {
+                visit_secret_references(value, visit)?;
+            }
+        },
+        Value::Object(values) => {
+            for (_, value) in values {
+                visit_secret_references(value, visit)?;
+            }
+        },
+        _ => {},
+    }
+
+    Ok(())
+}
@@ -0,0 +1,226 @@
+use std::fmt;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use ed25519_dalek::{Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+// Document signed for a destroy, which travels without a state
+pub const DESTROYED_STATE: &str = "{}";
+
+// Separates the fields of the signed message, so a signature can't be
+// reused for another purpose, project or environment
+const SIGNATURE_CONTEXT: &str = "ovejas-state-v2";
+
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    InvalidKey,
+    Missing,
+    UntrustedKey(String),
+    Invalid,
+    // The environment already applied a state of another project
+    OtherProject { expected: String, received: String },
+    // Older than the state the environment already applied, i.e. replayed
+    Outdated { sequence: i64, applied_sequence: i64 },
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::InvalidKey => write!(f, "Signing keys must be 32 bytes encoded in base64"),
+            SignatureError::Missing => write!(f, "State is not signed"),
+            SignatureError::UntrustedKey(key) => write!(f, "State is signed with the untrusted key '{key}'"),
+            SignatureError::Invalid => write!(f, "State signature is invalid"),
+            SignatureError::OtherProject { expected, received } => {
+                write!(f, "State is signed for project '{received}', the environment belongs to '{expected}'")
+            },
+            SignatureError::Outdated { sequence, applied_sequence } => {
+                write!(f, "State {sequence} is older than the applied state {applied_sequence}")
+            },
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+// Ed25519 signature of a state for an environment of a project, the key and
+// signature in base64. `project` and `sequence`, the position the server gives
+// the state among the ones of the environment, are signed too so agents can
+// refuse replayed states.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StateSignature {
+    pub signed_by: String,
+    pub signature: String,
+    pub project: String,
+    pub sequence: i64,
+}
+
+// What an agent remembers of the last state it applied for an environment
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AppliedSignature {
+    pub project: String,
+    pub sequence: i64,
+}
+
+impl From<&StateSignature> for AppliedSignature {
+    fn from(signature: &StateSignature) -> Self {
+        AppliedSignature {
+            project: signature.project.clone(),
+            sequence: signature.sequence,
+        }
+    }
+}
+
+fn decode_key(key: &str) -> Result<[u8; 32], SignatureError> {
+    BASE64_STANDARD.decode(key.trim())
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or(SignatureError::InvalidKey)
+}
+
+fn signed_message(project: &str, environment: &str, sequence: i64, state: &str) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, project, environment, sequence.to_string().as_str(), state].join("\0").into_bytes()
+}
+
+pub fn signing_key_from_base64(key: &str) -> Result<SigningKey, SignatureError> {
+    Ok(SigningKey::from_bytes(&decode_key(key)?))
+}
+
+pub fn verifying_key_from_base64(key: &str) -> Result<VerifyingKey, SignatureError> {
+    VerifyingKey::from_bytes(&decode_key(key)?).map_err(|_| SignatureError::InvalidKey)
+}
+
+pub fn public_key_base64(key: &SigningKey) -> String {
+    BASE64_STANDARD.encode(key.verifying_key().as_bytes())
+}
+
+// The state is signed byte for byte, the server signs what it sends and
+// the CLI what the server stores, i.e. the canonical form of the document.
+pub fn sign_state(key: &SigningKey, project: &str, environment: &str, sequence: i64, state: &str) -> StateSignature {
+    StateSignature {
+        signed_by: public_key_base64(key),
+        signature: BASE64_STANDARD.encode(key.sign(&signed_message(project, environment, sequence, state)).to_bytes()),
+        project: project.to_string(),
+        sequence,
+    }
+}
+
+// Checks the signature alone, whoever made it
+pub fn verify_signature(signature: &StateSignature, environment: &str, state: &str) -> Result<VerifyingKey, SignatureError> {
+    let signed_by = verifying_key_from_base64(&signature.signed_by)?;
+
+    let signature_bytes: [u8; 64] = BASE64_STANDARD.decode(&signature.signature)
+        .ok()
+        .and_then(|signature| signature.try_into().ok())
+        .ok_or(SignatureError::Invalid)?;
+
+    signed_by
+        .verify(
+            &signed_message(&signature.project, environment, signature.sequence, state),
+            &Signature::from_bytes(&signature_bytes),
+        )
+        .map_err(|_| SignatureError::Invalid)?;
+
+    Ok(signed_by)
+}
+
+pub fn verify_state(
+    trusted_keys: &[VerifyingKey],
+    signature: Option<&StateSignature>,
+    environment: &str,
+    state: &str,
+) -> Result<(), SignatureError> {
+    let signature = signature.ok_or(SignatureError::Missing)?;
+    let signed_by = verify_signature(signature, environment, state)?;
+
+    if !trusted_keys.contains(&signed_by) {
+        return Err(SignatureError::UntrustedKey(signature.signed_by.clone()));
+    }
+
+    Ok(())
+}
+
+// A valid signature can still be replayed by whoever relays it, so an agent
+// keeps the project of the environment and refuses anything older than the
+// state it applied last. The same state may be sent again, e.g. to remediate.
+pub fn check_not_replayed(signature: &StateSignature, last_applied: Option<&AppliedSignature>) -> Result<(), SignatureError> {
+    let Some(last_applied) = last_applied else {
+        return Ok(());
+    };
+
+    if signature.project != last_applied.project {
+        return Err(SignatureError::OtherProject {
+            expected: last_applied.project.clone(),
+            received: signature.project.clone(),
+        });
+    }
+
+    if signature.sequence < last_applied.sequence {
+        return Err(SignatureError::Outdated {
+            sequence: signature.sequence,
+            applied_sequence: last_applied.sequence,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_not_replayed, sign_state, signing_key_from_base64, verify_state, verifying_key_from_base64, AppliedSignature, SignatureError,
+        StateSignature,
+    };
+
+    #[test]
+    fn verify_against_trusted_keys() {
+        let key = signing_key_from_base64("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=").unwrap();
+        let other_key = signing_key_from_base64("HyAhIiMkJSYnKCkqKywtLi8wMTIzNDU2Nzg5Ojs8PT4=").unwrap();
+        let trusted_keys = [key.verifying_key()];
+
+        let state = r#"{"resources":[],"version":1}"#;
+        let signature = sign_state(&key, "web", "prod", 1, state);
+
+        assert_eq!(verifying_key_from_base64(&signature.signed_by).unwrap(), key.verifying_key());
+        assert_eq!(verify_state(&trusted_keys, Some(&signature), "prod", state), Ok(()));
+
+        assert_eq!(verify_state(&trusted_keys, None, "prod", state), Err(SignatureError::Missing));
+        assert_eq!(verify_state(&trusted_keys, Some(&signature), "staging", state), Err(SignatureError::Invalid));
+        assert_eq!(verify_state(&trusted_keys, Some(&signature), "prod", r#"{"resources":[]}"#), Err(SignatureError::Invalid));
+
+        // The project and the sequence are signed, not just carried along
+        let other_project = StateSignature { project: String::from("api"), ..signature.clone() };
+        assert_eq!(verify_state(&trusted_keys, Some(&other_project), "prod", state), Err(SignatureError::Invalid));
+
+        let later = StateSignature { sequence: 2, ..signature.clone() };
+        assert_eq!(verify_state(&trusted_keys, Some(&later), "prod", state), Err(SignatureError::Invalid));
+
+        assert!(matches!(
+            verify_state(&trusted_keys, Some(&sign_state(&other_key, "web", "prod", 1, state)), "prod", state),
+            Err(SignatureError::UntrustedKey(_)),
+        ));
+
+        assert_eq!(signing_key_from_base64("c2hvcnQ=").err(), Some(SignatureError::InvalidKey));
+    }
+
+    #[test]
+    fn replayed_states_are_refused() {
+        let key = signing_key_from_base64("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=").unwrap();
+        let applied = AppliedSignature::from(&sign_state(&key, "web", "prod", 2, "{}"));
+
+        assert_eq!(check_not_replayed(&sign_state(&key, "web", "prod", 1, "{}"), None), Ok(()));
+        assert_eq!(check_not_replayed(&sign_state(&key, "web", "prod", 2, "{}"), Some(&applied)), Ok(()));
+        assert_eq!(check_not_replayed(&sign_state(&key, "web", "prod", 3, "{}"), Some(&applied)), Ok(()));
+
+        assert_eq!(
+            check_not_replayed(&sign_state(&key, "web", "prod", 1, "{}"), Some(&applied)),
+            Err(SignatureError::Outdated { sequence: 1, applied_sequence: 2 }),
+        );
+
+        assert_eq!(
+            check_not_replayed(&sign_state(&key, "api", "prod", 3, "{}"), Some(&applied)),
+            Err(SignatureError::OtherProject { expected: String::from("web"), received: String::from("api") }),
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use tokio_tungstenite::tungstenite::Message;
 use serde::{Deserialize, Serialize};
 
+use crate::signing::StateSignature;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum StateAction {
     Up,
@@ -14,6 +16,12 @@ pub struct StateOperationMessage {
     pub action: StateAction,
     pub state: Option<String>,
     pub project: String,
+    // Lets the state reach devices outside the maintenance windows of the environment
+    #[serde(default)]
+    pub override_window: bool,
+    // Made by the pushing user over the canonical form of `state`
+    #[serde(default)]
+    pub signature: Option<StateSignature>,
 }
 
 impl From<StateOperationMessage> for Message {
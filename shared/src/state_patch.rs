@@ -0,0 +1,81 @@
+use std::fmt;
+
+use serde_json::Value;
+
+#[derive(Debug)]
+pub enum StatePatchError {
+    Parse(String),
+    Apply(String),
+}
+
+impl fmt::Display for StatePatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatePatchError::Parse(err) => write!(f, "Could not parse state or patch: {err}"),
+            StatePatchError::Apply(err) => write!(f, "Could not apply patch: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for StatePatchError {}
+
+fn parse_value(json: &str) -> Result<Value, StatePatchError> {
+    serde_json::from_str(json).map_err(|err| StatePatchError::Parse(err.to_string()))
+}
+
+// Compact, with object keys sorted. A patched document is written this way,
+// so only states already in this form can be patched and still hash the
+// same on both ends.
+pub fn canonical_json(json: &str) -> Result<String, StatePatchError> {
+    Ok(parse_value(json)?.to_string())
+}
+
+pub fn is_canonical(json: &str) -> bool {
+    canonical_json(json).is_ok_and(|canonical| canonical == json)
+}
+
+// JSON patch (RFC 6902) taking `base` to `target`
+pub fn diff(base: &str, target: &str) -> Result<String, StatePatchError> {
+    let patch = json_patch::diff(&parse_value(base)?, &parse_value(target)?);
+
+    Ok(serde_json::to_string(&patch).expect("Could not serialize"))
+}
+
+// Returns the patched document in canonical form
+pub fn apply(base: &str, patch: &str) -> Result<String, StatePatchError> {
+    let mut state = parse_value(base)?;
+    let patch: json_patch::Patch = serde_json::from_str(patch).map_err(|err| StatePatchError::Parse(err.to_string()))?;
+
+    json_patch::patch(&mut state, &patch).map_err(|err| StatePatchError::Apply(err.to_string()))?;
+
+    Ok(state.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, canonical_json, diff, is_canonical, StatePatchError};
+
+    #[test]
+    fn patch_round_trip() {
+        let base = canonical_json(r#"{"version":1,"resources":[{"urn":"ovejas.system::User::admin","parameters":{"uid":1000}}]}"#).unwrap();
+        let target = canonical_json(r#"{
+            "version": 1,
+            "resources": [
+                {"urn": "ovejas.system::User::admin", "parameters": {"uid": 1001}},
+                {"urn": "ovejas.system::User::deploy", "parameters": {"uid": 1002}}
+            ]
+        }"#).unwrap();
+
+        assert!(is_canonical(target.as_str()));
+        assert!(!is_canonical(r#"{"version": 1}"#));
+
+        let patch = diff(base.as_str(), target.as_str()).unwrap();
+
+        assert_eq!(apply(base.as_str(), patch.as_str()).unwrap(), target);
+
+        assert!(matches!(
+            apply(base.as_str(), r#"[{"op":"remove","path":"/resources/5"}]"#),
+            Err(StatePatchError::Apply(_)),
+        ));
+    }
+}